@@ -0,0 +1,480 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt::Display,
+    fs,
+    os::unix::fs::MetadataExt,
+};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    pwm::{Channel, Controller, Pwm, PwmError},
+};
+
+/// What a client wants to do with a channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read from"),
+            Permission::Write => write!(f, "write to"),
+        }
+    }
+}
+
+/// A user or, if prefixed with "@", a group, given by name or ID, e.g.,
+/// "alice", "1000" or "@gpio".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Principal {
+    User(String),
+    Group(String),
+}
+
+impl TryFrom<String> for Principal {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let principal = match s.strip_prefix('@') {
+            Some(group) => Principal::Group(group.to_owned()),
+            None => Principal::User(s.clone()),
+        };
+        match &principal {
+            Principal::User(name) | Principal::Group(name) if name.is_empty() => {
+                Err(format!("expected a user or an @group, got {:?}", s))
+            }
+            _ => Ok(principal),
+        }
+    }
+}
+
+impl Principal {
+    /// Looks up the ID of the principal, given the contents of `/etc/passwd`
+    /// and `/etc/group`.
+    fn resolve(&self, passwd: &str, group: &str) -> Option<Id> {
+        let id = |name: &str, contents: &str| {
+            name.parse().ok().or_else(|| {
+                contents
+                    .lines()
+                    .map(|line| line.split(':').collect::<Vec<_>>())
+                    .find(|fields| fields.len() >= 3 && fields[0] == name)
+                    .and_then(|fields| fields[2].parse().ok())
+            })
+        };
+        match self {
+            Principal::User(name) => id(name, passwd).map(Id::User),
+            Principal::Group(name) => id(name, group).map(Id::Group),
+        }
+    }
+}
+
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Principal::User(name) => write!(f, "{}", name),
+            Principal::Group(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+/// A principal, resolved to its user or group ID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Id {
+    User(u32),
+    Group(u32),
+}
+
+impl Id {
+    fn matches(&self, caller: &Credentials) -> bool {
+        match self {
+            Id::User(uid) => caller.uid == *uid,
+            Id::Group(gid) => caller.gids.contains(gid),
+        }
+    }
+}
+
+/// Who may read from and write to a channel. `None` leaves the decision to
+/// the next broader rule, i.e., the rule of the chip and then the default
+/// rule; if none of them decides, everyone may. Those a rule lets write may
+/// read as well.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub read: Option<Vec<Principal>>,
+    pub write: Option<Vec<Principal>>,
+}
+
+impl Rule {
+    pub fn is_empty(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+}
+
+/// The access rules that don't belong to a named channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Access {
+    /// May do anything, including stopping pwmd. root and the user pwmd runs
+    /// as are always administrators.
+    pub admins: Vec<Principal>,
+    /// Applies to channels that neither their own rule nor their chip's rule
+    /// covers.
+    pub default: Rule,
+    /// By chip, identified like the chip of a named channel.
+    pub chips: BTreeMap<String, Rule>,
+}
+
+/// A rule, with the principals resolved to IDs.
+#[derive(Debug, Default)]
+struct Resolved {
+    read: Option<Vec<Id>>,
+    write: Option<Vec<Id>>,
+}
+
+impl Resolved {
+    fn get(&self, permission: Permission) -> Option<&[Id]> {
+        match permission {
+            Permission::Read => self.read.as_deref(),
+            Permission::Write => self.write.as_deref(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+}
+
+/// The rule of a chip or named channel that wasn't found when pwmd started.
+/// It's looked up again whenever it might apply, so it's in force as soon as
+/// the chip shows up.
+#[derive(Debug)]
+struct Pending {
+    /// The chip or channel, for the log.
+    name: String,
+    chip: String,
+    /// `None` for the rule of a whole chip.
+    channel: Option<u32>,
+    rule: Resolved,
+}
+
+impl Pending {
+    /// Whether the rule applies to a channel of `controller`. Fails if that
+    /// cannot be told, e.g., because the chip is ambiguous.
+    fn applies(&self, pwm: &Pwm, controller: &Controller) -> Result<bool, PwmError> {
+        match pwm.find_controller(&self.chip) {
+            Ok(found) => Ok(found.0 == controller.0),
+            Err(PwmError::UnknownController(_)) | Err(PwmError::ControllerNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The access rules of the configuration file, with the principals resolved
+/// to IDs and the channels and chips to the controllers they're found at when
+/// pwmd starts.
+#[derive(Debug, Default)]
+pub struct Rules {
+    admins: Vec<Id>,
+    default: Resolved,
+    chips: HashMap<u32, Resolved>,
+    channels: HashMap<(u32, u32), Resolved>,
+    pending: Vec<Pending>,
+}
+
+impl Rules {
+    /// Resolves the rules, looking up the names of users and groups in
+    /// `/etc/passwd` and `/etc/group`. Unknown principals are skipped with a
+    /// warning; the rules of chips that cannot be found are kept pending.
+    pub fn resolve(config: &Config, pwm: &Pwm) -> Self {
+        let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let group = fs::read_to_string("/etc/group").unwrap_or_default();
+        Self::resolve_with(config, pwm, &passwd, &group)
+    }
+
+    fn resolve_with(config: &Config, pwm: &Pwm, passwd: &str, group: &str) -> Self {
+        let ids = |principals: &[Principal]| -> Vec<Id> {
+            principals
+                .iter()
+                .filter_map(|principal| {
+                    let id = principal.resolve(passwd, group);
+                    if id.is_none() {
+                        warn!("ignoring unknown user or group {:?}", principal.to_string());
+                    }
+                    id
+                })
+                .collect()
+        };
+        let resolve = |rule: &Rule| Resolved {
+            read: rule.read.as_deref().map(ids),
+            write: rule.write.as_deref().map(ids),
+        };
+        let access = &config.access;
+
+        let mut pending = vec![];
+        let mut chips = HashMap::new();
+        for (chip, rule) in access.chips.iter().filter(|(_, rule)| !rule.is_empty()) {
+            match pwm.find_controller(chip) {
+                Ok(controller) => {
+                    chips.entry(controller.0).or_insert_with(|| resolve(rule));
+                }
+                Err(e) => {
+                    warn!("keeping the access rule of chip {:?} pending: {}", chip, e);
+                    pending.push(Pending {
+                        name: format!("chip {:?}", chip),
+                        chip: chip.clone(),
+                        channel: None,
+                        rule: resolve(rule),
+                    });
+                }
+            }
+        }
+        let mut channels = HashMap::new();
+        for (name, named) in config.channels.iter().filter(|(_, n)| !n.access.is_empty()) {
+            match pwm.locate(named) {
+                Ok((controller, channel)) => {
+                    channels
+                        .entry((controller.0, channel.0))
+                        .or_insert_with(|| resolve(&named.access));
+                }
+                Err(e) => {
+                    warn!(
+                        "keeping the access rule of channel {:?} pending: {}",
+                        name, e
+                    );
+                    pending.push(Pending {
+                        name: format!("channel {:?}", name),
+                        chip: named.chip.clone(),
+                        channel: Some(named.channel.0),
+                        rule: resolve(&named.access),
+                    });
+                }
+            }
+        }
+        Self {
+            admins: ids(&access.admins),
+            default: resolve(&access.default),
+            chips,
+            channels,
+            pending,
+        }
+    }
+
+    /// Whether access to any channel is restricted. If not, there's no need
+    /// to look up who a client is, except to check whether it's an
+    /// administrator.
+    pub fn restricts_access(&self) -> bool {
+        !self.default.is_empty()
+            || !self.chips.is_empty()
+            || !self.channels.is_empty()
+            || !self.pending.is_empty()
+    }
+
+    pub fn is_admin(&self, caller: &Credentials) -> bool {
+        caller.uid == 0
+            || fs::metadata("/proc/self").is_ok_and(|proc| proc.uid() == caller.uid)
+            || self.admins.iter().any(|admin| admin.matches(caller))
+    }
+
+    /// Whether the caller may read from or write to a channel. Those the
+    /// deciding write rule names may read as well.
+    pub fn allows(
+        &self,
+        pwm: &Pwm,
+        caller: &Credentials,
+        permission: Permission,
+        controller: &Controller,
+        channel: &Channel,
+    ) -> bool {
+        let named = |permission| {
+            self.principals(pwm, permission, controller, channel)
+                .map(|ids| ids.iter().any(|id| id.matches(caller)))
+        };
+        self.is_admin(caller)
+            || named(permission).unwrap_or(true)
+            || (permission == Permission::Read && named(Permission::Write) == Some(true))
+    }
+
+    /// Who may read from or write to a channel according to the rule that
+    /// decides, if any: the channel's own rule, then its chip's rule, then
+    /// the default rule.
+    fn principals(
+        &self,
+        pwm: &Pwm,
+        permission: Permission,
+        controller: &Controller,
+        channel: &Channel,
+    ) -> Option<&[Id]> {
+        self.channels
+            .get(&(controller.0, channel.0))
+            .and_then(|rule| rule.get(permission))
+            .or_else(|| self.pending(pwm, permission, controller, Some(channel)))
+            .or_else(|| {
+                self.chips
+                    .get(&controller.0)
+                    .and_then(|rule| rule.get(permission))
+            })
+            .or_else(|| self.pending(pwm, permission, controller, None))
+            .or_else(|| self.default.get(permission))
+    }
+
+    /// Who may read from or write to a channel according to the first pending
+    /// rule of the channel, or of its chip if `channel` is `None`, that
+    /// applies. If it cannot be told whether one applies, only administrators
+    /// may.
+    fn pending(
+        &self,
+        pwm: &Pwm,
+        permission: Permission,
+        controller: &Controller,
+        channel: Option<&Channel>,
+    ) -> Option<&[Id]> {
+        let candidates = self.pending.iter().filter(|pending| {
+            pending.channel == channel.map(|channel| channel.0)
+                && pending.rule.get(permission).is_some()
+        });
+        for pending in candidates {
+            match pending.applies(pwm, controller) {
+                Ok(true) => return pending.rule.get(permission),
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(
+                        "cannot tell whether the access rule of {} applies, \
+                         only administrators may access pwmchip{}: {}",
+                        pending.name, controller.0, e
+                    );
+                    return Some(&[]);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Who a client runs as, as told by the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    /// The user's groups, including its primary group.
+    pub gids: Vec<u32>,
+}
+
+impl Display for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "uid {}", self.uid)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use temp_dir::TempDir;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n\
+                          alice:x:1000:1000::/home/alice:/bin/sh\n\
+                          bob:x:1001:100::/home/bob:/bin/sh\n";
+    const GROUP: &str = "root:x:0:\n\
+                         alice:x:1000:\n\
+                         gpio:x:997:alice,carol\n\
+                         fans:x:998:bob\n";
+
+    #[test]
+    fn resolve_principals_by_name_or_id() {
+        for (principal, id) in [
+            ("alice", Some(Id::User(1000))),
+            ("1001", Some(Id::User(1001))),
+            ("4242", Some(Id::User(4242))),
+            ("@gpio", Some(Id::Group(997))),
+            ("@998", Some(Id::Group(998))),
+            ("@alice", Some(Id::Group(1000))),
+            ("carol", None),
+            ("@wheel", None),
+        ] {
+            let principal = Principal::try_from(principal.to_owned()).unwrap();
+            assert_eq!(principal.resolve(PASSWD, GROUP), id, "{:?}", principal);
+        }
+        assert!(Principal::try_from("@".to_owned()).is_err());
+        assert!(Principal::try_from(String::new()).is_err());
+    }
+
+    #[test]
+    fn resolve_access_rules() {
+        let tmp = TempDir::new().unwrap();
+        for controller in 0..2 {
+            fake_sysfs::chip(tmp.path(), controller, 2);
+        }
+        let pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let config: Config = r#"
+            [channels]
+            heater = { chip = 0, channel = 1, write = ["alice"] }
+
+            [access]
+            admins = ["@fans"]
+            read = ["@gpio"]
+            write = ["@gpio", "dave"]
+
+            [access.chips]
+            pwmchip1 = { write = ["bob"] }
+            pwmchip7 = { write = [] }
+        "#
+        .parse()
+        .unwrap();
+        let rules = Rules::resolve_with(&config, &pwm, PASSWD, GROUP);
+        assert!(rules.restricts_access());
+        assert!(!Rules::resolve_with(&Config::default(), &pwm, PASSWD, GROUP).restricts_access());
+
+        let allows = |uid, gids: &[u32], permission, controller, channel| {
+            let caller = Credentials {
+                uid,
+                gids: gids.to_vec(),
+            };
+            let (controller, channel) = (Controller(controller), Channel(channel));
+            rules.allows(&pwm, &caller, permission, &controller, &channel)
+        };
+        let check = |uid, gids: &[u32], controller, channel| {
+            allows(uid, gids, Permission::Write, controller, channel)
+        };
+        let alice = |controller, channel| check(1000, &[1000, 997], controller, channel);
+        let carol = |controller, channel| check(1002, &[997], controller, channel);
+        let read = |uid, gids: &[u32], controller, channel| {
+            allows(uid, gids, Permission::Read, controller, channel)
+        };
+
+        // the named channel's rule decides:
+        assert!(alice(0, 1));
+        assert!(!carol(0, 1));
+        // ...then the chip's rule:
+        assert!(!alice(1, 0));
+        // ...then the default rule:
+        assert!(alice(0, 0));
+        assert!(carol(0, 0));
+        assert!(!check(1003, &[], 0, 0));
+        // administrators may do anything:
+        assert!(check(1001, &[100, 998], 1, 0));
+        assert!(check(0, &[], 0, 1));
+
+        // without a read rule of its own, the heater falls back to the
+        // default one:
+        assert!(read(1002, &[997], 0, 1));
+        assert!(!read(1003, &[], 0, 1));
+        // ...but those its write rule names may read it:
+        assert!(read(1000, &[1000], 0, 1));
+        // so may bob on pwmchip1, which he may write to:
+        assert!(read(1001, &[100], 1, 0));
+        assert!(!read(1001, &[100], 0, 0));
+
+        // the unknown chip's rule is in force as soon as the chip shows up:
+        assert_eq!(rules.chips.len(), 1);
+        assert!(carol(7, 0));
+        fake_sysfs::chip(tmp.path(), 7, 1);
+        assert!(!carol(7, 0));
+        assert!(alice(0, 0));
+    }
+}
@@ -10,6 +10,26 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    /// What to do at startup with the channel settings recorded in the state
+    /// file.
+    #[derive(Debug)]
+    pub enum Startup {
+        Restore,
+        Reset,
+        Leave
+    }
+}
+
+arg_enum! {
+    /// Who authorizes the methods that change channels.
+    #[derive(Debug)]
+    pub enum Authority {
+        Allow,
+        Polkit
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "pwmd", about = "Exposes PWM chips to DBUS.")]
 pub struct Args {
@@ -21,7 +41,48 @@ pub struct Args {
     #[structopt(long, env, default_value = "com.kevinbader.pwmd")]
     pub dbus_service_name: String,
 
+    /// Path to a TOML configuration file that names channels, e.g.,
+    /// `/etc/pwmd.toml`.
+    #[structopt(long, parse(from_os_str), env = "PWMD_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Where to record the channel settings whenever they are changed through
+    /// pwmd, e.g., `/var/lib/pwmd/state.json`. Nothing is recorded if not set.
+    #[structopt(long, parse(from_os_str), env)]
+    pub state_file: Option<PathBuf>,
+
+    /// What to do at startup with the settings recorded in the state file:
+    /// restore them, reset the recorded channels to safe defaults (disabled,
+    /// zero duty cycle) or leave the channels as they are.
+    #[structopt(long, env, possible_values=&Startup::variants(), case_insensitive=true, default_value = "leave")]
+    pub startup: Startup,
+
+    /// Who authorizes the methods of the pwm1 object that change channels:
+    /// allow all of them or ask polkit, which knows them as actions such as
+    /// `com.kevinbader.pwmd.set-duty-cycle`.
+    #[structopt(long, env, possible_values=&Authority::variants(), case_insensitive=true, default_value = "allow")]
+    pub authority: Authority,
+
+    /// For testing: the bus name of the polkit authority.
+    #[structopt(long, env, default_value = "org.freedesktop.PolicyKit1")]
+    pub polkit_service_name: String,
+
     /// For testing: path to the sysfs pwm class directory.
     #[structopt(long, parse(from_os_str), env)]
     pub sysfs_root: Option<PathBuf>,
+
+    /// For testing: path to the sysfs class directory that contains the
+    /// `thermal` and `hwmon` temperature sensors.
+    #[structopt(long, parse(from_os_str), env)]
+    pub sensor_root: Option<PathBuf>,
+
+    /// How often to check sysfs for changes made outside of pwmd, in
+    /// milliseconds. 0 disables the check.
+    #[structopt(long, env, default_value = "1000")]
+    pub poll_interval_ms: u64,
+
+    /// How often fans controlled by pwmd read their temperature sensor, in
+    /// milliseconds.
+    #[structopt(long, env, default_value = "1000")]
+    pub fan_interval_ms: u64,
 }
@@ -0,0 +1,282 @@
+use tracing::instrument;
+
+use crate::{
+    led::Gamma,
+    pwm::{Channel, Controller, Pwm, PwmError},
+};
+
+/// A color, with red, green and blue between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rgb {
+    pub red: f64,
+    pub green: f64,
+    pub blue: f64,
+}
+
+impl Rgb {
+    pub fn new(red: f64, green: f64, blue: f64) -> Result<Self, PwmError> {
+        if [red, green, blue].iter().all(|c| (0.0..=1.0).contains(c)) {
+            Ok(Self { red, green, blue })
+        } else {
+            Err(PwmError::InvalidColor(
+                "red, green and blue must be between 0 and 1",
+            ))
+        }
+    }
+
+    /// Converts from hue (in degrees), saturation and value, the latter two
+    /// between 0 and 1.
+    pub fn from_hsv(hue: f64, saturation: f64, value: f64) -> Result<Self, PwmError> {
+        if !(0.0..=360.0).contains(&hue) {
+            return Err(PwmError::InvalidColor("hue must be between 0 and 360"));
+        }
+        if !(0.0..=1.0).contains(&saturation) || !(0.0..=1.0).contains(&value) {
+            return Err(PwmError::InvalidColor(
+                "saturation and value must be between 0 and 1",
+            ));
+        }
+        let chroma = value * saturation;
+        let sector = (hue % 360.0) / 60.0;
+        let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+        let (red, green, blue) = match sector as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = value - chroma;
+        Ok(Self {
+            red: red + m,
+            green: green + m,
+            blue: blue + m,
+        })
+    }
+
+    /// Approximates the color of a black body at the given temperature, scaled
+    /// to the given brightness between 0 and 1.
+    ///
+    /// Uses Tanner Helland's approximation, which is good enough for LEDs:
+    /// <https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html>
+    pub fn from_kelvin(kelvin: u32, brightness: f64) -> Result<Self, PwmError> {
+        if !(1000..=40000).contains(&kelvin) {
+            return Err(PwmError::InvalidColor(
+                "color temperature must be between 1000 and 40000 kelvin",
+            ));
+        }
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(PwmError::InvalidBrightness(brightness));
+        }
+        let t = f64::from(kelvin) / 100.0;
+        let red = if t <= 66.0 {
+            255.0
+        } else {
+            329.698727446 * (t - 60.0).powf(-0.1332047592)
+        };
+        let green = if t <= 66.0 {
+            99.4708025861 * t.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (t - 60.0).powf(-0.0755148492)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (t - 10.0).ln() - 305.0447927307
+        };
+        let scale = |c: f64| c.clamp(0.0, 255.0) / 255.0 * brightness;
+        Ok(Self {
+            red: scale(red),
+            green: scale(green),
+            blue: scale(blue),
+        })
+    }
+
+    /// The color at `progress` (between 0 and 1) on the way to `other`.
+    pub fn mix(&self, other: &Rgb, progress: f64) -> Rgb {
+        let mix = |a: f64, b: f64| a + (b - a) * progress;
+        Rgb {
+            red: mix(self.red, other.red),
+            green: mix(self.green, other.green),
+            blue: mix(self.blue, other.blue),
+        }
+    }
+}
+
+/// An RGB or RGBW LED, with each color wired to a separate channel.
+#[derive(Debug, Clone)]
+pub struct RgbLight {
+    /// The red, green, blue and, for RGBW LEDs, white channels.
+    channels: Vec<(Controller, Channel)>,
+    /// A factor between 0 and 1 per channel, used to calibrate the channels
+    /// against each other, such that full red, green and blue appear white.
+    white_balance: Vec<f64>,
+    pub gamma: Gamma,
+}
+
+impl RgbLight {
+    pub fn new(channels: Vec<(Controller, Channel)>) -> Result<Self, PwmError> {
+        if !(3..=4).contains(&channels.len()) {
+            return Err(PwmError::InvalidLight(
+                "expected 3 (RGB) or 4 (RGBW) channels",
+            ));
+        }
+        for (i, (controller, channel)) in channels.iter().enumerate() {
+            if channels[..i]
+                .iter()
+                .any(|(c, ch)| c.0 == controller.0 && ch.0 == channel.0)
+            {
+                return Err(PwmError::InvalidLight("channels must not repeat"));
+            }
+        }
+        Ok(Self {
+            white_balance: vec![1.0; channels.len()],
+            channels,
+            gamma: Gamma::default(),
+        })
+    }
+
+    pub fn channels(&self) -> &[(Controller, Channel)] {
+        &self.channels
+    }
+
+    pub fn white_balance(&self) -> &[f64] {
+        &self.white_balance
+    }
+
+    pub fn set_white_balance(&mut self, white_balance: Vec<f64>) -> Result<(), PwmError> {
+        if white_balance.len() != self.channels.len() {
+            return Err(PwmError::InvalidLight(
+                "expected one white balance factor per channel",
+            ));
+        }
+        if !white_balance.iter().all(|f| (0.0..=1.0).contains(f)) {
+            return Err(PwmError::InvalidLight(
+                "white balance factors must be between 0 and 1",
+            ));
+        }
+        self.white_balance = white_balance;
+        Ok(())
+    }
+
+    /// The brightness of each channel that makes up the color. For RGBW
+    /// LEDs, the white channel takes over the part all colors have in common.
+    pub fn brightness(&self, color: &Rgb) -> Vec<f64> {
+        let levels = if self.channels.len() == 4 {
+            let white = color.red.min(color.green).min(color.blue);
+            vec![
+                color.red - white,
+                color.green - white,
+                color.blue - white,
+                white,
+            ]
+        } else {
+            vec![color.red, color.green, color.blue]
+        };
+        levels
+            .into_iter()
+            .zip(&self.white_balance)
+            .map(|(level, factor)| level * factor)
+            .collect()
+    }
+}
+
+/// Treats a group of channels as an RGB(W) LED.
+impl Pwm {
+    /// Sets the color of an RGB(W) LED, enabling its channels if needed.
+    #[instrument]
+    pub fn set_color(&mut self, light: &RgbLight, color: &Rgb) -> Result<(), PwmError> {
+        for ((controller, channel), brightness) in
+            light.channels.iter().zip(light.brightness(color))
+        {
+            self.set_brightness(controller.clone(), channel.clone(), brightness, light.gamma)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use std::fs;
+    use temp_dir::TempDir;
+
+    fn assert_rgb(actual: Rgb, (red, green, blue): (f64, f64, f64)) {
+        let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+        assert!(
+            close(actual.red, red) && close(actual.green, green) && close(actual.blue, blue),
+            "expected ({}, {}, {}), got {:?}",
+            red,
+            green,
+            blue,
+            actual
+        );
+    }
+
+    #[test]
+    fn convert_colors_to_rgb() {
+        assert_rgb(Rgb::from_hsv(0.0, 1.0, 1.0).unwrap(), (1.0, 0.0, 0.0));
+        assert_rgb(Rgb::from_hsv(120.0, 1.0, 0.5).unwrap(), (0.0, 0.5, 0.0));
+        assert_rgb(Rgb::from_hsv(240.0, 0.5, 1.0).unwrap(), (0.5, 0.5, 1.0));
+        assert_rgb(Rgb::from_hsv(360.0, 1.0, 1.0).unwrap(), (1.0, 0.0, 0.0));
+        assert!(Rgb::from_hsv(361.0, 1.0, 1.0).is_err());
+
+        assert_rgb(Rgb::from_kelvin(6600, 1.0).unwrap(), (1.0, 1.0, 1.0));
+        assert_rgb(Rgb::from_kelvin(2700, 1.0).unwrap(), (1.0, 0.65, 0.34));
+        assert_rgb(Rgb::from_kelvin(6600, 0.5).unwrap(), (0.5, 0.5, 0.5));
+        assert!(Rgb::from_kelvin(500, 1.0).is_err());
+
+        assert!(Rgb::new(1.0, 0.0, 1.5).is_err());
+    }
+
+    #[test]
+    fn use_the_white_channel_and_white_balance() {
+        let channels = |n| (0..n).map(|i| (Controller(0), Channel(i))).collect();
+        let color = Rgb::new(1.0, 0.5, 0.25).unwrap();
+
+        let mut rgb = RgbLight::new(channels(3)).unwrap();
+        assert_eq!(rgb.brightness(&color), [1.0, 0.5, 0.25]);
+        rgb.set_white_balance(vec![1.0, 0.5, 0.8]).unwrap();
+        assert_eq!(rgb.brightness(&color), [1.0, 0.25, 0.2]);
+        assert!(rgb.set_white_balance(vec![1.0, 0.5]).is_err());
+
+        let rgbw = RgbLight::new(channels(4)).unwrap();
+        assert_eq!(rgbw.brightness(&color), [0.75, 0.25, 0.0, 0.25]);
+
+        assert!(RgbLight::new(channels(2)).is_err());
+        assert!(RgbLight::new(vec![
+            (Controller(0), Channel(0)),
+            (Controller(0), Channel(1)),
+            (Controller(0), Channel(0))
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn set_the_color_of_all_channels() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 3);
+        for i in 0..3 {
+            fake_sysfs::channel(&chip, i, 1000, 0);
+        }
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let mut light =
+            RgbLight::new((0..3).map(|i| (Controller(0), Channel(i))).collect()).unwrap();
+        light.gamma = Gamma::new(1.0).unwrap();
+
+        pwm.set_color(&light, &Rgb::new(1.0, 0.5, 0.0).unwrap())
+            .unwrap();
+
+        for (i, duty_cycle) in ["1000", "500", "0"].iter().enumerate() {
+            let channel = chip.join(format!("pwm{}", i));
+            assert_eq!(
+                fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+                *duty_cycle
+            );
+            assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{collections::BTreeMap, fmt::Display, fs, path::Path, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    access::{Access, Principal, Rule},
+    limits::Limits,
+    pwm::{Channel, Controller, Polarity, Pwm, PwmError},
+};
+
+/// What a named channel is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Led,
+    Servo,
+    Fan,
+    #[default]
+    Raw,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Led => write!(f, "led"),
+            Role::Servo => write!(f, "servo"),
+            Role::Fan => write!(f, "fan"),
+            Role::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+/// What happens to a channel when pwmd quits or when the client leasing it
+/// disconnects.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Fallback {
+    /// Leaves the channel as it is.
+    #[default]
+    Keep,
+    Disable,
+    /// Keeps the channel enabled at the given duty ratio, e.g., to keep a fan
+    /// spinning.
+    Duty(f64),
+    /// Unexports the channel if pwmd exported it and disables it otherwise.
+    Unexport,
+}
+
+/// A channel given a name in the configuration file.
+#[derive(Debug, Clone)]
+pub struct NamedChannel {
+    /// Identifies the controller, see `Pwm::find_controller`.
+    pub chip: String,
+    pub channel: Channel,
+    pub role: Role,
+    /// The period set at startup, if any.
+    pub period: Option<Duration>,
+    /// The polarity set at startup, if any.
+    pub polarity: Option<Polarity>,
+    pub shutdown: Fallback,
+    /// Applied when the client leasing the channel disconnects.
+    pub disconnect: Fallback,
+    pub limits: Limits,
+    /// Who may read from and write to the channel.
+    pub access: Rule,
+}
+
+/// The contents of the configuration file, e.g.:
+///
+/// ```toml
+/// [defaults]
+/// period_ns = 1000000
+/// polarity = "normal"
+/// on_shutdown = "disable"
+///
+/// [channels]
+/// status_led = { chip = "1c21400.pwm", channel = 2, role = "led" }
+/// tilt = { chip = 0, channel = 3, role = "servo", period_ns = 20000000 }
+/// fan = { chip = 0, channel = 4, role = "fan", on_shutdown = { duty_percent = 100 } }
+/// motor = { chip = 0, channel = 5, on_disconnect = { duty_percent = 0 }, max_duty_percent = 80 }
+/// heater = { chip = 1, channel = 0, read = ["@users"], write = ["alice", "@heating"] }
+///
+/// [access]
+/// admins = ["@wheel"]
+/// write = ["@pwm"]
+///
+/// [access.chips]
+/// 0 = { write = ["@gpio"] }
+/// ```
+///
+/// The chip is either the controller's number or an identifier that survives
+/// reordered controllers, see `Pwm::find_controller`. The defaults apply to
+/// all named channels that don't set the period, polarity or policies
+/// themselves. `on_shutdown` is one of "keep" (the default), "disable",
+/// "unexport" or a duty cycle in percent. `on_disconnect` takes the same
+/// values but defaults to "disable"; the default also applies to unnamed
+/// channels. `max_duty_percent`, `min_period_ns`, `max_period_ns`,
+/// `lock_polarity` (to the configured polarity) and `read_only` limit what
+/// may be written to a channel, see `Limits`.
+///
+/// `read` and `write` list the users and groups (prefixed with "@") that may
+/// read from and write to a channel; those allowed to write may read as
+/// well. A named channel's lists take precedence over those of its chip in
+/// `[access.chips]`, which take precedence over those in `[access]`. Without
+/// any lists, everyone may. `admins` may do anything, including stopping
+/// pwmd, see `Access`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub channels: BTreeMap<String, NamedChannel>,
+    /// Applied to unnamed channels when the client leasing them disconnects.
+    pub disconnect: Fallback,
+    pub access: Access,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channels: BTreeMap::new(),
+            disconnect: Fallback::Disable,
+            access: Access::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    defaults: Settings,
+    #[serde(default)]
+    channels: BTreeMap<String, ChannelEntry>,
+    #[serde(default)]
+    access: AccessEntry,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessEntry {
+    #[serde(default)]
+    admins: Vec<Principal>,
+    read: Option<Vec<Principal>>,
+    write: Option<Vec<Principal>>,
+    #[serde(default)]
+    chips: BTreeMap<String, Rule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    period_ns: Option<u64>,
+    polarity: Option<String>,
+    on_shutdown: Option<FallbackEntry>,
+    on_disconnect: Option<FallbackEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelEntry {
+    chip: Chip,
+    channel: u32,
+    #[serde(default)]
+    role: Role,
+    period_ns: Option<u64>,
+    polarity: Option<String>,
+    on_shutdown: Option<FallbackEntry>,
+    on_disconnect: Option<FallbackEntry>,
+    max_duty_percent: Option<f64>,
+    min_period_ns: Option<u64>,
+    max_period_ns: Option<u64>,
+    #[serde(default)]
+    lock_polarity: bool,
+    #[serde(default)]
+    read_only: bool,
+    read: Option<Vec<Principal>>,
+    write: Option<Vec<Principal>>,
+}
+
+impl ChannelEntry {
+    /// The limits of the channel, given the period and polarity it's set up
+    /// with.
+    fn limits(
+        &self,
+        period: Option<Duration>,
+        polarity: Option<Polarity>,
+    ) -> anyhow::Result<Limits> {
+        if let Some(max_duty_percent) = self.max_duty_percent {
+            anyhow::ensure!(
+                (0.0..=100.0).contains(&max_duty_percent),
+                "max_duty_percent must be between 0 and 100, got {}",
+                max_duty_percent
+            );
+        }
+        if let (Some(min), Some(max)) = (self.min_period_ns, self.max_period_ns) {
+            anyhow::ensure!(
+                min <= max,
+                "min_period_ns must not be greater than max_period_ns"
+            );
+        }
+        if let Some(period) = period {
+            let period_ns = period.as_nanos() as u64;
+            anyhow::ensure!(
+                self.min_period_ns.is_none_or(|min| period_ns >= min)
+                    && self.max_period_ns.is_none_or(|max| period_ns <= max),
+                "the period of {} ns is outside of min_period_ns and max_period_ns",
+                period_ns
+            );
+        }
+        anyhow::ensure!(
+            !self.lock_polarity || polarity.is_some(),
+            "lock_polarity requires the polarity to be set"
+        );
+        Ok(Limits {
+            max_duty_ratio: self.max_duty_percent.map(|percent| percent / 100.0),
+            min_period: self.min_period_ns.map(Duration::from_nanos),
+            max_period: self.max_period_ns.map(Duration::from_nanos),
+            polarity: polarity.filter(|_| self.lock_polarity),
+            read_only: self.read_only,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FallbackEntry {
+    Named(String),
+    Duty { duty_percent: f64 },
+}
+
+impl FallbackEntry {
+    fn parse(self) -> anyhow::Result<Fallback> {
+        match self {
+            FallbackEntry::Named(name) => name.parse(),
+            FallbackEntry::Duty { duty_percent } => Fallback::duty_percent(duty_percent),
+        }
+    }
+}
+
+impl Fallback {
+    fn duty_percent(duty_percent: f64) -> anyhow::Result<Self> {
+        if !(0.0..=100.0).contains(&duty_percent) {
+            return Err(PwmError::InvalidDutyRatio(duty_percent / 100.0).into());
+        }
+        Ok(Fallback::Duty(duty_percent / 100.0))
+    }
+}
+
+impl FromStr for Fallback {
+    type Err = anyhow::Error;
+
+    /// Parses "keep", "disable", "unexport" or a duty cycle in percent, e.g.,
+    /// "25%".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Fallback::Keep),
+            "disable" => Ok(Fallback::Disable),
+            "unexport" => Ok(Fallback::Unexport),
+            _ => match s.strip_suffix('%').map(|n| n.trim().parse::<f64>()) {
+                Some(Ok(duty_percent)) => Fallback::duty_percent(duty_percent),
+                _ => anyhow::bail!(
+                    "legal policies: 'keep', 'disable', 'unexport', 'N%', {{ duty_percent = N }}"
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Chip {
+    Number(u32),
+    Id(String),
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        contents
+            .parse()
+            .with_context(|| format!("invalid configuration file {:?}", path))
+    }
+
+    /// Looks up a channel by its name.
+    pub fn channel(&self, name: &str) -> Option<&NamedChannel> {
+        self.channels.get(name)
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: File = toml::from_str(s)?;
+        let polarity = |polarity: Option<String>| -> anyhow::Result<Option<Polarity>> {
+            polarity
+                .map(|polarity| polarity.parse::<Polarity>())
+                .transpose()
+                .map_err(anyhow::Error::from)
+        };
+        let default_period = file.defaults.period_ns.map(Duration::from_nanos);
+        let default_polarity = polarity(file.defaults.polarity)?;
+        let default_shutdown = file
+            .defaults
+            .on_shutdown
+            .map(FallbackEntry::parse)
+            .transpose()
+            .context("on_shutdown")?
+            .unwrap_or_default();
+        let default_disconnect = file
+            .defaults
+            .on_disconnect
+            .map(FallbackEntry::parse)
+            .transpose()
+            .context("on_disconnect")?
+            .unwrap_or(Fallback::Disable);
+
+        let mut channels = BTreeMap::new();
+        for (name, entry) in file.channels {
+            anyhow::ensure!(
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "channel names may only contain ASCII letters, digits and underscores, got {:?}",
+                name
+            );
+            let period = entry.period_ns.map(Duration::from_nanos).or(default_period);
+            let polarity = polarity(entry.polarity.clone())
+                .with_context(|| format!("channel {:?}", name))?
+                .or(default_polarity);
+            let limits = entry
+                .limits(period, polarity)
+                .with_context(|| format!("channel {:?}", name))?;
+            let named = NamedChannel {
+                chip: match entry.chip {
+                    Chip::Number(n) => n.to_string(),
+                    Chip::Id(id) => id,
+                },
+                channel: Channel(entry.channel),
+                role: entry.role,
+                period,
+                polarity,
+                shutdown: entry
+                    .on_shutdown
+                    .map(FallbackEntry::parse)
+                    .transpose()
+                    .with_context(|| format!("channel {:?}", name))?
+                    .unwrap_or(default_shutdown),
+                disconnect: entry
+                    .on_disconnect
+                    .map(FallbackEntry::parse)
+                    .transpose()
+                    .with_context(|| format!("channel {:?}", name))?
+                    .unwrap_or(default_disconnect),
+                limits,
+                access: Rule {
+                    read: entry.read,
+                    write: entry.write,
+                },
+            };
+            channels.insert(name, named);
+        }
+        Ok(Self {
+            channels,
+            disconnect: default_disconnect,
+            access: Access {
+                admins: file.access.admins,
+                default: Rule {
+                    read: file.access.read,
+                    write: file.access.write,
+                },
+                chips: file.access.chips,
+            },
+        })
+    }
+}
+
+/// Looks up the channels named in the configuration file.
+impl Pwm {
+    /// Returns the controller and channel a named channel refers to.
+    pub fn locate(&self, named: &NamedChannel) -> Result<(Controller, Channel), PwmError> {
+        let controller = self.find_controller(&named.chip)?;
+        Ok((controller, named.channel.clone()))
+    }
+
+    /// Looks up the name of a channel.
+    pub fn name_of<'a>(
+        &self,
+        config: &'a Config,
+        controller: &Controller,
+        channel: &Channel,
+    ) -> Option<&'a str> {
+        config
+            .channels
+            .iter()
+            .find(|(_, named)| {
+                self.locate(named)
+                    .is_ok_and(|(c, ch)| c.0 == controller.0 && ch.0 == channel.0)
+            })
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn parse_named_channels() {
+        let config: Config = r#"
+            [defaults]
+            period_ns = 1000000
+            polarity = "inversed"
+            on_shutdown = "disable"
+
+            [channels]
+            status_led = { chip = "1c21400.pwm", channel = 2, role = "led" }
+            tilt = { chip = 1, channel = 0, role = "servo", period_ns = 20000000, polarity = "normal", max_duty_percent = 12.5, max_period_ns = 25000000, lock_polarity = true }
+            fan = { chip = 1, channel = 2, role = "fan", on_shutdown = { duty_percent = 100 } }
+            spare = { chip = 1, channel = 1, on_disconnect = "keep" }
+        "#
+        .parse()
+        .unwrap();
+
+        let led = config.channel("status_led").unwrap();
+        assert_eq!((led.chip.as_str(), led.channel.0), ("1c21400.pwm", 2));
+        assert_eq!(led.role, Role::Led);
+        assert_eq!(led.period, Some(Duration::from_millis(1)));
+        assert_eq!(led.polarity, Some(Polarity::Inversed));
+        assert_eq!(led.shutdown, Fallback::Disable);
+        assert_eq!(config.channel("fan").unwrap().shutdown, Fallback::Duty(1.0));
+
+        let tilt = config.channel("tilt").unwrap();
+        assert_eq!(tilt.chip, "1");
+        assert_eq!(tilt.period, Some(Duration::from_millis(20)));
+        assert_eq!(tilt.polarity, Some(Polarity::Normal));
+        assert_eq!(
+            tilt.limits,
+            Limits {
+                max_duty_ratio: Some(0.125),
+                min_period: None,
+                max_period: Some(Duration::from_millis(25)),
+                polarity: Some(Polarity::Normal),
+                read_only: false,
+            }
+        );
+        assert_eq!(led.limits, Limits::default());
+
+        let spare = config.channel("spare").unwrap();
+        assert_eq!(spare.role, Role::Raw);
+        assert_eq!(spare.disconnect, Fallback::Keep);
+        assert_eq!(led.disconnect, Fallback::Disable);
+        assert_eq!(config.disconnect, Fallback::Disable);
+
+        for invalid in [
+            "[channels]\nled = { chip = 0 }",
+            "[channels]\nled = { chip = 0, channel = 0, role = \"lamp\" }",
+            "[channels]\nled = { chip = 0, channel = 0, polarity = \"reversed\" }",
+            "[channels]\n\"a-b\" = { chip = 0, channel = 0 }",
+            "[channels]\nled = { chip = -1, channel = 0 }",
+            "[defaults]\nfrequency = 50",
+            "[defaults]\non_shutdown = \"stop\"",
+            "[defaults]\non_shutdown = { duty_percent = 150 }",
+            "[defaults]\non_disconnect = \"halt\"",
+            "[channels]\nled = { chip = 0, channel = 0, max_duty_percent = 150 }",
+            "[channels]\nled = { chip = 0, channel = 0, min_period_ns = 2, max_period_ns = 1 }",
+            "[channels]\nled = { chip = 0, channel = 0, period_ns = 5, max_period_ns = 4 }",
+            "[channels]\nled = { chip = 0, channel = 0, lock_polarity = true }",
+            "[channels]\nled = { chip = 0, channel = 0, write = [\"@\"] }",
+            "[access]\nadmins = \"alice\"",
+            "[access.chips]\n0 = { execute = [\"alice\"] }",
+        ] {
+            assert!(
+                invalid.parse::<Config>().is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn parse_fallback_policies() {
+        assert_eq!("keep".parse::<Fallback>().unwrap(), Fallback::Keep);
+        assert_eq!("unexport".parse::<Fallback>().unwrap(), Fallback::Unexport);
+        assert_eq!("25%".parse::<Fallback>().unwrap(), Fallback::Duty(0.25));
+        assert_eq!("0 %".parse::<Fallback>().unwrap(), Fallback::Duty(0.0));
+        for invalid in ["stop", "25", "150%", "%"] {
+            assert!(
+                invalid.parse::<Fallback>().is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+}
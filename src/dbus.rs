@@ -1,22 +1,137 @@
-use std::{convert::TryInto, sync::Arc, time::Duration};
+use std::{collections::HashSet, convert::TryInto, path::PathBuf, sync::Arc, time::Duration};
 
-use tokio::sync::Notify;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{Mutex, Notify},
+};
 use tracing::{debug, info, instrument, warn};
-use zbus::{dbus_interface, fdo, names::WellKnownName, Connection, ConnectionBuilder};
+use zbus::{
+    dbus_interface, fdo, names::WellKnownName, zvariant::OwnedObjectPath, Connection,
+    ConnectionBuilder, MessageHeader, ObjectServer, SignalContext,
+};
 
-use crate::args::{Args, Bus};
-use crate::pwm::{Channel, Controller, Polarity, Pwm};
+use crate::access::Rules;
+use crate::args::{self, Args, Bus, Startup};
+use crate::color::RgbLight;
+use crate::config::{Config, Fallback};
+use crate::motor::{Motor, Wiring};
+use crate::pwm::{Channel, Controller, Polarity, Pwm, PwmError, PwmState, Timing};
+use crate::state::Snapshot;
+
+/// Who may call which methods
+mod access;
+/// Authorization of the methods that change channels
+mod authority;
+/// Buzzer interface of the channel objects
+mod buzzer;
+/// Per-channel objects
+mod channel;
+/// Per-controller objects
+mod controller;
+/// What the methods fail with
+mod error;
+/// Fallback policies of channels
+mod fallback;
+/// Fan interface of the channel objects
+mod fan;
+/// Exclusive write access to channels for the lifetime of a client
+mod lease;
+/// LED interface of the channel objects
+mod led;
+/// RGB(W) LEDs made up of several channels
+mod light;
+/// DC motors driven by a pair of channels
+mod motor;
+/// Replies to `Set` with the error the property setter failed with
+mod properties;
+/// Servo interface of the channel objects
+mod servo;
+/// Sets up the named channels at startup
+mod setup;
+/// Leaves the named channels as configured when pwmd quits
+mod shutdown;
+/// Background tasks driving channels
+mod tasks;
+/// Keeps the published objects in line with sysfs
+mod tree;
+/// Fallbacks for channels whose client stops responding
+mod watchdog;
+
+use authority::Action;
+use channel::{ChannelState, Property};
+use error::{Error, Result};
+use properties::Properties;
+
+/// All objects are published below this path.
+const ROOT_PATH: &str = "/com/kevinbader/pwmd";
 
 /// Expose DBUS interface and block on handling connections.
 pub async fn listen(args: Args, on_ready: impl FnOnce()) -> anyhow::Result<()> {
     debug!(?args);
-    let pwm = match args.sysfs_root {
+    let mut pwm = match args.sysfs_root {
         Some(sysfs_root) => Pwm::with_sysfs_root(sysfs_root),
         None => Pwm::new(),
     };
     debug!(?pwm);
+    let config = match args.config {
+        Some(path) => Config::from_file(&path)?,
+        None => Config::default(),
+    };
+    debug!(?config);
+    let rules = Rules::resolve(&config, &pwm);
+    debug!(?rules);
+    // Only channels exported by pwmd are unexported on shutdown.
+    let exported_before = pwm.exported_pairs()?;
+    // The recorded settings are restored before the named channels' limits
+    // apply, which would reject them otherwise:
+    let snapshot = match &args.state_file {
+        Some(path) => Snapshot::load(path)?,
+        None => None,
+    };
+    match (snapshot, args.startup) {
+        (Some(snapshot), Startup::Restore) => pwm.restore(&snapshot),
+        (Some(snapshot), Startup::Reset) => pwm.reset(&snapshot),
+        (Some(_), Startup::Leave) | (None, _) => {}
+    }
+    pwm.set_up_all(&config);
+    if let Some(path) = &args.state_file {
+        pwm.snapshot()?.save(path)?;
+    }
+    anyhow::ensure!(
+        args.fan_interval_ms > 0,
+        "the fan interval must be greater than zero"
+    );
+    let fans = fan::Fans::new(
+        args.sensor_root
+            .unwrap_or_else(|| PathBuf::from("/sys/class")),
+        Duration::from_millis(args.fan_interval_ms),
+    );
+    let authority: Box<dyn authority::Authority> = match args.authority {
+        args::Authority::Allow => Box::new(authority::AllowAll),
+        args::Authority::Polkit => Box::new(authority::Polkit {
+            service_name: args.polkit_service_name,
+        }),
+    };
+    let shared = Arc::new(Shared {
+        pwm: Mutex::new(pwm),
+        config,
+        rules,
+        state_file: args.state_file,
+        exported_before,
+        authority,
+        tree: tree::Tree::default(),
+        fans,
+        leases: lease::Leases::default(),
+        leds: led::Leds::default(),
+        lights: light::Lights::default(),
+        motors: motor::Motors::default(),
+        servos: servo::Servos::default(),
+        tasks: tasks::Tasks::default(),
+        watchdogs: watchdog::Watchdogs::default(),
+        runtime: tokio::runtime::Handle::current(),
+    });
     let pwm_api = PwmApi {
-        pwm,
+        shared: shared.clone(),
         done: Arc::new(Notify::new()),
     };
     let done = pwm_api.done.clone();
@@ -25,10 +140,32 @@ pub async fn listen(args: Args, on_ready: impl FnOnce()) -> anyhow::Result<()> {
         Bus::Session => ConnectionBuilder::session()?.build().await?,
         Bus::System => ConnectionBuilder::system()?.build().await?,
     };
-    connection
-        .object_server_mut()
-        .await
-        .at("/com/kevinbader/pwmd/pwm1", pwm_api)?;
+    {
+        let mut server = connection.object_server_mut().await;
+        server.at(ROOT_PATH, tree::ObjectManager::new(shared.clone()))?;
+        Properties::publish(&mut server, ROOT_PATH, &shared)?;
+        let pwm1_path = format!("{}/pwm1", ROOT_PATH);
+        server.at(pwm1_path.as_str(), pwm_api)?;
+        Properties::publish(&mut server, &pwm1_path, &shared)?;
+    }
+    tree::sync(&shared, &connection).await?;
+    let tree_sync = tokio::spawn(tree::keep_in_sync(shared.clone(), connection.clone()));
+    let poll = match args.poll_interval_ms {
+        0 => None,
+        ms => Some(tokio::spawn(tree::poll(
+            shared.clone(),
+            Duration::from_millis(ms),
+        ))),
+    };
+    let owner_changes = fdo::DBusProxy::new(&connection)
+        .await?
+        .receive_name_owner_changed()
+        .await?;
+    let leases = tokio::spawn(lease::watch_owners(
+        shared.clone(),
+        connection.clone(),
+        owner_changes,
+    ));
     let name: WellKnownName = args
         .dbus_service_name
         .as_str()
@@ -36,146 +173,1247 @@ pub async fn listen(args: Args, on_ready: impl FnOnce()) -> anyhow::Result<()> {
         .expect("invalid dbus name");
     connection.request_name(name).await?;
 
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
     on_ready();
 
-    done.notified().await;
+    tokio::select! {
+        _ = done.notified() => {}
+        _ = sigterm.recv() => info!("received SIGTERM"),
+        _ = sigint.recv() => info!("received SIGINT"),
+    }
+    tree_sync.abort();
+    leases.abort();
+    if let Some(poll) = poll {
+        poll.abort();
+    }
+    // Records the changes made right before, e.g., those made by the client
+    // that asked pwmd to quit:
+    if let Err(e) = tree::sync(&shared, &connection).await {
+        warn!("failed to sync objects: {:?}", e);
+    }
+
+    // Leave the hardware in a defined state:
+    shared.watchdogs.disarm_all().await;
+    shared.tasks.stop_all().await;
+    shared
+        .pwm
+        .lock()
+        .await
+        .shut_down_all(&shared.config, &shared.exported_before);
 
     Ok(())
 }
 
+/// State shared by all DBUS objects.
+#[derive(Debug)]
+struct Shared {
+    pwm: Mutex<Pwm>,
+    /// The channels named in the configuration file.
+    config: Config,
+    /// The access rules of the configuration file, resolved at startup.
+    rules: Rules,
+    /// Where to record the channel settings, if anywhere.
+    state_file: Option<PathBuf>,
+    /// The channels that were exported when pwmd started. pwmd leaves them
+    /// exported when applying a policy.
+    exported_before: HashSet<(u32, u32)>,
+    authority: Box<dyn authority::Authority>,
+    tree: tree::Tree,
+    fans: fan::Fans,
+    leases: lease::Leases,
+    leds: led::Leds,
+    lights: light::Lights,
+    motors: motor::Motors,
+    servos: servo::Servos,
+    tasks: tasks::Tasks,
+    watchdogs: watchdog::Watchdogs,
+    /// Used to spawn tasks from method handlers, which zbus doesn't run on the
+    /// tokio runtime.
+    runtime: tokio::runtime::Handle,
+}
+
 #[derive(Debug)]
 struct PwmApi {
-    pwm: Pwm,
+    shared: Arc<Shared>,
     done: Arc<Notify>,
 }
 
 #[dbus_interface(name = "com.kevinbader.pwmd.pwm1")]
 impl PwmApi {
-    #[instrument]
-    async fn quit(&mut self) {
+    /// Stops pwmd, which applies the shutdown policies of the named channels.
+    /// Only administrators may call this.
+    #[instrument(skip(connection, header))]
+    async fn quit(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Quit)
+            .await?;
+        self.shared.check_admin(connection, &header).await?;
         info!("quit");
         self.done.notify_one();
+        Ok(())
     }
 
+    /// Lists all controllers as (controller, npwm, exported channels, device).
+    /// The device is empty if the controller has no `device` symlink.
     #[instrument]
-    async fn npwm(&self, controller: u32) -> fdo::Result<u32> {
-        let controller = Controller(controller);
-        self.pwm.npwm(&controller).map_err(|e| {
+    async fn list_controllers(&self) -> Result<Vec<(u32, u32, Vec<u32>, String)>> {
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .controllers()
+            .map(|controllers| {
+                controllers
+                    .into_iter()
+                    .map(|info| {
+                        (
+                            info.controller.0,
+                            info.npwm,
+                            info.exported.into_iter().map(|channel| channel.0).collect(),
+                            info.device
+                                .map(|device| device.to_string_lossy().into_owned())
+                                .unwrap_or_default(),
+                        )
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    /// Returns the number of the controller that matches the given
+    /// identifier: the path or name of its device (e.g., `1c21400.pwm`), its
+    /// device-tree node (e.g., `/soc/pwm@1c21400`) or its driver. Unlike
+    /// controller numbers, these don't change when the kernel probes the
+    /// controllers in a different order.
+    #[instrument]
+    async fn find_controller(&self, id: String) -> Result<u32> {
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .find_controller(&id)
+            .map(|controller| controller.0)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    /// Lists the channels named in the configuration file as (name,
+    /// controller, channel, role). Channels whose controller cannot be found
+    /// are left out.
+    #[instrument]
+    async fn list_named_channels(&self) -> Vec<(String, u32, u32, String)> {
+        let pwm = self.shared.pwm.lock().await;
+        self.shared
+            .config
+            .channels
+            .iter()
+            .filter_map(|(name, named)| match pwm.locate(named) {
+                Ok((controller, channel)) => Some((
+                    name.clone(),
+                    controller.0,
+                    channel.0,
+                    named.role.to_string(),
+                )),
+                Err(e) => {
+                    warn!("channel {:?}: {}", name, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the path of the object of a channel named in the
+    /// configuration file.
+    #[instrument]
+    async fn get_channel_by_name(&self, name: String) -> Result<OwnedObjectPath> {
+        let named =
+            self.shared.config.channel(&name).ok_or_else(|| {
+                fdo::Error::UnknownObject(format!("channel {:?} not found", name))
+            })?;
+        let (controller, channel) = self.shared.pwm.lock().await.locate(named).map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
-        })
+            Error::Pwm(e)
+        })?;
+        tree::channel_path(controller.0, channel.0)
+            .try_into()
+            .map_err(|e| fdo::Error::Failed(format!("{:?}", e)).into())
     }
 
     #[instrument]
-    async fn is_exported(&self, controller: u32) -> fdo::Result<bool> {
+    async fn npwm(&self, controller: u32) -> Result<u32> {
         let controller = Controller(controller);
-        self.pwm.is_exported(&controller).map_err(|e| {
+        self.shared.pwm.lock().await.npwm(&controller).map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
+            Error::Pwm(e)
         })
     }
 
+    /// Whether all channels of a controller are exported.
     #[instrument]
-    async fn export(&mut self, controller: u32) -> fdo::Result<()> {
+    async fn is_exported(&self, controller: u32) -> Result<bool> {
+        let controller = Controller(controller);
+        let pwm = self.shared.pwm.lock().await;
+        pwm.exported_channels(&controller)
+            .and_then(|exported| Ok(exported.len() == pwm.npwm(&controller)? as usize))
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    /// Exports all channels of a controller.
+    #[instrument(skip(connection, header))]
+    async fn export(
+        &mut self,
+        controller: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Export)
+            .await?;
         let controller = Controller(controller);
-        self.pwm.export(controller).map_err(|e| {
+        let channels = all_channels(&self.shared, &controller).await?;
+        self.shared
+            .check_write(connection, &header, &channels)
+            .await?;
+        let res = self.shared.pwm.lock().await.export_all(controller);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
+            Error::Pwm(e)
         })
     }
 
-    #[instrument]
-    async fn unexport(&mut self, controller: u32) -> fdo::Result<()> {
+    /// Unexports all channels of a controller.
+    #[instrument(skip(connection, header))]
+    async fn unexport(
+        &mut self,
+        controller: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Unexport)
+            .await?;
         let controller = Controller(controller);
-        self.pwm.unexport(controller).map_err(|e| {
+        let channels = all_channels(&self.shared, &controller).await?;
+        self.shared
+            .check_write(connection, &header, &channels)
+            .await?;
+        let res = self.shared.pwm.lock().await.unexport_all(controller);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
+            Error::Pwm(e)
         })
     }
 
-    async fn is_enabled(&self, controller: u32, channel: u32) -> fdo::Result<bool> {
+    #[instrument(skip(connection, header))]
+    async fn is_channel_exported(
+        &self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<bool> {
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_read(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .is_exported(&controller, &channel)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    #[instrument(skip(connection, header))]
+    async fn export_channel(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Export)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
-        self.pwm.is_enabled(&controller, &channel).map_err(|e| {
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        let res = self.shared.pwm.lock().await.export(controller, channel);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
+            Error::Pwm(e)
         })
     }
 
-    #[instrument]
-    async fn enable(&mut self, controller: u32, channel: u32) -> fdo::Result<()> {
+    #[instrument(skip(connection, header))]
+    async fn unexport_channel(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Unexport)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
-        self.pwm.enable(controller, channel).map_err(|e| {
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        let res = self.shared.pwm.lock().await.unexport(controller, channel);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
+            Error::Pwm(e)
         })
     }
 
-    #[instrument]
-    async fn disable(&mut self, controller: u32, channel: u32) -> fdo::Result<()> {
+    /// Gives the caller exclusive write access to a channel until it calls
+    /// `ReleaseChannel` or disconnects from the bus. Other clients can still
+    /// read the channel, but their writes, including property changes, fail
+    /// with AccessDenied. When the caller disconnects, the channel's
+    /// `on_disconnect` policy is applied; when the channel is unexported, the
+    /// lease ends. Stops the task driving the channel, if any.
+    #[instrument(skip(connection, header))]
+    async fn acquire_channel(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::LeaseChannel)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
-        self.pwm.disable(controller, channel).map_err(|e| {
+        // Fails for channels that don't exist:
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .is_exported(&controller, &channel)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .leases
+            .acquire(connection, &controller, &channel, &header)
+            .await?;
+        self.shared.tasks.stop(&controller, &channel).await;
+        Ok(())
+    }
+
+    /// Ends the caller's lease on a channel, leaving the channel as it is.
+    #[instrument(skip(connection, header))]
+    async fn release_channel(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::LeaseChannel)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .leases
+            .release(&controller, &channel, &header)
+            .await?;
+        Ok(())
+    }
+
+    /// Arms a watchdog for a channel, replacing the one armed before, if any.
+    /// Unless `Kick` is called for the channel at least every `timeout_ms`,
+    /// the fallback is applied and `WatchdogExpired` is emitted. The fallback
+    /// is "disable", "unexport", "keep" or a duty cycle in percent, e.g.,
+    /// "0%".
+    #[instrument(skip(connection, header))]
+    async fn arm_watchdog(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        timeout_ms: u32,
+        fallback: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::ManageWatchdogs)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        if timeout_ms == 0 {
+            return Err(fdo::Error::InvalidArgs(
+                "the timeout must be greater than zero".to_owned(),
+            )
+            .into());
+        }
+        let fallback = fallback.parse::<Fallback>().map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::Failed(e.to_string())
-        })
+            fdo::Error::InvalidArgs(e.to_string())
+        })?;
+        // Fails for channels that don't exist:
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .is_exported(&controller, &channel)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        self.shared
+            .watchdogs
+            .arm(
+                self.shared.clone(),
+                connection.clone(),
+                controller,
+                channel,
+                Duration::from_millis(timeout_ms.into()),
+                fallback,
+            )
+            .await;
+        Ok(())
     }
 
-    #[instrument]
+    /// Restarts the timer of a channel's watchdog. Fails if no watchdog is
+    /// armed for the channel, e.g., because it expired already.
+    #[instrument(skip(connection, header))]
+    async fn kick(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::ManageWatchdogs)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        if self.shared.watchdogs.kick(&controller, &channel).await {
+            Ok(())
+        } else {
+            Err(no_watchdog(&controller, &channel))
+        }
+    }
+
+    /// Disarms a channel's watchdog, leaving the channel as it is.
+    #[instrument(skip(connection, header))]
+    async fn disarm_watchdog(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::ManageWatchdogs)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        if self.shared.watchdogs.disarm(&controller, &channel).await {
+            Ok(())
+        } else {
+            Err(no_watchdog(&controller, &channel))
+        }
+    }
+
+    /// Emitted when a channel's watchdog expired and its fallback was
+    /// applied.
+    #[dbus_interface(signal)]
+    async fn watchdog_expired(
+        ctxt: &SignalContext<'_>,
+        controller: u32,
+        channel: u32,
+    ) -> zbus::Result<()>;
+
+    async fn is_enabled(
+        &self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<bool> {
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_read(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .is_enabled(&controller, &channel)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    #[instrument(skip(server, connection, header))]
+    async fn enable(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::SetEnabled)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared.tasks.stop(&controller, &channel).await;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .enable(controller.clone(), channel.clone())
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        emit_changed(
+            &self.shared,
+            server,
+            &controller,
+            &channel,
+            Property::Enabled,
+        )
+        .await;
+        Ok(())
+    }
+
+    #[instrument(skip(server, connection, header))]
+    async fn disable(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::SetEnabled)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared.tasks.stop(&controller, &channel).await;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .disable(controller.clone(), channel.clone())
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        emit_changed(
+            &self.shared,
+            server,
+            &controller,
+            &channel,
+            Property::Enabled,
+        )
+        .await;
+        Ok(())
+    }
+
+    #[instrument(skip(connection, header))]
+    async fn get_period_ns(
+        &self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<u64> {
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_read(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .period(&controller, &channel)
+            .map(|period| period.as_nanos() as u64)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })
+    }
+
+    #[instrument(skip(server, connection, header))]
     async fn set_period_ns(
         &mut self,
         controller: u32,
         channel: u32,
         period: u64,
-    ) -> fdo::Result<()> {
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::SetPeriod)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared.tasks.stop(&controller, &channel).await;
         let period = Duration::from_nanos(period);
-        self.pwm
-            .set_period(controller, channel, period)
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .set_period(controller.clone(), channel.clone(), period)
             .map_err(|e| {
                 warn!("{:?}", e);
-                fdo::Error::Failed(e.to_string())
+                Error::Pwm(e)
+            })?;
+        emit_changed(
+            &self.shared,
+            server,
+            &controller,
+            &channel,
+            Property::Period,
+        )
+        .await;
+        Ok(())
+    }
+
+    #[instrument(skip(connection, header))]
+    async fn get_duty_cycle_ns(
+        &self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<u64> {
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_read(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .duty_cycle(&controller, &channel)
+            .map(|duty_cycle| duty_cycle.as_nanos() as u64)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
             })
     }
 
-    #[instrument]
+    #[instrument(skip(server, connection, header))]
     async fn set_duty_cycle_ns(
         &mut self,
         controller: u32,
         channel: u32,
         duty_cycle: u64,
-    ) -> fdo::Result<()> {
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::SetDutyCycle)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared.tasks.stop(&controller, &channel).await;
         let duty_cycle = Duration::from_nanos(duty_cycle);
-        self.pwm
-            .set_duty_cycle(controller, channel, duty_cycle)
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .set_duty_cycle(controller.clone(), channel.clone(), duty_cycle)
             .map_err(|e| {
                 warn!("{:?}", e);
-                fdo::Error::Failed(e.to_string())
+                Error::Pwm(e)
+            })?;
+        emit_changed(
+            &self.shared,
+            server,
+            &controller,
+            &channel,
+            Property::DutyCycle,
+        )
+        .await;
+        Ok(())
+    }
+
+    #[instrument(skip(connection, header))]
+    async fn get_polarity(
+        &self,
+        controller: u32,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<String> {
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_read(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .polarity(&controller, &channel)
+            .map(|polarity| polarity.to_string())
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
             })
     }
 
-    #[instrument]
+    #[instrument(skip(server, connection, header))]
     async fn set_polarity(
         &mut self,
         controller: u32,
         channel: u32,
         polarity: String,
-    ) -> fdo::Result<()> {
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::SetPolarity)
+            .await?;
         let controller = Controller(controller);
         let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
         let polarity = polarity.parse::<Polarity>().map_err(|e| {
             warn!("{:?}", e);
-            fdo::Error::InvalidArgs(e.to_string())
+            Error::Pwm(e)
         })?;
-        self.pwm
-            .set_polarity(controller, channel, polarity)
+        self.shared.tasks.stop(&controller, &channel).await;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .set_polarity(controller.clone(), channel.clone(), polarity)
             .map_err(|e| {
                 warn!("{:?}", e);
-                fdo::Error::Failed(e.to_string())
-            })
+                Error::Pwm(e)
+            })?;
+        emit_changed(
+            &self.shared,
+            server,
+            &controller,
+            &channel,
+            Property::Polarity,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Changes period, duty cycle, polarity and the enabled state of a channel
+    /// at once. The changes are applied in an order that is accepted by the
+    /// kernel; if one of them fails, the others are rolled back.
+    #[instrument(skip(server, connection, header))]
+    #[allow(clippy::too_many_arguments)]
+    async fn apply_state(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        period_ns: u64,
+        duty_cycle_ns: u64,
+        polarity: String,
+        enabled: bool,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Configure)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        let state = PwmState {
+            period: Duration::from_nanos(period_ns),
+            duty_cycle: Duration::from_nanos(duty_cycle_ns),
+            polarity: polarity.parse::<Polarity>().map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?,
+            enabled,
+        };
+        change_channel(&self.shared, server, &controller, &channel, |pwm| {
+            pwm.apply(controller.clone(), channel.clone(), state)
+        })
+        .await
+    }
+
+    /// Sets the frequency of a channel, keeping its duty ratio. Returns the
+    /// period and duty cycle in nanoseconds after rounding, as well as the
+    /// resulting frequency and duty cycle percentage.
+    #[instrument(skip(server, connection, header))]
+    async fn set_frequency_hz(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        frequency_hz: f64,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<(u64, u64, f64, f64)> {
+        self.shared
+            .authorize(connection, &header, Action::SetPeriod)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        change_channel(&self.shared, server, &controller, &channel, |pwm| {
+            pwm.set_frequency(controller.clone(), channel.clone(), frequency_hz)
+        })
+        .await
+        .map(timing_reply)
+    }
+
+    /// Sets the duty cycle of a channel as a percentage of its period. Returns
+    /// the same values as `SetFrequencyHz`.
+    #[instrument(skip(server, connection, header))]
+    async fn set_duty_percent(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        duty_percent: f64,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<(u64, u64, f64, f64)> {
+        self.shared
+            .authorize(connection, &header, Action::SetDutyCycle)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        change_channel(&self.shared, server, &controller, &channel, |pwm| {
+            pwm.set_duty_ratio(controller.clone(), channel.clone(), duty_percent / 100.0)
+        })
+        .await
+        .map(timing_reply)
+    }
+
+    /// Sets frequency and duty cycle percentage of a channel at once. Returns
+    /// the same values as `SetFrequencyHz`.
+    #[instrument(skip(server, connection, header))]
+    #[allow(clippy::too_many_arguments)]
+    async fn configure(
+        &mut self,
+        controller: u32,
+        channel: u32,
+        frequency_hz: f64,
+        duty_percent: f64,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<(u64, u64, f64, f64)> {
+        self.shared
+            .authorize(connection, &header, Action::Configure)
+            .await?;
+        let controller = Controller(controller);
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(controller.clone(), channel.clone())],
+            )
+            .await?;
+        change_channel(&self.shared, server, &controller, &channel, |pwm| {
+            pwm.configure(
+                controller.clone(),
+                channel.clone(),
+                frequency_hz,
+                duty_percent / 100.0,
+            )
+        })
+        .await
+        .map(timing_reply)
+    }
+
+    /// Declares an RGB or RGBW LED made up of the given red, green, blue
+    /// and, optionally, white channels, given as pairs of controller and
+    /// channel numbers. Returns the path of the object published for it.
+    #[instrument(skip(connection, header))]
+    async fn add_rgb_light(
+        &mut self,
+        name: String,
+        channels: Vec<(u32, u32)>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<OwnedObjectPath> {
+        self.shared
+            .authorize(connection, &header, Action::ManageDevices)
+            .await?;
+        let channels: Vec<_> = channels
+            .into_iter()
+            .map(|(controller, channel)| (Controller(controller), Channel(channel)))
+            .collect();
+        {
+            let pwm = self.shared.pwm.lock().await;
+            for (controller, channel) in &channels {
+                // Fails for channels that don't exist:
+                pwm.is_exported(controller, channel).map_err(|e| {
+                    warn!("{:?}", e);
+                    Error::Pwm(e)
+                })?;
+            }
+        }
+        self.shared
+            .check_write(connection, &header, &channels)
+            .await?;
+        let light = RgbLight::new(channels).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        let path = light::light_path(&name);
+        self.shared.lights.add(name, light).await?;
+        self.shared.tree.refresh();
+        path.try_into()
+            .map_err(|e| fdo::Error::Failed(format!("{:?}", e)).into())
+    }
+
+    /// Removes an RGB(W) LED declared using `AddRgbLight`. Its channels are
+    /// left as they are.
+    #[instrument(skip(connection, header))]
+    async fn remove_rgb_light(
+        &mut self,
+        name: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::ManageDevices)
+            .await?;
+        let (light, _) = self.shared.lights.get(&name).await?;
+        self.shared
+            .check_write(connection, &header, light.channels())
+            .await?;
+        self.shared.lights.remove(&name).await?;
+        for (controller, channel) in light.channels() {
+            self.shared.tasks.stop(controller, channel).await;
+        }
+        self.shared.tree.refresh();
+        Ok(())
+    }
+
+    /// Declares a DC motor driven by two channels, given as pairs of
+    /// controller and channel numbers. With "direction" wiring, the first
+    /// channel sets the speed and the second one the direction; with "dual"
+    /// wiring, the first channel drives the motor forward and the second one
+    /// in reverse. Returns the path of the object published for it.
+    #[instrument(skip(connection, header))]
+    async fn add_motor(
+        &mut self,
+        name: String,
+        wiring: String,
+        channels: Vec<(u32, u32)>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<OwnedObjectPath> {
+        self.shared
+            .authorize(connection, &header, Action::ManageDevices)
+            .await?;
+        let channels: Vec<_> = channels
+            .into_iter()
+            .map(|(controller, channel)| (Controller(controller), Channel(channel)))
+            .collect();
+        {
+            let pwm = self.shared.pwm.lock().await;
+            for (controller, channel) in &channels {
+                // Fails for channels that don't exist:
+                pwm.is_exported(controller, channel).map_err(|e| {
+                    warn!("{:?}", e);
+                    Error::Pwm(e)
+                })?;
+            }
+        }
+        self.shared
+            .check_write(connection, &header, &channels)
+            .await?;
+        let motor = wiring
+            .parse::<Wiring>()
+            .and_then(|wiring| Motor::new(channels, wiring))
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        let path = motor::motor_path(&name);
+        self.shared.motors.add(name, motor).await?;
+        self.shared.tree.refresh();
+        path.try_into()
+            .map_err(|e| fdo::Error::Failed(format!("{:?}", e)).into())
+    }
+
+    /// Removes a motor declared using `AddMotor`. Its channels are left as
+    /// they are, so stop the motor first.
+    #[instrument(skip(connection, header))]
+    async fn remove_motor(
+        &mut self,
+        name: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::ManageDevices)
+            .await?;
+        let motor = self.shared.motors.get(&name).await?;
+        self.shared
+            .check_write(connection, &header, motor.channels())
+            .await?;
+        self.shared.motors.remove(&name).await?;
+        for (controller, channel) in motor.channels() {
+            self.shared.tasks.stop(controller, channel).await;
+        }
+        self.shared.tree.refresh();
+        Ok(())
+    }
+}
+
+/// Applies `change` to a channel and lets subscribers know about every
+/// property it changed. Stops the task driving the channel, if any.
+async fn change_channel<T>(
+    shared: &Shared,
+    server: &ObjectServer,
+    controller: &Controller,
+    channel: &Channel,
+    change: impl FnOnce(&mut Pwm) -> Result<T, PwmError>,
+) -> Result<T> {
+    shared.tasks.stop(controller, channel).await;
+    let (before, after, result) = {
+        let mut pwm = shared.pwm.lock().await;
+        let before = ChannelState::read(&pwm, controller, channel);
+        let result = change(&mut pwm).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        (
+            before,
+            ChannelState::read(&pwm, controller, channel),
+            result,
+        )
+    };
+    for property in before.changes(&after) {
+        emit_changed(shared, server, controller, channel, property).await;
+    }
+    Ok(result)
+}
+
+/// Checks the name of an object declared by a client, e.g., an RGB(W) LED,
+/// which becomes part of the object's path.
+fn check_name(name: &str) -> fdo::Result<()> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(fdo::Error::InvalidArgs(format!(
+            "names may only contain letters, digits and underscores, got {:?}",
+            name
+        )))
+    }
+}
+
+/// All channels of a controller, exported or not.
+async fn all_channels(
+    shared: &Shared,
+    controller: &Controller,
+) -> Result<Vec<(Controller, Channel)>> {
+    let npwm = shared.pwm.lock().await.npwm(controller).map_err(|e| {
+        warn!("{:?}", e);
+        Error::Pwm(e)
+    })?;
+    Ok((0..npwm)
+        .map(|channel| (controller.clone(), Channel(channel)))
+        .collect())
+}
+
+fn no_watchdog(controller: &Controller, channel: &Channel) -> Error {
+    fdo::Error::Failed(format!(
+        "no watchdog is armed for pwmchip{}/pwm{}",
+        controller.0, channel.0
+    ))
+    .into()
+}
+
+/// Period and duty cycle in nanoseconds, frequency in Hz and duty cycle in
+/// percent.
+fn timing_reply(timing: Timing) -> (u64, u64, f64, f64) {
+    (
+        timing.period.as_nanos() as u64,
+        timing.duty_cycle.as_nanos() as u64,
+        timing.frequency_hz(),
+        timing.duty_ratio() * 100.0,
+    )
+}
+
+/// Records the settings of all channels in the state file, if any. Failing to
+/// do so doesn't fail the change that led to it. Called by `tree::sync`.
+async fn save_state(shared: &Shared) {
+    if let Some(path) = &shared.state_file {
+        let snapshot = shared.pwm.lock().await.snapshot();
+        let res = snapshot
+            .map_err(anyhow::Error::from)
+            .and_then(|snapshot| snapshot.save(path));
+        if let Err(e) = res {
+            warn!("failed to record channel settings: {:?}", e);
+        }
+    }
+}
+
+/// Lets subscribers know about a change to a channel's property. Failing to
+/// do so doesn't fail the change itself.
+async fn emit_changed(
+    shared: &Shared,
+    server: &ObjectServer,
+    controller: &Controller,
+    channel: &Channel,
+    property: Property,
+) {
+    let state = ChannelState::read(&*shared.pwm.lock().await, controller, channel);
+    shared.tree.record(controller, channel, state).await;
+    if let Err(e) = channel::emit_changed(server, controller, channel, property).await {
+        warn!("failed to emit change of {:?}: {:?}", property, e);
     }
 }
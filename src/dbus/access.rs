@@ -0,0 +1,135 @@
+use std::convert::TryFrom;
+
+use zbus::{fdo, names::BusName, Connection, MessageHeader};
+
+use super::Shared;
+use crate::{
+    access::{Credentials, Permission},
+    pwm::{Channel, Controller},
+};
+
+/// Looks up who sent a message, using the credentials the bus knows for the
+/// sender's connection. Buses that don't know the groups of a connection
+/// leave them empty, so rules for groups don't let the sender in.
+async fn credentials(
+    connection: &Connection,
+    header: &MessageHeader<'_>,
+) -> fdo::Result<Credentials> {
+    let sender = match header.sender() {
+        Ok(Some(sender)) => sender.to_owned(),
+        Ok(None) => {
+            return Err(fdo::Error::AccessDenied(
+                "the sender of the message is unknown".to_owned(),
+            ))
+        }
+        Err(e) => return Err(fdo::Error::Failed(e.to_string())),
+    };
+    let mut credentials = fdo::DBusProxy::new(connection)
+        .await?
+        .get_connection_credentials(BusName::Unique(sender))
+        .await?;
+    let uid = credentials
+        .remove("UnixUserID")
+        .and_then(|uid| u32::try_from(uid).ok())
+        .ok_or_else(|| {
+            fdo::Error::AccessDenied("the bus doesn't know the sender's user".to_owned())
+        })?;
+    let gids = credentials
+        .remove("UnixGroupIDs")
+        .and_then(|gids| Vec::<u32>::try_from(gids).ok())
+        .unwrap_or_default();
+    Ok(Credentials { uid, gids })
+}
+
+impl Shared {
+    /// Fails unless the sender of the message may write to the channels and
+    /// none of them is leased to another client.
+    pub async fn check_write(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+        channels: &[(Controller, Channel)],
+    ) -> fdo::Result<()> {
+        self.check_access(connection, header, Permission::Write, channels)
+            .await?;
+        self.leases.check(channels, header).await
+    }
+
+    /// Looks up who sent a message, unless the configuration file has no
+    /// access rules and everyone may read and write anything.
+    pub async fn reader(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+    ) -> fdo::Result<Option<Credentials>> {
+        if !self.rules.restricts_access() {
+            return Ok(None);
+        }
+        credentials(connection, header).await.map(Some)
+    }
+
+    /// Fails unless the sender of the message may read from the channels.
+    pub async fn check_read(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+        channels: &[(Controller, Channel)],
+    ) -> fdo::Result<()> {
+        self.check_access(connection, header, Permission::Read, channels)
+            .await
+    }
+
+    /// Fails unless the sender of the message is an administrator.
+    pub async fn check_admin(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        let caller = credentials(connection, header).await?;
+        if self.rules.is_admin(&caller) {
+            Ok(())
+        } else {
+            Err(fdo::Error::AccessDenied(format!(
+                "{} is not an administrator",
+                caller
+            )))
+        }
+    }
+
+    async fn check_access(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+        permission: Permission,
+        channels: &[(Controller, Channel)],
+    ) -> fdo::Result<()> {
+        if !self.rules.restricts_access() {
+            return Ok(());
+        }
+        let caller = credentials(connection, header).await?;
+        self.check_caller(&caller, permission, channels).await
+    }
+
+    /// Like `check_read` and `check_write`, for a caller that has been
+    /// looked up already, leaving out the leases.
+    pub async fn check_caller(
+        &self,
+        caller: &Credentials,
+        permission: Permission,
+        channels: &[(Controller, Channel)],
+    ) -> fdo::Result<()> {
+        let pwm = self.pwm.lock().await;
+        let denied = channels.iter().find(|(controller, channel)| {
+            !self
+                .rules
+                .allows(&pwm, caller, permission, controller, channel)
+        });
+        match denied {
+            Some((controller, channel)) => Err(fdo::Error::AccessDenied(format!(
+                "{} may not {} pwmchip{}/pwm{}",
+                caller, permission, controller.0, channel.0
+            ))),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use async_trait::async_trait;
+use tracing::warn;
+use zbus::{dbus_proxy, fdo, zvariant::Value, Connection, MessageHeader};
+
+use super::{lease, Shared};
+
+/// What a client asks pwmd to do, as far as the authority is concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Action {
+    Quit,
+    Export,
+    Unexport,
+    /// Declaring and removing RGB(W) LEDs and motors.
+    ManageDevices,
+    LeaseChannel,
+    ManageWatchdogs,
+    SetEnabled,
+    SetPeriod,
+    SetDutyCycle,
+    SetPolarity,
+    /// Changing several settings of a channel at once.
+    Configure,
+    /// Driving LEDs, servos, fans, buzzers, RGB(W) LEDs and motors through
+    /// their own interfaces.
+    Drive,
+}
+
+impl Action {
+    /// The polkit action ID, e.g., `com.kevinbader.pwmd.set-duty-cycle`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Action::Quit => "com.kevinbader.pwmd.quit",
+            Action::Export => "com.kevinbader.pwmd.export",
+            Action::Unexport => "com.kevinbader.pwmd.unexport",
+            Action::ManageDevices => "com.kevinbader.pwmd.manage-devices",
+            Action::LeaseChannel => "com.kevinbader.pwmd.lease-channel",
+            Action::ManageWatchdogs => "com.kevinbader.pwmd.manage-watchdogs",
+            Action::SetEnabled => "com.kevinbader.pwmd.set-enabled",
+            Action::SetPeriod => "com.kevinbader.pwmd.set-period",
+            Action::SetDutyCycle => "com.kevinbader.pwmd.set-duty-cycle",
+            Action::SetPolarity => "com.kevinbader.pwmd.set-polarity",
+            Action::Configure => "com.kevinbader.pwmd.configure",
+            Action::Drive => "com.kevinbader.pwmd.drive",
+        }
+    }
+}
+
+/// Decides whether a client may perform an action. Consulted before every
+/// method and property setter that changes something.
+#[async_trait]
+pub(super) trait Authority: Debug + Send + Sync {
+    /// Fails unless the client, given by its unique bus name, may perform
+    /// the action.
+    async fn check(&self, connection: &Connection, client: &str, action: Action)
+        -> fdo::Result<()>;
+}
+
+/// Allows every action, leaving access control to the D-Bus policy and the
+/// access rules of the configuration file.
+#[derive(Debug)]
+pub(super) struct AllowAll;
+
+#[async_trait]
+impl Authority for AllowAll {
+    async fn check(&self, _: &Connection, _: &str, _: Action) -> fdo::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether the subject is authorized, whether it could be after
+/// authenticating, and details.
+type AuthorizationResult = (bool, bool, HashMap<String, String>);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait PolkitAuthority {
+    fn check_authorization(
+        &self,
+        subject: &(&str, HashMap<&str, Value<'_>>),
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<(AuthorizationResult,)>;
+}
+
+/// Lets polkit ask the user to authenticate, if the action requires it.
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// Asks polkit, or whatever implements its interface under the given bus
+/// name, on the bus pwmd is connected to.
+#[derive(Debug)]
+pub(super) struct Polkit {
+    pub service_name: String,
+}
+
+#[async_trait]
+impl Authority for Polkit {
+    async fn check(
+        &self,
+        connection: &Connection,
+        client: &str,
+        action: Action,
+    ) -> fdo::Result<()> {
+        let subject = (
+            "system-bus-name",
+            vec![("name", Value::from(client))].into_iter().collect(),
+        );
+        let authority = PolkitAuthorityProxy::builder(connection)
+            .destination(self.service_name.as_str())?
+            .cache_properties(false)
+            .build()
+            .await?;
+        let ((authorized, _, _),) = authority
+            .check_authorization(
+                &subject,
+                action.id(),
+                HashMap::new(),
+                ALLOW_USER_INTERACTION,
+                "",
+            )
+            .await
+            .map_err(|e| {
+                warn!("{:?}", e);
+                fdo::Error::Failed(format!("failed to ask polkit: {}", e))
+            })?;
+        if authorized {
+            Ok(())
+        } else {
+            Err(fdo::Error::AccessDenied(format!(
+                "{} is not authorized to perform {}",
+                client,
+                action.id()
+            )))
+        }
+    }
+}
+
+impl Shared {
+    /// Fails unless the authority lets the sender of the message perform the
+    /// action.
+    pub async fn authorize(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+        action: Action,
+    ) -> fdo::Result<()> {
+        let client = lease::caller(header)?;
+        self.authority.check(connection, &client, action).await
+    }
+}
@@ -0,0 +1,198 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, Connection, MessageHeader, SignalContext};
+
+use super::{tree, Action, Error, Result, Shared};
+use crate::{
+    pwm::{Channel, Controller},
+    tone::Melody,
+};
+
+/// The silence between two notes, so repeated notes can be told apart.
+const NOTE_GAP: Duration = Duration::from_millis(10);
+
+/// Published next to `com.kevinbader.pwmd.Channel1` for every exported
+/// channel, for channels that drive a piezo buzzer.
+#[derive(Debug)]
+pub(super) struct BuzzerApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+}
+
+impl BuzzerApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller, channel: Channel) -> Self {
+        Self {
+            shared,
+            controller,
+            channel,
+        }
+    }
+
+    /// Replaces the running playback, if any, with the given melody.
+    async fn play(
+        &self,
+        melody: Melody,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+    ) -> Result<()> {
+        let shared = &self.shared;
+        let (controller, channel) = (self.controller.clone(), self.channel.clone());
+        shared.authorize(connection, header, Action::Drive).await?;
+        shared
+            .check_write(connection, header, &[(controller.clone(), channel.clone())])
+            .await?;
+        shared.tasks.stop(&controller, &channel).await;
+        let melody = shared
+            .pwm
+            .lock()
+            .await
+            .start_melody(controller.clone(), channel.clone(), melody)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        let playback = Playback {
+            shared: shared.clone(),
+            connection: connection.clone(),
+            controller: controller.clone(),
+            channel: channel.clone(),
+            completed: false,
+        };
+        shared
+            .tasks
+            .start(
+                &shared.runtime,
+                &[(controller, channel)],
+                play(playback, melody),
+            )
+            .await;
+        Ok(())
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Buzzer1")]
+impl BuzzerApi {
+    /// Plays a tone at the given frequency and 50% duty cycle. Returns right
+    /// away; `Finished` is emitted once the tone is over.
+    #[instrument(skip(connection, header))]
+    async fn play_tone(
+        &self,
+        frequency_hz: f64,
+        duration_ms: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let duration = Duration::from_millis(duration_ms.into());
+        self.play(Melody::tone(frequency_hz, duration), connection, &header)
+            .await
+    }
+
+    /// Plays a melody given in the Ring Tone Text Transfer Language (RTTTL).
+    /// Returns right away; `Finished` is emitted once the melody is over.
+    #[instrument(skip(connection, header))]
+    async fn play_melody(
+        &self,
+        rtttl: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let melody = Melody::from_rtttl(&rtttl).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        self.play(melody, connection, &header).await
+    }
+
+    /// Stops the playback and silences the buzzer by disabling the channel.
+    /// Writing to the channel stops the playback as well.
+    #[instrument(skip(connection, header))]
+    async fn stop(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        shared
+            .pwm
+            .lock()
+            .await
+            .disable(self.controller.clone(), self.channel.clone())
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        shared.tree.refresh();
+        Ok(())
+    }
+
+    /// Emitted when a tone or melody is over. `completed` is false if the
+    /// playback was stopped or failed.
+    #[dbus_interface(signal)]
+    async fn finished(ctxt: &SignalContext<'_>, completed: bool) -> zbus::Result<()>;
+}
+
+/// Emits `Finished` once dropped, i.e., also if the playback is stopped.
+struct Playback {
+    shared: Arc<Shared>,
+    connection: Connection,
+    controller: Controller,
+    channel: Channel,
+    completed: bool,
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        let connection = self.connection.clone();
+        let path = tree::channel_path(self.controller.0, self.channel.0);
+        let completed = self.completed;
+        self.shared.runtime.spawn(async move {
+            let res = match SignalContext::new(&connection, path.as_str()) {
+                Ok(ctxt) => BuzzerApi::finished(&ctxt, completed).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!("failed to emit end of playback: {:?}", e);
+            }
+        });
+        self.shared.tree.refresh();
+    }
+}
+
+/// Plays the melody note by note, then silences the buzzer.
+async fn play(mut playback: Playback, melody: Melody) {
+    let (controller, channel) = (playback.controller.clone(), playback.channel.clone());
+    for note in &melody.notes {
+        let tones = [
+            (note.frequency, note.duration.saturating_sub(NOTE_GAP)),
+            (None, NOTE_GAP.min(note.duration)),
+        ];
+        for (frequency, duration) in tones {
+            if let Err(e) = playback.shared.pwm.lock().await.set_tone(
+                controller.clone(),
+                channel.clone(),
+                frequency,
+            ) {
+                warn!(
+                    "playback of {:?} on {:?}/{:?} stopped: {}",
+                    melody.name, controller, channel, e
+                );
+                return;
+            }
+            tokio::time::sleep(duration).await;
+        }
+    }
+    playback.completed = true;
+}
@@ -0,0 +1,290 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, InterfaceDeref, ObjectServer};
+
+use super::{led, properties::Guarded, servo, tree, Action, Error, Shared};
+use crate::pwm::{Channel, Controller, Polarity, Pwm};
+
+/// The properties of a channel's object.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Property {
+    Period,
+    DutyCycle,
+    Polarity,
+    Enabled,
+}
+
+/// A channel's settings as read from sysfs. Settings that cannot be read are
+/// `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct ChannelState {
+    period: Option<Duration>,
+    duty_cycle: Option<Duration>,
+    polarity: Option<Polarity>,
+    enabled: Option<bool>,
+}
+
+impl ChannelState {
+    pub fn read(pwm: &Pwm, controller: &Controller, channel: &Channel) -> Self {
+        Self {
+            period: pwm.period(controller, channel).ok(),
+            duty_cycle: pwm.duty_cycle(controller, channel).ok(),
+            polarity: pwm.polarity(controller, channel).ok(),
+            enabled: pwm.is_enabled(controller, channel).ok(),
+        }
+    }
+
+    /// Returns the properties that differ between the two states.
+    pub fn changes(&self, other: &ChannelState) -> Vec<Property> {
+        let mut changes = vec![];
+        if self.period != other.period {
+            changes.push(Property::Period);
+        }
+        if self.duty_cycle != other.duty_cycle {
+            changes.push(Property::DutyCycle);
+        }
+        if self.polarity != other.polarity {
+            changes.push(Property::Polarity);
+        }
+        if self.enabled != other.enabled {
+            changes.push(Property::Enabled);
+        }
+        changes
+    }
+}
+
+/// Emits `PropertiesChanged` with the current value of a property, unless the
+/// channel has no object (yet).
+pub(super) async fn emit_changed(
+    server: &ObjectServer,
+    controller: &Controller,
+    channel: &Channel,
+    property: Property,
+) -> zbus::Result<()> {
+    let path = tree::channel_path(controller.0, channel.0);
+    let res = server
+        .with(
+            path.as_str(),
+            |iface: InterfaceDeref<'_, ChannelApi>, ctxt| async move {
+                match property {
+                    Property::Period => iface.period_changed(&ctxt).await,
+                    Property::DutyCycle => iface.duty_cycle_changed(&ctxt).await,
+                    Property::Polarity => iface.polarity_changed(&ctxt).await,
+                    Property::Enabled => iface.enabled_changed(&ctxt).await,
+                }
+            },
+        )
+        .await;
+    match res {
+        Err(zbus::Error::InterfaceNotFound) => Ok(()),
+        res => res,
+    }?;
+    match property {
+        Property::Period | Property::DutyCycle => {
+            led::emit_brightness_changed(server, controller, channel).await?;
+            servo::emit_angle_changed(server, controller, channel).await
+        }
+        Property::Polarity | Property::Enabled => Ok(()),
+    }
+}
+
+/// Published at `/com/kevinbader/pwmd/pwmchipN/pwmM` for every exported
+/// channel M of controller N.
+#[derive(Debug)]
+pub(super) struct ChannelApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+}
+
+impl ChannelApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller, channel: Channel) -> Self {
+        Self {
+            shared,
+            controller,
+            channel,
+        }
+    }
+
+    /// Setters emit `PropertiesChanged` on their own, so the tree must not
+    /// report the change again.
+    async fn record(&self, pwm: &Pwm) {
+        let state = ChannelState::read(pwm, &self.controller, &self.channel);
+        self.shared
+            .tree
+            .record(&self.controller, &self.channel, state)
+            .await;
+    }
+}
+
+#[async_trait]
+impl Guarded for ChannelApi {
+    fn action(&self, property: &str) -> Option<Action> {
+        match property {
+            "Period" => Some(Action::SetPeriod),
+            "DutyCycle" => Some(Action::SetDutyCycle),
+            "Polarity" => Some(Action::SetPolarity),
+            "Enabled" => Some(Action::SetEnabled),
+            _ => None,
+        }
+    }
+
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        Ok(vec![(self.controller.clone(), self.channel.clone())])
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Channel1")]
+impl ChannelApi {
+    /// The name given to the channel in the configuration file. Empty if the
+    /// channel isn't named.
+    #[dbus_interface(property)]
+    async fn name(&self) -> String {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.name_of(&self.shared.config, &self.controller, &self.channel)
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    /// What the channel is used for according to the configuration file,
+    /// one of "led", "servo", "fan" or "raw". Channels that aren't named are
+    /// "raw".
+    #[dbus_interface(property)]
+    async fn role(&self) -> String {
+        let config = &self.shared.config;
+        let pwm = self.shared.pwm.lock().await;
+        pwm.name_of(config, &self.controller, &self.channel)
+            .and_then(|name| config.channel(name))
+            .map(|named| named.role)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// The total period of the PWM signal in nanoseconds.
+    #[dbus_interface(property)]
+    async fn period(&self) -> u64 {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.period(&self.controller, &self.channel)
+            .map(|period| period.as_nanos() as u64)
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                0
+            })
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_period(&self, period: u64) -> zbus::Result<()> {
+        let period = Duration::from_nanos(period);
+        self.shared
+            .tasks
+            .stop(&self.controller, &self.channel)
+            .await;
+        let mut pwm = self.shared.pwm.lock().await;
+        pwm.set_period(self.controller.clone(), self.channel.clone(), period)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                zbus::Error::from(Error::Pwm(e))
+            })?;
+        self.record(&pwm).await;
+        Ok(())
+    }
+
+    /// The active time of the PWM signal in nanoseconds.
+    #[dbus_interface(property)]
+    async fn duty_cycle(&self) -> u64 {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.duty_cycle(&self.controller, &self.channel)
+            .map(|duty_cycle| duty_cycle.as_nanos() as u64)
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                0
+            })
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_duty_cycle(&self, duty_cycle: u64) -> zbus::Result<()> {
+        let duty_cycle = Duration::from_nanos(duty_cycle);
+        self.shared
+            .tasks
+            .stop(&self.controller, &self.channel)
+            .await;
+        let mut pwm = self.shared.pwm.lock().await;
+        pwm.set_duty_cycle(self.controller.clone(), self.channel.clone(), duty_cycle)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                zbus::Error::from(Error::Pwm(e))
+            })?;
+        self.record(&pwm).await;
+        Ok(())
+    }
+
+    /// The polarity of the PWM signal, either "normal" or "inversed".
+    #[dbus_interface(property)]
+    async fn polarity(&self) -> String {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.polarity(&self.controller, &self.channel)
+            .map(|polarity| polarity.to_string())
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                String::new()
+            })
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_polarity(&self, polarity: String) -> zbus::Result<()> {
+        let polarity = polarity.parse::<Polarity>().map_err(|e| {
+            warn!("{:?}", e);
+            zbus::Error::from(Error::Pwm(e))
+        })?;
+        self.shared
+            .tasks
+            .stop(&self.controller, &self.channel)
+            .await;
+        let mut pwm = self.shared.pwm.lock().await;
+        pwm.set_polarity(self.controller.clone(), self.channel.clone(), polarity)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                zbus::Error::from(Error::Pwm(e))
+            })?;
+        self.record(&pwm).await;
+        Ok(())
+    }
+
+    /// Whether the PWM signal is enabled.
+    #[dbus_interface(property)]
+    async fn enabled(&self) -> bool {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.is_enabled(&self.controller, &self.channel)
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                false
+            })
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_enabled(&self, enabled: bool) -> zbus::Result<()> {
+        self.shared
+            .tasks
+            .stop(&self.controller, &self.channel)
+            .await;
+        let mut pwm = self.shared.pwm.lock().await;
+        let (controller, channel) = (self.controller.clone(), self.channel.clone());
+        if enabled {
+            pwm.enable(controller, channel)
+        } else {
+            pwm.disable(controller, channel)
+        }
+        .map_err(|e| {
+            warn!("{:?}", e);
+            zbus::Error::from(Error::Pwm(e))
+        })?;
+        self.record(&pwm).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, Connection, MessageHeader};
+
+use super::{Action, Error, Result, Shared};
+use crate::pwm::{Channel, Controller};
+
+/// Published at `/com/kevinbader/pwmd/pwmchipN` for every controller N.
+#[derive(Debug)]
+pub(super) struct ControllerApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+}
+
+impl ControllerApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller) -> Self {
+        Self { shared, controller }
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Controller1")]
+impl ControllerApi {
+    /// The number of channels.
+    #[dbus_interface(property)]
+    async fn npwm(&self) -> u32 {
+        let pwm = self.shared.pwm.lock().await;
+        pwm.npwm(&self.controller).unwrap_or_else(|e| {
+            warn!("{:?}", e);
+            0
+        })
+    }
+
+    /// The resolved target of the controller's `device` symlink, or an empty
+    /// string if there is none.
+    #[dbus_interface(property)]
+    async fn device(&self) -> String {
+        let pwm = self.shared.pwm.lock().await;
+        match pwm.device(&self.controller) {
+            Ok(device) => device
+                .map(|device| device.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            Err(e) => {
+                warn!("{:?}", e);
+                String::new()
+            }
+        }
+    }
+
+    /// Exports a channel. The channel's object is published once the channel
+    /// shows up in sysfs.
+    #[instrument(skip(connection, header))]
+    async fn export(
+        &self,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Export)
+            .await?;
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), channel.clone())],
+            )
+            .await?;
+        let res = self
+            .shared
+            .pwm
+            .lock()
+            .await
+            .export(self.controller.clone(), channel);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })
+    }
+
+    /// Unexports a channel, which also removes the channel's object.
+    #[instrument(skip(connection, header))]
+    async fn unexport(
+        &self,
+        channel: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Unexport)
+            .await?;
+        let channel = Channel(channel);
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), channel.clone())],
+            )
+            .await?;
+        let res = self
+            .shared
+            .pwm
+            .lock()
+            .await
+            .unexport(self.controller.clone(), channel);
+        self.shared.tree.refresh();
+        res.map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })
+    }
+}
@@ -0,0 +1,172 @@
+use std::{collections::HashMap, io};
+
+use thiserror::Error;
+use zbus::{fdo, zvariant::Value, Connection, DBusError, Message, MessageBuilder, MessageHeader};
+
+use crate::pwm::{self, PwmError};
+
+/// The names of pwmd's own errors start with this, followed by the name of
+/// the `PwmError` variant, e.g., `com.kevinbader.pwmd.Error.NotExported`.
+const PREFIX: &str = "com.kevinbader.pwmd.Error";
+
+/// What the methods and property setters of pwmd's interfaces fail with.
+///
+/// `PwmError`s are replied with an error of their own name, whose arguments
+/// are the message and a dictionary of details, e.g., the controller and the
+/// channel. Everything else, e.g., a channel leased to another client, is
+/// replied with the standard `org.freedesktop.DBus.Error` names.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Pwm(#[from] PwmError),
+    #[error(transparent)]
+    Fdo(#[from] fdo::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Property setters return `zbus::Error`s. To fail with their own names
+/// nonetheless, `PwmError`s are wrapped in an I/O error, which
+/// `properties::Properties` unwraps again when replying.
+impl From<Error> for zbus::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Pwm(e) => zbus::Error::Io(io::Error::other(e)),
+            Error::Fdo(e) => e.into(),
+        }
+    }
+}
+
+impl From<zbus::Error> for Error {
+    fn from(e: zbus::Error) -> Self {
+        match e {
+            zbus::Error::FDO(e) => Error::Fdo(*e),
+            zbus::Error::Io(e) if e.get_ref().is_some_and(|e| e.is::<PwmError>()) => {
+                let e = e.into_inner().and_then(|e| e.downcast().ok());
+                Error::Pwm(*e.expect("checked to be a PwmError"))
+            }
+            e => Error::Fdo(e.into()),
+        }
+    }
+}
+
+impl Error {
+    pub fn name(&self) -> String {
+        match self {
+            Error::Pwm(e) => format!("{}.{}", PREFIX, variant(e)),
+            Error::Fdo(e) => e.name().to_owned(),
+        }
+    }
+
+    /// Sends the error in reply to a method call; called by the code
+    /// generated by `dbus_interface`.
+    pub async fn reply(&self, connection: &Connection, call: &Message) -> zbus::Result<u32> {
+        connection
+            .send_message(self.reply_to(&call.header()?)?)
+            .await
+    }
+}
+
+impl DBusError for Error {
+    fn reply_to(&self, call: &MessageHeader<'_>) -> zbus::Result<Message> {
+        match self {
+            Error::Pwm(e) => MessageBuilder::error(call, self.name().as_str())?
+                .build(&(e.to_string(), details(e))),
+            Error::Fdo(e) => e.reply_to(call),
+        }
+    }
+}
+
+fn variant(e: &PwmError) -> &'static str {
+    use PwmError::*;
+    match e {
+        ControllerNotFound(..) => "ControllerNotFound",
+        UnknownController(..) => "UnknownController",
+        AmbiguousController(..) => "AmbiguousController",
+        ChannelNotFound(..) => "ChannelNotFound",
+        NotExported(..) => "NotExported",
+        Sysfs(..) => "Sysfs",
+        DutyCycleGreaterThanPeriod => "DutyCycleGreaterThanPeriod",
+        InvalidPolarity => "InvalidPolarity",
+        IllegalChangeWhileEnabled(..) => "IllegalChangeWhileEnabled",
+        NotBoolean(..) => "NotBoolean",
+        NotADuration(..) => "NotADuration",
+        InvalidFrequency(..) => "InvalidFrequency",
+        InvalidDutyRatio(..) => "InvalidDutyRatio",
+        InvalidBrightness(..) => "InvalidBrightness",
+        InvalidGamma(..) => "InvalidGamma",
+        PeriodNotSet(..) => "PeriodNotSet",
+        InvalidEffect(..) => "InvalidEffect",
+        InvalidColor(..) => "InvalidColor",
+        InvalidLight(..) => "InvalidLight",
+        InvalidAngle(..) => "InvalidAngle",
+        InvalidServo(..) => "InvalidServo",
+        InvalidSensor(..) => "InvalidSensor",
+        NotATemperature(..) => "NotATemperature",
+        InvalidFanCurve(..) => "InvalidFanCurve",
+        InvalidMelody(..) => "InvalidMelody",
+        InvalidSpeed(..) => "InvalidSpeed",
+        InvalidMotor(..) => "InvalidMotor",
+        LimitExceeded(..) => "LimitExceeded",
+    }
+}
+
+/// What clients may want to know about an error besides its name, e.g., the
+/// value that was rejected.
+fn details(e: &PwmError) -> HashMap<&'static str, Value<'_>> {
+    use PwmError::*;
+    let channel = |controller: &pwm::Controller, channel: &pwm::Channel| {
+        vec![
+            ("controller", Value::from(controller.0)),
+            ("channel", Value::from(channel.0)),
+        ]
+    };
+    let details = match e {
+        ControllerNotFound(controller) => vec![("controller", Value::from(controller.0))],
+        UnknownController(id) | AmbiguousController(id) => vec![("id", Value::from(id.as_str()))],
+        ChannelNotFound(c, ch) | NotExported(c, ch) | PeriodNotSet(c, ch) => channel(c, ch),
+        Sysfs(access, e) => {
+            let (operation, path) = match access {
+                pwm::Access::Read(path) => ("read", path),
+                pwm::Access::Write(path) => ("write", path),
+            };
+            let mut details = vec![
+                ("operation", Value::from(operation)),
+                ("path", Value::from(path.to_string_lossy().into_owned())),
+            ];
+            if let Some(errno) = e.raw_os_error() {
+                details.push(("errno", Value::from(errno)));
+            }
+            details
+        }
+        DutyCycleGreaterThanPeriod | InvalidPolarity => vec![],
+        IllegalChangeWhileEnabled(setting) => vec![("setting", Value::from(*setting))],
+        NotBoolean(value)
+        | NotADuration(value, _)
+        | InvalidSensor(value)
+        | NotATemperature(value) => vec![("value", Value::from(value.as_str()))],
+        InvalidFrequency(value)
+        | InvalidDutyRatio(value)
+        | InvalidBrightness(value)
+        | InvalidGamma(value)
+        | InvalidSpeed(value) => vec![("value", Value::from(*value))],
+        InvalidAngle(value, max) => {
+            vec![("value", Value::from(*value)), ("max", Value::from(*max))]
+        }
+        InvalidEffect(reason)
+        | InvalidColor(reason)
+        | InvalidLight(reason)
+        | InvalidServo(reason)
+        | InvalidFanCurve(reason)
+        | InvalidMotor(reason) => {
+            vec![("reason", Value::from(*reason))]
+        }
+        InvalidMelody(reason) => vec![("reason", Value::from(reason.as_str()))],
+        LimitExceeded(c, ch, reason) => {
+            let mut details = channel(c, ch);
+            details.push(("reason", Value::from(reason.as_str())));
+            details
+        }
+    };
+    details.into_iter().collect()
+}
@@ -0,0 +1,77 @@
+use tracing::instrument;
+
+use crate::{
+    config::Fallback,
+    limits::Limits,
+    pwm::{Channel, Controller, Pwm, PwmError},
+};
+
+/// Applies the fallback policies, e.g., when pwmd quits, the client leasing a
+/// channel disconnects or a watchdog expires.
+impl Pwm {
+    /// Brings a channel into the state the policy asks for. Channels that
+    /// aren't exported are left alone.
+    ///
+    /// The policy is part of the configuration, just like the channel's
+    /// limits, so it applies to read-only channels as well. All other limits
+    /// still apply.
+    #[instrument]
+    pub fn fall_back(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        policy: Fallback,
+        exported_by_pwmd: bool,
+    ) -> Result<(), PwmError> {
+        let limits = match self.limits(&controller, &channel) {
+            Some(limits) if limits.read_only => limits.clone(),
+            _ => return self.apply_fallback(controller, channel, policy, exported_by_pwmd),
+        };
+        let writable = Limits {
+            read_only: false,
+            ..limits.clone()
+        };
+        self.set_limits(&controller, &channel, writable);
+        let res = self.apply_fallback(
+            controller.clone(),
+            channel.clone(),
+            policy,
+            exported_by_pwmd,
+        );
+        self.set_limits(&controller, &channel, limits);
+        res
+    }
+
+    fn apply_fallback(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        policy: Fallback,
+        exported_by_pwmd: bool,
+    ) -> Result<(), PwmError> {
+        if !self.is_exported(&controller, &channel)? {
+            return Ok(());
+        }
+        match policy {
+            Fallback::Keep => Ok(()),
+            Fallback::Disable => self.disable(controller, channel),
+            Fallback::Duty(duty_ratio) => {
+                if self.period(&controller, &channel)?.is_zero() {
+                    return Err(PwmError::PeriodNotSet(controller, channel));
+                }
+                self.set_duty_ratio(controller.clone(), channel.clone(), duty_ratio)?;
+                if !self.is_enabled(&controller, &channel)? {
+                    self.enable(controller, channel)?;
+                }
+                Ok(())
+            }
+            Fallback::Unexport => {
+                self.disable(controller.clone(), channel.clone())?;
+                if exported_by_pwmd {
+                    self.unexport(controller, channel)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
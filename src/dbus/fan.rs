@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, Connection, MessageHeader, SignalContext};
+
+use super::{properties::Guarded, Action, Error, Result, Shared};
+use crate::{
+    fan::{FanCurve, Sensor},
+    pwm::{Channel, Controller, PwmError},
+};
+
+/// The channels pwmd controls as fans, with the sensor and curve set for each.
+#[derive(Debug)]
+pub(super) struct Fans {
+    /// The sysfs class directory that contains the temperature sensors.
+    sensor_root: PathBuf,
+    /// How often the sensors are read.
+    interval: Duration,
+    /// Locked from `Drop` as well, so this isn't an async mutex.
+    fans: Mutex<HashMap<(u32, u32), Arc<Fan>>>,
+}
+
+#[derive(Debug)]
+struct Fan {
+    sensor: Sensor,
+    curve: FanCurve,
+}
+
+impl Fans {
+    pub fn new(sensor_root: PathBuf, interval: Duration) -> Self {
+        Self {
+            sensor_root,
+            interval,
+            fans: Mutex::default(),
+        }
+    }
+
+    fn get(&self, controller: &Controller, channel: &Channel) -> Option<Arc<Fan>> {
+        self.fans
+            .lock()
+            .unwrap()
+            .get(&(controller.0, channel.0))
+            .cloned()
+    }
+
+    fn insert(&self, controller: &Controller, channel: &Channel, fan: Arc<Fan>) {
+        let mut fans = self.fans.lock().unwrap();
+        fans.insert((controller.0, channel.0), fan);
+    }
+
+    fn remove(&self, controller: &Controller, channel: &Channel) {
+        let mut fans = self.fans.lock().unwrap();
+        fans.remove(&(controller.0, channel.0));
+    }
+
+    /// Removes the fan, unless it has been replaced already.
+    fn remove_if_current(&self, controller: &Controller, channel: &Channel, fan: &Arc<Fan>) {
+        let mut fans = self.fans.lock().unwrap();
+        let key = (controller.0, channel.0);
+        if fans
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, fan))
+        {
+            fans.remove(&key);
+        }
+    }
+
+    fn read(&self, sensor: &Sensor) -> Result<f64, PwmError> {
+        sensor.read(&self.sensor_root)
+    }
+}
+
+/// Published next to `com.kevinbader.pwmd.Channel1` for every exported
+/// channel, for channels that drive a fan.
+#[derive(Debug)]
+pub(super) struct FanApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+}
+
+impl FanApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller, channel: Channel) -> Self {
+        Self {
+            shared,
+            controller,
+            channel,
+        }
+    }
+
+    fn fan(&self) -> Option<Arc<Fan>> {
+        self.shared.fans.get(&self.controller, &self.channel)
+    }
+
+    /// Lets subscribers know about a new sensor and curve.
+    async fn emit_settings_changed(&self, ctxt: &SignalContext<'_>) {
+        let res = async {
+            self.sensor_changed(ctxt).await?;
+            self.curve_changed(ctxt).await?;
+            self.hysteresis_changed(ctxt).await?;
+            self.min_duty_percent_changed(ctxt).await
+        };
+        if let Err(e) = res.await {
+            warn!("failed to emit change of fan settings: {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Guarded for FanApi {
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        Ok(vec![(self.controller.clone(), self.channel.clone())])
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Fan1")]
+impl FanApi {
+    /// The temperature sensor the fan is controlled by, relative to
+    /// `/sys/class`. Empty if the fan isn't controlled by pwmd.
+    #[dbus_interface(property)]
+    async fn sensor(&self) -> String {
+        self.fan()
+            .map(|fan| fan.sensor.name().to_owned())
+            .unwrap_or_default()
+    }
+
+    /// The fan curve, as pairs of temperature in degrees Celsius and duty
+    /// cycle in percent.
+    #[dbus_interface(property)]
+    async fn curve(&self) -> Vec<(f64, f64)> {
+        self.fan()
+            .map(|fan| {
+                fan.curve
+                    .points()
+                    .iter()
+                    .map(|(temperature, duty)| (*temperature, duty * 100.0))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// How many degrees Celsius the temperature needs to fall before the fan
+    /// slows down.
+    #[dbus_interface(property)]
+    async fn hysteresis(&self) -> f64 {
+        self.fan()
+            .map(|fan| fan.curve.hysteresis)
+            .unwrap_or_default()
+    }
+
+    /// The lowest duty cycle in percent the fan is run at, unless the curve
+    /// turns it off.
+    #[dbus_interface(property)]
+    async fn min_duty_percent(&self) -> f64 {
+        self.fan()
+            .map(|fan| fan.curve.min_duty * 100.0)
+            .unwrap_or_default()
+    }
+
+    /// The temperature currently read from the sensor, in degrees Celsius.
+    /// NaN if the fan isn't controlled by pwmd or the sensor cannot be read.
+    #[dbus_interface(property)]
+    async fn temperature(&self) -> f64 {
+        match self.fan() {
+            Some(fan) => self.shared.fans.read(&fan.sensor).unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                f64::NAN
+            }),
+            None => f64::NAN,
+        }
+    }
+
+    /// Lets pwmd control the fan's duty cycle according to a curve, given as
+    /// pairs of temperature in degrees Celsius and duty cycle in percent. The
+    /// sensor is a thermal zone (`thermal/thermal_zoneN/temp`) or a hwmon
+    /// input (`hwmon/hwmonN/tempM_input`). The channel's period needs to be
+    /// set already. Writing to the channel stops the control again.
+    #[instrument(skip(ctxt, connection, header))]
+    #[allow(clippy::too_many_arguments)]
+    async fn set_curve(
+        &self,
+        sensor: String,
+        curve: Vec<(f64, f64)>,
+        hysteresis: f64,
+        min_duty_percent: f64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let points = curve
+            .into_iter()
+            .map(|(temperature, duty_percent)| (temperature, duty_percent / 100.0))
+            .collect();
+        let fan = Sensor::new(&sensor)
+            .and_then(|sensor| {
+                let curve = FanCurve::new(points, hysteresis, min_duty_percent / 100.0)?;
+                Ok(Arc::new(Fan { sensor, curve }))
+            })
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+
+        let shared = &self.shared;
+        let (controller, channel) = (self.controller.clone(), self.channel.clone());
+        shared.tasks.stop(&controller, &channel).await;
+        // Fails early if the channel cannot be controlled at all:
+        let temperature = shared.fans.read(&fan.sensor);
+        shared
+            .pwm
+            .lock()
+            .await
+            .control_fan(controller.clone(), channel.clone(), &fan.curve, temperature)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        shared.fans.insert(&controller, &channel, fan.clone());
+        let task = run_fan(ControlledFan {
+            shared: shared.clone(),
+            controller: controller.clone(),
+            channel: channel.clone(),
+            fan,
+        });
+        shared
+            .tasks
+            .start(&shared.runtime, &[(controller, channel)], task)
+            .await;
+        self.emit_settings_changed(&ctxt).await;
+        shared.tree.refresh();
+        Ok(())
+    }
+
+    /// Stops controlling the fan, leaving its duty cycle as it is.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn stop(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        shared.fans.remove(&self.controller, &self.channel);
+        self.emit_settings_changed(&ctxt).await;
+        Ok(())
+    }
+}
+
+/// A fan controlled by `run_fan`. Dropping it, when the task fails or is
+/// aborted because the channel was written to, forgets the fan's settings.
+struct ControlledFan {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+    fan: Arc<Fan>,
+}
+
+impl Drop for ControlledFan {
+    fn drop(&mut self) {
+        self.shared
+            .fans
+            .remove_if_current(&self.controller, &self.channel, &self.fan);
+    }
+}
+
+/// Controls the fan until it is stopped.
+async fn run_fan(controlled: ControlledFan) {
+    let ControlledFan {
+        shared,
+        controller,
+        channel,
+        fan,
+    } = &controlled;
+    let mut interval = tokio::time::interval(shared.fans.interval);
+    loop {
+        interval.tick().await;
+        let temperature = shared.fans.read(&fan.sensor);
+        let res = shared.pwm.lock().await.control_fan(
+            controller.clone(),
+            channel.clone(),
+            &fan.curve,
+            temperature,
+        );
+        match res {
+            // Let the tree report changes to the duty cycle.
+            Ok(_) => shared.tree.refresh(),
+            // Reading or writing sysfs may fail for a moment, e.g., while the
+            // driver is busy; the fan is still controlled on the next tick.
+            Err(e @ PwmError::Sysfs(..)) => {
+                warn!(
+                    "failed to control fan {:?}/{:?}: {}",
+                    controller, channel, e
+                )
+            }
+            Err(e) => {
+                warn!(
+                    "control of fan {:?}/{:?} stopped: {}",
+                    controller, channel, e
+                );
+                break;
+            }
+        }
+    }
+}
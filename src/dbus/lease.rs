@@ -0,0 +1,222 @@
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+
+use futures_util::StreamExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use zbus::{fdo, names::BusName, Connection, MessageHeader};
+
+use super::{change_channel, Shared};
+use crate::{
+    config::Fallback,
+    pwm::{Channel, Controller},
+};
+
+/// The channels clients have acquired exclusive write access to, by the
+/// unique bus name of the client, e.g., ":1.42".
+///
+/// A lease lasts until its owner releases it or disconnects from the bus; in
+/// the latter case the channel's `on_disconnect` policy is applied, see
+/// `watch_owners`.
+#[derive(Debug, Default)]
+pub(super) struct Leases {
+    owners: Mutex<HashMap<(u32, u32), String>>,
+}
+
+impl Leases {
+    /// Leases a channel to the sender of the message. Acquiring a channel
+    /// the sender holds already is fine.
+    pub async fn acquire(
+        &self,
+        connection: &Connection,
+        controller: &Controller,
+        channel: &Channel,
+        header: &MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        let caller = caller(header)?;
+        {
+            let mut owners = self.owners.lock().await;
+            match owners.get(&(controller.0, channel.0)) {
+                Some(owner) if *owner != caller => {
+                    return Err(leased(controller, channel, owner));
+                }
+                _ => {
+                    info!(
+                        "leasing pwmchip{}/pwm{} to {}",
+                        controller.0, channel.0, caller
+                    );
+                    owners.insert((controller.0, channel.0), caller.clone());
+                }
+            }
+        }
+        // `watch_owners` ends the leases of clients that disconnect, but
+        // misses those that were gone before their lease was recorded:
+        let name = BusName::try_from(caller.as_str()).map_err(zbus::Error::from)?;
+        let connected = fdo::DBusProxy::new(connection)
+            .await?
+            .name_has_owner(name)
+            .await?;
+        if !connected {
+            self.end(&caller).await;
+            return Err(fdo::Error::Failed(format!("{} disconnected", caller)));
+        }
+        Ok(())
+    }
+
+    /// Ends the lease the sender of the message holds on a channel.
+    pub async fn release(
+        &self,
+        controller: &Controller,
+        channel: &Channel,
+        header: &MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        let caller = caller(header)?;
+        let mut owners = self.owners.lock().await;
+        match owners.get(&(controller.0, channel.0)) {
+            Some(owner) if *owner == caller => {
+                info!(
+                    "{} released pwmchip{}/pwm{}",
+                    caller, controller.0, channel.0
+                );
+                owners.remove(&(controller.0, channel.0));
+                Ok(())
+            }
+            Some(owner) => Err(leased(controller, channel, owner)),
+            None => Err(fdo::Error::Failed(format!(
+                "pwmchip{}/pwm{} is not leased",
+                controller.0, channel.0
+            ))),
+        }
+    }
+
+    /// Fails if one of the channels is leased to a client other than the
+    /// sender of the message.
+    pub async fn check(
+        &self,
+        channels: &[(Controller, Channel)],
+        header: &MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        let caller = caller(header)?;
+        let owners = self.owners.lock().await;
+        for (controller, channel) in channels {
+            match owners.get(&(controller.0, channel.0)) {
+                Some(owner) if *owner != caller => {
+                    return Err(leased(controller, channel, owner));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the lease on a channel that is gone, e.g., because it was
+    /// unexported.
+    pub async fn forget(&self, controller: &Controller, channel: &Channel) {
+        let mut owners = self.owners.lock().await;
+        if let Some(owner) = owners.remove(&(controller.0, channel.0)) {
+            info!(
+                "pwmchip{}/pwm{} is gone, ending the lease of {}",
+                controller.0, channel.0, owner
+            );
+        }
+    }
+
+    /// Ends all leases of a client, returning the channels it held.
+    async fn end(&self, owner: &str) -> Vec<(Controller, Channel)> {
+        let mut owners = self.owners.lock().await;
+        let channels: Vec<_> = owners
+            .iter()
+            .filter(|(_, o)| *o == owner)
+            .map(|(&(c, ch), _)| (Controller(c), Channel(ch)))
+            .collect();
+        for (controller, channel) in &channels {
+            owners.remove(&(controller.0, channel.0));
+        }
+        channels
+    }
+}
+
+impl Shared {
+    /// Returns what happens to a channel when the client leasing it
+    /// disconnects: the policy of the named channel, or the default one.
+    async fn disconnect_policy(&self, controller: &Controller, channel: &Channel) -> Fallback {
+        let config = &self.config;
+        self.pwm
+            .lock()
+            .await
+            .name_of(config, controller, channel)
+            .and_then(|name| config.channel(name))
+            .map_or(config.disconnect, |named| named.disconnect)
+    }
+}
+
+/// The unique bus name of the sender of a message.
+pub(super) fn caller(header: &MessageHeader<'_>) -> fdo::Result<String> {
+    match header.sender() {
+        Ok(Some(sender)) => Ok(sender.to_string()),
+        Ok(None) => Err(fdo::Error::AccessDenied(
+            "the sender of the message is unknown".to_owned(),
+        )),
+        Err(e) => Err(fdo::Error::Failed(e.to_string())),
+    }
+}
+
+fn leased(controller: &Controller, channel: &Channel, owner: &str) -> fdo::Error {
+    fdo::Error::AccessDenied(format!(
+        "pwmchip{}/pwm{} is leased by {}",
+        controller.0, channel.0, owner
+    ))
+}
+
+/// Ends the leases of clients that disconnect from the bus and applies the
+/// `on_disconnect` policy to the channels they held, e.g., to stop a motor
+/// whose client crashed.
+pub(super) async fn watch_owners(
+    shared: Arc<Shared>,
+    connection: Connection,
+    mut changes: fdo::NameOwnerChangedStream<'static>,
+) {
+    while let Some(signal) = changes.next().await {
+        let args = match signal.args() {
+            Ok(args) => args,
+            Err(e) => {
+                warn!("invalid NameOwnerChanged signal: {:?}", e);
+                continue;
+            }
+        };
+        // Unique names are never passed on, so they only change owner when
+        // their client disconnects.
+        if args.new_owner().is_some() || !matches!(args.name(), BusName::Unique(_)) {
+            continue;
+        }
+        let gone = args.name().to_string();
+        let channels = shared.leases.end(&gone).await;
+        if channels.is_empty() {
+            continue;
+        }
+        for (controller, channel) in channels {
+            let policy = shared.disconnect_policy(&controller, &channel).await;
+            let exported_by_pwmd = !shared.exported_before.contains(&(controller.0, channel.0));
+            info!(
+                "{} disconnected, applying {:?} to pwmchip{}/pwm{}",
+                gone, policy, controller.0, channel.0
+            );
+            let server = connection.object_server().await;
+            let res = change_channel(&shared, &server, &controller, &channel, |pwm| {
+                pwm.fall_back(
+                    controller.clone(),
+                    channel.clone(),
+                    policy,
+                    exported_by_pwmd,
+                )
+            })
+            .await;
+            if let Err(e) = res {
+                warn!(
+                    "failed to fall back pwmchip{}/pwm{}: {:?}",
+                    controller.0, channel.0, e
+                );
+            }
+        }
+        shared.tree.refresh();
+    }
+}
@@ -0,0 +1,370 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, Connection, InterfaceDeref, MessageHeader, ObjectServer};
+
+use super::{properties::Guarded, tasks, tree, Action, Error, Result, Shared};
+use crate::{
+    effect::Effect,
+    led::Gamma,
+    pwm::{Channel, Controller, Pwm, PwmError},
+};
+
+/// The LED settings of all channels. Kept apart from the channel objects, so
+/// they survive a channel being unexported and exported again.
+#[derive(Debug, Default)]
+pub(super) struct Leds {
+    gammas: Mutex<HashMap<(u32, u32), Gamma>>,
+}
+
+impl Leds {
+    pub async fn gamma(&self, controller: &Controller, channel: &Channel) -> Gamma {
+        self.gammas
+            .lock()
+            .await
+            .get(&(controller.0, channel.0))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub async fn set_gamma(&self, controller: &Controller, channel: &Channel, gamma: Gamma) {
+        let mut gammas = self.gammas.lock().await;
+        gammas.insert((controller.0, channel.0), gamma);
+    }
+}
+
+/// Emits `PropertiesChanged` for the brightness of a channel's LED, unless the
+/// channel has no object (yet).
+pub(super) async fn emit_brightness_changed(
+    server: &ObjectServer,
+    controller: &Controller,
+    channel: &Channel,
+) -> zbus::Result<()> {
+    let path = tree::channel_path(controller.0, channel.0);
+    let res = server
+        .with(
+            path.as_str(),
+            |iface: InterfaceDeref<'_, LedApi>, ctxt| async move {
+                iface.brightness_changed(&ctxt).await
+            },
+        )
+        .await;
+    match res {
+        Err(zbus::Error::InterfaceNotFound) => Ok(()),
+        res => res,
+    }
+}
+
+/// Published next to `com.kevinbader.pwmd.Channel1` for every exported
+/// channel, for channels that drive an LED.
+#[derive(Debug)]
+pub(super) struct LedApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+}
+
+impl LedApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller, channel: Channel) -> Self {
+        Self {
+            shared,
+            controller,
+            channel,
+        }
+    }
+
+    /// Replaces the running effect, if any, with the one `prepare` returns
+    /// after setting the initial brightness.
+    async fn start_effect(
+        &self,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+        prepare: impl FnOnce(&mut Pwm, Gamma) -> Result<Effect, PwmError>,
+    ) -> Result<()> {
+        let (controller, channel) = (self.controller.clone(), self.channel.clone());
+        let shared = &self.shared;
+        shared.authorize(connection, header, Action::Drive).await?;
+        shared
+            .check_write(connection, header, &[(controller.clone(), channel.clone())])
+            .await?;
+        shared.tasks.stop(&controller, &channel).await;
+        let gamma = shared.leds.gamma(&controller, &channel).await;
+
+        let effect = prepare(&mut *self.shared.pwm.lock().await, gamma).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+
+        let task = run_effect(
+            self.shared.clone(),
+            controller.clone(),
+            channel.clone(),
+            effect,
+            gamma,
+        );
+        shared
+            .tasks
+            .start(&shared.runtime, &[(controller, channel)], task)
+            .await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Guarded for LedApi {
+    fn action(&self, _property: &str) -> Option<Action> {
+        Some(Action::Drive)
+    }
+
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        Ok(vec![(self.controller.clone(), self.channel.clone())])
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Led1")]
+impl LedApi {
+    /// The perceived brightness, between 0 (off) and 1 (full). Setting it
+    /// stops the running fade or effect and enables the channel, whose period
+    /// must be set already.
+    #[dbus_interface(property)]
+    async fn brightness(&self) -> f64 {
+        let gamma = self
+            .shared
+            .leds
+            .gamma(&self.controller, &self.channel)
+            .await;
+        let pwm = self.shared.pwm.lock().await;
+        pwm.brightness(&self.controller, &self.channel, gamma)
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                0.0
+            })
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_brightness(&self, brightness: f64) -> zbus::Result<()> {
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        let gamma = shared.leds.gamma(&self.controller, &self.channel).await;
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .set_brightness(
+                self.controller.clone(),
+                self.channel.clone(),
+                brightness,
+                gamma,
+            )
+            .map_err(|e| {
+                warn!("{:?}", e);
+                zbus::Error::from(Error::Pwm(e))
+            })?;
+        // Let the tree report the new duty cycle and enabled state.
+        self.shared.tree.refresh();
+        Ok(())
+    }
+
+    /// The gamma of the curve that maps brightness onto the duty ratio, which
+    /// is `brightness ^ gamma`. Defaults to 2.2; 1 maps brightness linearly.
+    /// Changing it keeps the brightness and adjusts the duty cycle instead.
+    #[dbus_interface(property)]
+    async fn gamma(&self) -> f64 {
+        self.shared
+            .leds
+            .gamma(&self.controller, &self.channel)
+            .await
+            .value()
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_gamma(&self, gamma: f64) -> zbus::Result<()> {
+        let gamma = Gamma::new(gamma).map_err(|e| {
+            warn!("{:?}", e);
+            zbus::Error::from(Error::Pwm(e))
+        })?;
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        let old_gamma = shared.leds.gamma(&self.controller, &self.channel).await;
+        {
+            let mut pwm = self.shared.pwm.lock().await;
+            let (controller, channel) = (self.controller.clone(), self.channel.clone());
+            pwm.brightness(&controller, &channel, old_gamma)
+                .and_then(|brightness| {
+                    pwm.set_duty_ratio(controller, channel, gamma.duty_ratio(brightness))
+                })
+                .map_err(|e| {
+                    warn!("{:?}", e);
+                    zbus::Error::from(Error::Pwm(e))
+                })?;
+        }
+        shared
+            .leds
+            .set_gamma(&self.controller, &self.channel, gamma)
+            .await;
+        self.shared.tree.refresh();
+        Ok(())
+    }
+
+    /// Changes the brightness gradually over the given time. Returns right
+    /// away, like all effects.
+    #[instrument(skip(connection, header))]
+    async fn fade_to(
+        &self,
+        brightness: f64,
+        duration_ms: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.start_effect(connection, &header, |pwm, gamma| {
+            pwm.start_fade(
+                self.controller.clone(),
+                self.channel.clone(),
+                brightness,
+                ms(duration_ms),
+                gamma,
+            )
+            .map(Effect::Fade)
+        })
+        .await
+    }
+
+    /// Switches between full brightness and off, `count` times or until the
+    /// effect is stopped if `count` is 0.
+    #[instrument(skip(connection, header))]
+    async fn blink(
+        &self,
+        on_ms: u32,
+        off_ms: u32,
+        count: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.start_effect(connection, &header, |pwm, gamma| {
+            let effect = Effect::blink(ms(on_ms), ms(off_ms), count)?;
+            pwm.start_effect(self.controller.clone(), self.channel.clone(), effect, gamma)
+        })
+        .await
+    }
+
+    /// Fades in and out smoothly until the effect is stopped.
+    #[instrument(skip(connection, header))]
+    async fn breathe(
+        &self,
+        period_ms: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.start_effect(connection, &header, |pwm, gamma| {
+            let effect = Effect::breathe(ms(period_ms))?;
+            pwm.start_effect(self.controller.clone(), self.channel.clone(), effect, gamma)
+        })
+        .await
+    }
+
+    /// Flashes twice per period until the effect is stopped.
+    #[instrument(skip(connection, header))]
+    async fn heartbeat(
+        &self,
+        period_ms: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.start_effect(connection, &header, |pwm, gamma| {
+            let effect = Effect::heartbeat(ms(period_ms))?;
+            pwm.start_effect(self.controller.clone(), self.channel.clone(), effect, gamma)
+        })
+        .await
+    }
+
+    /// Fades in and out again, `count` times or until the effect is stopped if
+    /// `count` is 0.
+    #[instrument(skip(connection, header))]
+    async fn pulse(
+        &self,
+        rise_ms: u32,
+        fall_ms: u32,
+        count: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.start_effect(connection, &header, |pwm, gamma| {
+            let effect = Effect::pulse(ms(rise_ms), ms(fall_ms), count)?;
+            pwm.start_effect(self.controller.clone(), self.channel.clone(), effect, gamma)
+        })
+        .await
+    }
+
+    /// Stops the running fade or effect, leaving the brightness as it is.
+    /// Writing the duty cycle or the brightness does so as well.
+    #[instrument(skip(connection, header))]
+    async fn stop_effect(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        self.shared
+            .tasks
+            .stop(&self.controller, &self.channel)
+            .await;
+        self.shared.tree.refresh();
+        Ok(())
+    }
+}
+
+fn ms(ms: u32) -> Duration {
+    Duration::from_millis(ms.into())
+}
+
+/// Drives the LED until the effect is over.
+async fn run_effect(
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+    effect: Effect,
+    gamma: Gamma,
+) {
+    let start = Instant::now();
+    let mut steps = tokio::time::interval(tasks::STEP);
+    let mut last = None;
+    loop {
+        steps.tick().await;
+        let elapsed = start.elapsed();
+        let brightness = effect.brightness_at(elapsed);
+        if last != Some(brightness) {
+            if let Err(e) = shared.pwm.lock().await.set_brightness(
+                controller.clone(),
+                channel.clone(),
+                brightness,
+                gamma,
+            ) {
+                warn!(
+                    "{:?} on {:?}/{:?} stopped: {}",
+                    effect, controller, channel, e
+                );
+                break;
+            }
+            last = Some(brightness);
+        }
+        if effect.duration().is_some_and(|d| elapsed >= d) {
+            break;
+        }
+    }
+    // Intermediate steps are picked up by polling, the final one is reported
+    // right away.
+    shared.tree.refresh();
+}
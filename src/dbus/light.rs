@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, Connection, InterfaceDeref, MessageHeader, SignalContext};
+
+use super::{properties::Guarded, tasks, Action, Error, Result, Shared};
+use crate::{
+    color::{Rgb, RgbLight},
+    led::Gamma,
+    pwm::{Channel, Controller, PwmError},
+};
+
+/// The RGB(W) LEDs declared by clients, by name.
+#[derive(Debug, Default)]
+pub(super) struct Lights {
+    lights: Mutex<BTreeMap<String, Light>>,
+}
+
+#[derive(Debug)]
+struct Light {
+    light: RgbLight,
+    /// The color last set.
+    color: Rgb,
+}
+
+impl Lights {
+    pub async fn add(&self, name: String, light: RgbLight) -> fdo::Result<()> {
+        super::check_name(&name)?;
+        let mut lights = self.lights.lock().await;
+        if lights.contains_key(&name) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "light {:?} exists already",
+                name
+            )));
+        }
+        let color = Rgb::default();
+        lights.insert(name, Light { light, color });
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> fdo::Result<RgbLight> {
+        self.lights
+            .lock()
+            .await
+            .remove(name)
+            .map(|light| light.light)
+            .ok_or_else(|| not_found(name))
+    }
+
+    pub async fn names(&self) -> BTreeSet<String> {
+        self.lights.lock().await.keys().cloned().collect()
+    }
+
+    pub async fn get(&self, name: &str) -> fdo::Result<(RgbLight, Rgb)> {
+        self.lights
+            .lock()
+            .await
+            .get(name)
+            .map(|light| (light.light.clone(), light.color))
+            .ok_or_else(|| not_found(name))
+    }
+
+    async fn update(&self, name: &str, update: impl FnOnce(&mut Light)) -> fdo::Result<()> {
+        self.lights
+            .lock()
+            .await
+            .get_mut(name)
+            .map(update)
+            .ok_or_else(|| not_found(name))
+    }
+}
+
+fn not_found(name: &str) -> fdo::Error {
+    fdo::Error::UnknownObject(format!("light {:?} not found", name))
+}
+
+pub(super) fn light_path(name: &str) -> String {
+    format!("{}/light/{}", super::ROOT_PATH, name)
+}
+
+/// Published at `/com/kevinbader/pwmd/light/<name>` for every RGB(W) LED
+/// declared using `AddRgbLight`.
+#[derive(Debug)]
+pub(super) struct RgbLightApi {
+    shared: Arc<Shared>,
+    name: String,
+}
+
+impl RgbLightApi {
+    pub fn new(shared: Arc<Shared>, name: String) -> Self {
+        Self { shared, name }
+    }
+
+    /// Stops running effects and fades on the light's channels, then sets
+    /// the color.
+    async fn set_color(
+        &self,
+        color: Result<Rgb, PwmError>,
+        ctxt: &SignalContext<'_>,
+        connection: &Connection,
+        header: &MessageHeader<'_>,
+    ) -> Result<()> {
+        let color = color.map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        let (light, _) = self.shared.lights.get(&self.name).await?;
+        self.shared
+            .authorize(connection, header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(connection, header, light.channels())
+            .await?;
+        for (controller, channel) in light.channels() {
+            self.shared.tasks.stop(controller, channel).await;
+        }
+        self.shared
+            .pwm
+            .lock()
+            .await
+            .set_color(&light, &color)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        self.shared
+            .lights
+            .update(&self.name, |light| light.color = color)
+            .await?;
+        if let Err(e) = self.color_changed(ctxt).await {
+            warn!("failed to emit change of color: {:?}", e);
+        }
+        // Let the tree report the new channel settings.
+        self.shared.tree.refresh();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Guarded for RgbLightApi {
+    fn action(&self, _property: &str) -> Option<Action> {
+        Some(Action::Drive)
+    }
+
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        let (light, _) = self.shared.lights.get(&self.name).await?;
+        Ok(light.channels().to_vec())
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.RgbLight1")]
+impl RgbLightApi {
+    /// The red, green, blue and, for RGBW LEDs, white channels, as pairs of
+    /// controller and channel numbers.
+    #[dbus_interface(property)]
+    async fn channels(&self) -> Vec<(u32, u32)> {
+        match self.shared.lights.get(&self.name).await {
+            Ok((light, _)) => light
+                .channels()
+                .iter()
+                .map(|(controller, channel)| (controller.0, channel.0))
+                .collect(),
+            Err(e) => {
+                warn!("{:?}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// The color last set, as red, green and blue between 0 and 1.
+    #[dbus_interface(property)]
+    async fn color(&self) -> (f64, f64, f64) {
+        match self.shared.lights.get(&self.name).await {
+            Ok((_, color)) => (color.red, color.green, color.blue),
+            Err(e) => {
+                warn!("{:?}", e);
+                (0.0, 0.0, 0.0)
+            }
+        }
+    }
+
+    /// The gamma used to map each channel's brightness onto its duty cycle,
+    /// see `com.kevinbader.pwmd.Led1`. Takes effect with the next color change.
+    #[dbus_interface(property)]
+    async fn gamma(&self) -> f64 {
+        match self.shared.lights.get(&self.name).await {
+            Ok((light, _)) => light.gamma.value(),
+            Err(e) => {
+                warn!("{:?}", e);
+                0.0
+            }
+        }
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_gamma(&self, gamma: f64) -> zbus::Result<()> {
+        let gamma = Gamma::new(gamma).map_err(|e| {
+            warn!("{:?}", e);
+            zbus::Error::from(Error::Pwm(e))
+        })?;
+        self.shared
+            .lights
+            .update(&self.name, |light| light.light.gamma = gamma)
+            .await?;
+        Ok(())
+    }
+
+    /// A factor between 0 and 1 per channel that its brightness is scaled
+    /// with, see `SetWhiteBalance`.
+    #[dbus_interface(property)]
+    async fn white_balance(&self) -> Vec<f64> {
+        match self.shared.lights.get(&self.name).await {
+            Ok((light, _)) => light.white_balance().to_vec(),
+            Err(e) => {
+                warn!("{:?}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// Calibrates the channels against each other, such that equal red,
+    /// green and blue appear white: takes a factor between 0 and 1 per
+    /// channel that its brightness is scaled with. Takes effect with the next
+    /// color change.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn set_white_balance(
+        &self,
+        white_balance: Vec<f64>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        let mut res = Ok(());
+        self.shared
+            .lights
+            .update(&self.name, |light| {
+                res = light.light.set_white_balance(white_balance);
+            })
+            .await?;
+        res.map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        if let Err(e) = self.white_balance_changed(&ctxt).await {
+            warn!("failed to emit change of white balance: {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Sets the color from red, green and blue, between 0 and 1 each.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn set_color_rgb(
+        &self,
+        red: f64,
+        green: f64,
+        blue: f64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.set_color(Rgb::new(red, green, blue), &ctxt, connection, &header)
+            .await
+    }
+
+    /// Sets the color from hue (in degrees), saturation and value, the latter
+    /// two between 0 and 1.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn set_color_hsv(
+        &self,
+        hue: f64,
+        saturation: f64,
+        value: f64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.set_color(
+            Rgb::from_hsv(hue, saturation, value),
+            &ctxt,
+            connection,
+            &header,
+        )
+        .await
+    }
+
+    /// Sets the color to white of the given color temperature, between 1000
+    /// and 40000 kelvin, at the given brightness between 0 and 1.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn set_color_temperature(
+        &self,
+        kelvin: u32,
+        brightness: f64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.set_color(
+            Rgb::from_kelvin(kelvin, brightness),
+            &ctxt,
+            connection,
+            &header,
+        )
+        .await
+    }
+
+    /// Changes the color gradually from the current one over the given time.
+    /// Returns right away; the fade stops early when the color or one of the
+    /// channels is written.
+    #[instrument(skip(connection, header))]
+    async fn fade_to_rgb(
+        &self,
+        red: f64,
+        green: f64,
+        blue: f64,
+        duration_ms: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let to = Rgb::new(red, green, blue).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })?;
+        let (light, from) = self.shared.lights.get(&self.name).await?;
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(connection, &header, light.channels())
+            .await?;
+        let task = fade(
+            self.shared.clone(),
+            connection.clone(),
+            self.name.clone(),
+            from,
+            to,
+            Duration::from_millis(duration_ms.into()),
+        );
+        self.shared
+            .tasks
+            .start(&self.shared.runtime, light.channels(), task)
+            .await;
+        Ok(())
+    }
+}
+
+async fn fade(
+    shared: Arc<Shared>,
+    connection: Connection,
+    name: String,
+    from: Rgb,
+    to: Rgb,
+    duration: Duration,
+) {
+    let start = Instant::now();
+    let mut steps = tokio::time::interval(tasks::STEP);
+    loop {
+        steps.tick().await;
+        let progress = if duration.is_zero() {
+            1.0
+        } else {
+            (start.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0)
+        };
+        let color = from.mix(&to, progress);
+        let res = match shared.lights.get(&name).await {
+            Ok((light, _)) => shared
+                .pwm
+                .lock()
+                .await
+                .set_color(&light, &color)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            warn!("fade of light {:?} stopped: {}", name, e);
+            break;
+        }
+        let _ = shared
+            .lights
+            .update(&name, |light| light.color = color)
+            .await;
+        if progress >= 1.0 {
+            break;
+        }
+    }
+
+    let server = connection.object_server().await;
+    let res = server
+        .with(
+            light_path(&name).as_str(),
+            |iface: InterfaceDeref<'_, RgbLightApi>, ctxt| async move {
+                iface.color_changed(&ctxt).await
+            },
+        )
+        .await;
+    match res {
+        Ok(()) | Err(zbus::Error::InterfaceNotFound) => {}
+        Err(e) => warn!("failed to emit change of color: {:?}", e),
+    }
+    shared.tree.refresh();
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, Connection, InterfaceDeref, MessageHeader, SignalContext};
+
+use super::{properties::Guarded, tasks, Action, Error, Result, Shared};
+use crate::{
+    motor::{Motor, Ramp},
+    pwm::{Channel, Controller, PwmError},
+};
+
+/// The motors declared by clients, by name.
+#[derive(Debug, Default)]
+pub(super) struct Motors {
+    motors: Mutex<BTreeMap<String, Motor>>,
+}
+
+impl Motors {
+    pub async fn add(&self, name: String, motor: Motor) -> fdo::Result<()> {
+        super::check_name(&name)?;
+        let mut motors = self.motors.lock().await;
+        if motors.contains_key(&name) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "motor {:?} exists already",
+                name
+            )));
+        }
+        motors.insert(name, motor);
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> fdo::Result<Motor> {
+        self.motors
+            .lock()
+            .await
+            .remove(name)
+            .ok_or_else(|| not_found(name))
+    }
+
+    pub async fn names(&self) -> BTreeSet<String> {
+        self.motors.lock().await.keys().cloned().collect()
+    }
+
+    pub async fn get(&self, name: &str) -> fdo::Result<Motor> {
+        self.motors
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or_else(|| not_found(name))
+    }
+
+    async fn update(
+        &self,
+        name: &str,
+        update: impl FnOnce(&mut Motor) -> Result<(), PwmError>,
+    ) -> Result<()> {
+        let mut motors = self.motors.lock().await;
+        let motor = motors.get_mut(name).ok_or_else(|| not_found(name))?;
+        update(motor).map_err(|e| {
+            warn!("{:?}", e);
+            Error::Pwm(e)
+        })
+    }
+}
+
+fn not_found(name: &str) -> fdo::Error {
+    fdo::Error::UnknownObject(format!("motor {:?} not found", name))
+}
+
+pub(super) fn motor_path(name: &str) -> String {
+    format!("{}/motor/{}", super::ROOT_PATH, name)
+}
+
+/// Published at `/com/kevinbader/pwmd/motor/<name>` for every motor declared
+/// using `AddMotor`.
+#[derive(Debug)]
+pub(super) struct MotorApi {
+    shared: Arc<Shared>,
+    name: String,
+}
+
+impl MotorApi {
+    pub fn new(shared: Arc<Shared>, name: String) -> Self {
+        Self { shared, name }
+    }
+}
+
+#[async_trait]
+impl Guarded for MotorApi {
+    fn action(&self, _property: &str) -> Option<Action> {
+        Some(Action::Drive)
+    }
+
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        let motor = self.shared.motors.get(&self.name).await?;
+        Ok(motor.channels().to_vec())
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Motor1")]
+impl MotorApi {
+    /// The speed (or forward) and direction (or reverse) channels, as pairs
+    /// of controller and channel numbers.
+    #[dbus_interface(property)]
+    async fn channels(&self) -> Vec<(u32, u32)> {
+        match self.shared.motors.get(&self.name).await {
+            Ok(motor) => motor
+                .channels()
+                .iter()
+                .map(|(controller, channel)| (controller.0, channel.0))
+                .collect(),
+            Err(e) => {
+                warn!("{:?}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// How the motor driver is wired to the channels, either "direction" or
+    /// "dual".
+    #[dbus_interface(property)]
+    async fn wiring(&self) -> String {
+        match self.shared.motors.get(&self.name).await {
+            Ok(motor) => motor.wiring().to_string(),
+            Err(e) => {
+                warn!("{:?}", e);
+                String::new()
+            }
+        }
+    }
+
+    /// The current speed, between -1 (full reverse) and 1 (full forward).
+    #[dbus_interface(property)]
+    async fn speed(&self) -> f64 {
+        let res = match self.shared.motors.get(&self.name).await {
+            Ok(motor) => self
+                .shared
+                .pwm
+                .lock()
+                .await
+                .motor_speed(&motor)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        res.unwrap_or_else(|e| {
+            warn!("{}", e);
+            0.0
+        })
+    }
+
+    /// How fast `SetSpeed` lets the speed increase, in full speeds per
+    /// second. Defaults to 1.
+    #[dbus_interface(property)]
+    async fn acceleration(&self) -> f64 {
+        match self.shared.motors.get(&self.name).await {
+            Ok(motor) => motor.acceleration(),
+            Err(e) => {
+                warn!("{:?}", e);
+                0.0
+            }
+        }
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_acceleration(&self, acceleration: f64) -> zbus::Result<()> {
+        self.shared
+            .motors
+            .update(&self.name, |motor| motor.set_acceleration(acceleration))
+            .await?;
+        Ok(())
+    }
+
+    /// How fast `SetSpeed` lets the speed decrease, in full speeds per
+    /// second. Defaults to 1.
+    #[dbus_interface(property)]
+    async fn deceleration(&self) -> f64 {
+        match self.shared.motors.get(&self.name).await {
+            Ok(motor) => motor.deceleration(),
+            Err(e) => {
+                warn!("{:?}", e);
+                0.0
+            }
+        }
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_deceleration(&self, deceleration: f64) -> zbus::Result<()> {
+        self.shared
+            .motors
+            .update(&self.name, |motor| motor.set_deceleration(deceleration))
+            .await?;
+        Ok(())
+    }
+
+    /// Changes the speed gradually, at the rates of acceleration and
+    /// deceleration. Reversing slows the motor down to zero first. Returns
+    /// right away; the ramp stops early when one of the channels is written.
+    #[instrument(skip(connection, header))]
+    async fn set_speed(
+        &self,
+        speed: f64,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let shared = &self.shared;
+        let motor = shared.motors.get(&self.name).await?;
+        shared.authorize(connection, &header, Action::Drive).await?;
+        shared
+            .check_write(connection, &header, motor.channels())
+            .await?;
+        for (controller, channel) in motor.channels() {
+            shared.tasks.stop(controller, channel).await;
+        }
+        let ramp = shared
+            .pwm
+            .lock()
+            .await
+            .start_ramp(&motor, speed)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        let task = run_ramp(shared.clone(), connection.clone(), self.name.clone(), ramp);
+        shared
+            .tasks
+            .start(&shared.runtime, motor.channels(), task)
+            .await;
+        Ok(())
+    }
+
+    /// Stops the motor right away, without slowing it down gradually.
+    #[instrument(skip(ctxt, connection, header))]
+    async fn stop(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let shared = &self.shared;
+        let motor = shared.motors.get(&self.name).await?;
+        shared.authorize(connection, &header, Action::Drive).await?;
+        shared
+            .check_write(connection, &header, motor.channels())
+            .await?;
+        for (controller, channel) in motor.channels() {
+            shared.tasks.stop(controller, channel).await;
+        }
+        shared
+            .pwm
+            .lock()
+            .await
+            .set_motor_speed(&motor, 0.0)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        if let Err(e) = self.speed_changed(&ctxt).await {
+            warn!("failed to emit change of speed: {:?}", e);
+        }
+        // Let the tree report the new channel settings.
+        shared.tree.refresh();
+        Ok(())
+    }
+}
+
+/// Drives the motor until the ramp is over.
+async fn run_ramp(shared: Arc<Shared>, connection: Connection, name: String, ramp: Ramp) {
+    let start = Instant::now();
+    let mut steps = tokio::time::interval(tasks::STEP);
+    loop {
+        steps.tick().await;
+        let elapsed = start.elapsed();
+        let res = match shared.motors.get(&name).await {
+            Ok(motor) => shared
+                .pwm
+                .lock()
+                .await
+                .set_motor_speed(&motor, ramp.speed_at(elapsed))
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            warn!("{:?} of motor {:?} stopped: {}", ramp, name, e);
+            break;
+        }
+        if elapsed >= ramp.duration() {
+            break;
+        }
+    }
+
+    let server = connection.object_server().await;
+    let res =
+        server
+            .with(
+                motor_path(&name).as_str(),
+                |iface: InterfaceDeref<'_, MotorApi>, ctxt| async move {
+                    iface.speed_changed(&ctxt).await
+                },
+            )
+            .await;
+    match res {
+        Ok(()) | Err(zbus::Error::InterfaceNotFound) => {}
+        Err(e) => warn!("failed to emit change of speed: {:?}", e),
+    }
+    shared.tree.refresh();
+}
@@ -0,0 +1,243 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use zbus::{
+    dbus_interface, fdo,
+    names::InterfaceName,
+    zvariant::{ObjectPath, OwnedValue, Value},
+    Connection, DispatchResult, Interface, InterfaceDeref, MessageHeader, ObjectServer,
+    SignalContext,
+};
+
+use super::{
+    buzzer::BuzzerApi, channel::ChannelApi, controller::ControllerApi, fan::FanApi, led::LedApi,
+    light::RgbLightApi, motor::MotorApi, servo::ServoApi, tree::ObjectManager, Action, Error,
+    PwmApi, Result, Shared,
+};
+use crate::{
+    access::{Credentials, Permission},
+    pwm::{Channel, Controller},
+};
+
+/// The interfaces zbus publishes on every object, none of which has
+/// properties.
+const STANDARD_INTERFACES: [&str; 3] = [
+    "org.freedesktop.DBus.Peer",
+    "org.freedesktop.DBus.Introspectable",
+    "org.freedesktop.DBus.Properties",
+];
+
+/// Takes the place of the `org.freedesktop.DBus.Properties` interface zbus
+/// publishes on every object, which replies to a failed `Set` with
+/// `org.freedesktop.DBus.Error.Failed`, whatever the setter failed with. This
+/// one replies with the setter's error, like the methods do.
+///
+/// zbus doesn't pass the message to property getters and setters, so `Get`
+/// and `GetAll` check the read rules on their behalf, and `Set` asks the
+/// authority and checks the write rules and leases, like the methods do.
+#[derive(Debug)]
+pub(super) struct Properties {
+    shared: Arc<Shared>,
+}
+
+impl Properties {
+    /// Replaces zbus' implementation at `path`, where one of pwmd's
+    /// interfaces has just been published.
+    pub fn publish(
+        server: &mut ObjectServer,
+        path: &str,
+        shared: &Arc<Shared>,
+    ) -> zbus::Result<()> {
+        server.remove::<fdo::Properties, _>(path)?;
+        let shared = shared.clone();
+        server.at(path, Properties { shared })?;
+        Ok(())
+    }
+}
+
+/// What `Properties` needs to know about one of pwmd's interfaces before
+/// reading or setting its properties.
+#[async_trait]
+pub(super) trait Guarded: Sync {
+    /// What setting the property means to the authority, if anything.
+    fn action(&self, _property: &str) -> Option<Action> {
+        None
+    }
+
+    /// The channels the object's properties are read from and written to.
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        Ok(vec![])
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.DBus.Properties")]
+impl Properties {
+    async fn get(
+        &self,
+        interface_name: InterfaceName<'_>,
+        property_name: &str,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<OwnedValue> {
+        let path = header.path()?.ok_or(zbus::Error::MissingField)?;
+        let iface = interface(server, path, &interface_name)
+            .await
+            .ok_or_else(|| unknown_interface(&interface_name))?;
+        let channels = iface.guarded().pwm_channels().await?;
+        self.shared
+            .check_read(connection, &header, &channels)
+            .await?;
+        let res = iface.get().get(property_name).await;
+        Ok(res.unwrap_or_else(|| Err(unknown_property(property_name)))?)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn set(
+        &self,
+        interface_name: InterfaceName<'_>,
+        property_name: &str,
+        value: Value<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<()> {
+        let path = header.path()?.ok_or(zbus::Error::MissingField)?;
+        let iface = interface(server, path, &interface_name)
+            .await
+            .ok_or_else(|| unknown_interface(&interface_name))?;
+        // pwmd's setters take `&self`, so they never require `set_mut`. The
+        // setter doesn't run before its future is awaited:
+        let set = match iface.get().set(property_name, &value, &ctxt) {
+            DispatchResult::Async(f) => f,
+            DispatchResult::NotFound | DispatchResult::RequiresMut => {
+                return Err(unknown_property(property_name).into())
+            }
+        };
+        if let Some(action) = iface.guarded().action(property_name) {
+            self.shared.authorize(connection, &header, action).await?;
+        }
+        let channels = iface.guarded().pwm_channels().await?;
+        self.shared
+            .check_write(connection, &header, &channels)
+            .await?;
+        let res = set.await.map_err(Error::from);
+        res
+    }
+
+    async fn get_all(
+        &self,
+        interface_name: InterfaceName<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<HashMap<String, OwnedValue>> {
+        let path = header.path()?.ok_or(zbus::Error::MissingField)?;
+        let iface = match interface(server, path, &interface_name).await {
+            Some(iface) => iface,
+            None if STANDARD_INTERFACES.contains(&interface_name.as_str()) => {
+                return Ok(HashMap::new())
+            }
+            None => return Err(unknown_interface(&interface_name).into()),
+        };
+        let channels = iface.guarded().pwm_channels().await?;
+        self.shared
+            .check_read(connection, &header, &channels)
+            .await?;
+        Ok(iface.get().get_all().await)
+    }
+
+    /// Emits the `org.freedesktop.DBus.Properties.PropertiesChanged` signal.
+    #[dbus_interface(signal)]
+    pub async fn properties_changed(
+        ctxt: &SignalContext<'_>,
+        interface_name: InterfaceName<'_>,
+        changed_properties: &HashMap<&str, &Value<'_>>,
+        invalidated_properties: &[&str],
+    ) -> zbus::Result<()>;
+}
+
+/// Whether `caller` may read the properties of the interface called `name` at
+/// `path`.
+pub(super) async fn readable(
+    shared: &Shared,
+    server: &ObjectServer,
+    path: &ObjectPath<'_>,
+    name: &str,
+    caller: &Credentials,
+) -> bool {
+    let iface = match interface(server, path, name).await {
+        Some(iface) => iface,
+        None => return true,
+    };
+    match iface.guarded().pwm_channels().await {
+        Ok(channels) => shared
+            .check_caller(caller, Permission::Read, &channels)
+            .await
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// One of pwmd's interfaces, whatever its type.
+trait Published: Send + Sync {
+    fn get(&self) -> &dyn Interface;
+
+    fn guarded(&self) -> &dyn Guarded;
+}
+
+impl<I: Interface + Guarded> Published for InterfaceDeref<'_, I> {
+    fn get(&self) -> &dyn Interface {
+        &**self
+    }
+
+    fn guarded(&self) -> &dyn Guarded {
+        &**self
+    }
+}
+
+/// Looks up the interface of pwmd's called `name` at `path`.
+async fn interface<'s>(
+    server: &'s ObjectServer,
+    path: &ObjectPath<'_>,
+    name: &str,
+) -> Option<Box<dyn Published + 's>> {
+    match name {
+        n if n == PwmApi::name().as_str() => find::<PwmApi>(server, path).await,
+        n if n == ObjectManager::name().as_str() => find::<ObjectManager>(server, path).await,
+        n if n == ControllerApi::name().as_str() => find::<ControllerApi>(server, path).await,
+        n if n == ChannelApi::name().as_str() => find::<ChannelApi>(server, path).await,
+        n if n == LedApi::name().as_str() => find::<LedApi>(server, path).await,
+        n if n == ServoApi::name().as_str() => find::<ServoApi>(server, path).await,
+        n if n == FanApi::name().as_str() => find::<FanApi>(server, path).await,
+        n if n == BuzzerApi::name().as_str() => find::<BuzzerApi>(server, path).await,
+        n if n == RgbLightApi::name().as_str() => find::<RgbLightApi>(server, path).await,
+        n if n == MotorApi::name().as_str() => find::<MotorApi>(server, path).await,
+        _ => None,
+    }
+}
+
+async fn find<'s, I: Interface + Guarded>(
+    server: &'s ObjectServer,
+    path: &ObjectPath<'_>,
+) -> Option<Box<dyn Published + 's>> {
+    let iface = server.get_interface::<_, I>(path.clone()).await.ok()?;
+    Some(Box::new(iface))
+}
+
+fn unknown_interface(name: &str) -> fdo::Error {
+    fdo::Error::UnknownInterface(format!("Unknown interface '{}'", name))
+}
+
+fn unknown_property(name: &str) -> fdo::Error {
+    fdo::Error::UnknownProperty(format!("Unknown property '{}'", name))
+}
+
+impl Guarded for PwmApi {}
+
+impl Guarded for ObjectManager {}
+
+impl Guarded for ControllerApi {}
+
+impl Guarded for BuzzerApi {}
@@ -0,0 +1,364 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{instrument, warn};
+use zbus::{dbus_interface, fdo, Connection, InterfaceDeref, MessageHeader, ObjectServer};
+
+use super::{properties::Guarded, tasks, tree, Action, Error, Result, Shared};
+use crate::{
+    pwm::{Channel, Controller, PwmError},
+    servo::{Move, Servo},
+};
+
+/// The servo settings of all channels. Kept apart from the channel objects,
+/// so they survive a channel being unexported and exported again.
+#[derive(Debug, Default)]
+pub(super) struct Servos {
+    servos: Mutex<HashMap<(u32, u32), Servo>>,
+}
+
+impl Servos {
+    pub async fn get(&self, controller: &Controller, channel: &Channel) -> Servo {
+        self.servos
+            .lock()
+            .await
+            .get(&(controller.0, channel.0))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Changes the settings of a channel's servo, unless that leaves them
+    /// inconsistent.
+    pub async fn update(
+        &self,
+        controller: &Controller,
+        channel: &Channel,
+        update: impl FnOnce(&mut Servo),
+    ) -> Result<(), PwmError> {
+        let mut servos = self.servos.lock().await;
+        let mut servo = servos
+            .get(&(controller.0, channel.0))
+            .cloned()
+            .unwrap_or_default();
+        update(&mut servo);
+        servo.check()?;
+        servos.insert((controller.0, channel.0), servo);
+        Ok(())
+    }
+}
+
+/// Emits `PropertiesChanged` for the angle of a channel's servo, unless the
+/// channel has no object (yet).
+pub(super) async fn emit_angle_changed(
+    server: &ObjectServer,
+    controller: &Controller,
+    channel: &Channel,
+) -> zbus::Result<()> {
+    let path = tree::channel_path(controller.0, channel.0);
+    let res =
+        server
+            .with(
+                path.as_str(),
+                |iface: InterfaceDeref<'_, ServoApi>, ctxt| async move {
+                    iface.angle_changed(&ctxt).await
+                },
+            )
+            .await;
+    match res {
+        Err(zbus::Error::InterfaceNotFound) => Ok(()),
+        res => res,
+    }
+}
+
+/// Published next to `com.kevinbader.pwmd.Channel1` for every exported
+/// channel, for channels that drive a servo.
+#[derive(Debug)]
+pub(super) struct ServoApi {
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+}
+
+impl ServoApi {
+    pub fn new(shared: Arc<Shared>, controller: Controller, channel: Channel) -> Self {
+        Self {
+            shared,
+            controller,
+            channel,
+        }
+    }
+
+    async fn servo(&self) -> Servo {
+        self.shared
+            .servos
+            .get(&self.controller, &self.channel)
+            .await
+    }
+
+    async fn update(&self, update: impl FnOnce(&mut Servo)) -> zbus::Result<()> {
+        self.shared
+            .servos
+            .update(&self.controller, &self.channel, update)
+            .await
+            .map_err(|e| {
+                warn!("{:?}", e);
+                zbus::Error::from(Error::Pwm(e))
+            })
+    }
+}
+
+#[async_trait]
+impl Guarded for ServoApi {
+    fn action(&self, _property: &str) -> Option<Action> {
+        Some(Action::Drive)
+    }
+
+    async fn pwm_channels(&self) -> fdo::Result<Vec<(Controller, Channel)>> {
+        Ok(vec![(self.controller.clone(), self.channel.clone())])
+    }
+}
+
+#[dbus_interface(name = "com.kevinbader.pwmd.Servo1")]
+impl ServoApi {
+    /// The angle the servo is turned to, in degrees, as derived from the
+    /// duty cycle.
+    #[dbus_interface(property)]
+    async fn angle(&self) -> f64 {
+        let servo = self.servo().await;
+        let pwm = self.shared.pwm.lock().await;
+        pwm.angle(&self.controller, &self.channel, &servo)
+            .unwrap_or_else(|e| {
+                warn!("{:?}", e);
+                0.0
+            })
+    }
+
+    /// The period of the PWM signal in microseconds. Defaults to 20000, the
+    /// period most servos expect.
+    #[dbus_interface(property)]
+    async fn period_us(&self) -> u32 {
+        self.servo().await.period.as_micros() as u32
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_period_us(&self, period_us: u32) -> zbus::Result<()> {
+        self.update(|servo| servo.period = us(period_us)).await
+    }
+
+    /// The pulse width at 0 degrees in microseconds. Defaults to 1000.
+    #[dbus_interface(property)]
+    async fn min_pulse_us(&self) -> u32 {
+        self.servo().await.min_pulse.as_micros() as u32
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_min_pulse_us(&self, min_pulse_us: u32) -> zbus::Result<()> {
+        self.update(|servo| servo.min_pulse = us(min_pulse_us))
+            .await
+    }
+
+    /// The pulse width at `Range` degrees in microseconds. Defaults to 2000.
+    #[dbus_interface(property)]
+    async fn max_pulse_us(&self) -> u32 {
+        self.servo().await.max_pulse.as_micros() as u32
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_max_pulse_us(&self, max_pulse_us: u32) -> zbus::Result<()> {
+        self.update(|servo| servo.max_pulse = us(max_pulse_us))
+            .await
+    }
+
+    /// The angle the servo turns between the minimum and the maximum pulse
+    /// width, in degrees. Defaults to 180.
+    #[dbus_interface(property)]
+    async fn range(&self) -> f64 {
+        self.servo().await.range
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_range(&self, range: f64) -> zbus::Result<()> {
+        self.update(|servo| servo.range = range).await
+    }
+
+    /// Added to every angle before it is mapped onto a pulse width, in
+    /// degrees. Calibrates servos that are mounted or centered slightly off.
+    #[dbus_interface(property)]
+    async fn offset(&self) -> f64 {
+        self.servo().await.offset
+    }
+
+    #[instrument]
+    #[dbus_interface(property)]
+    async fn set_offset(&self, offset: f64) -> zbus::Result<()> {
+        self.update(|servo| servo.offset = offset).await
+    }
+
+    /// Turns the servo to the given angle right away. Sets the period and
+    /// enables the channel if needed.
+    #[instrument(skip(connection, header))]
+    async fn set_angle(
+        &self,
+        angle: f64,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        let servo = self.servo().await;
+        shared
+            .pwm
+            .lock()
+            .await
+            .set_angle(self.controller.clone(), self.channel.clone(), &servo, angle)
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        // Let the tree report the new channel settings.
+        shared.tree.refresh();
+        Ok(())
+    }
+
+    /// Turns the servo to the given angle at the given speed in degrees per
+    /// second. Returns right away; the move stops early when the channel is
+    /// written.
+    #[instrument(skip(connection, header))]
+    async fn move_to(
+        &self,
+        angle: f64,
+        degrees_per_second: f64,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let shared = &self.shared;
+        let (controller, channel) = (self.controller.clone(), self.channel.clone());
+        shared.tasks.stop(&controller, &channel).await;
+        let servo = self.servo().await;
+        let motion = shared
+            .pwm
+            .lock()
+            .await
+            .start_move(
+                controller.clone(),
+                channel.clone(),
+                &servo,
+                angle,
+                degrees_per_second,
+            )
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        let task = run_move(
+            shared.clone(),
+            controller.clone(),
+            channel.clone(),
+            servo,
+            motion,
+        );
+        shared
+            .tasks
+            .start(&shared.runtime, &[(controller, channel)], task)
+            .await;
+        Ok(())
+    }
+
+    /// Stops driving the servo by disabling the channel, which lets most
+    /// servos turn freely.
+    #[instrument(skip(connection, header))]
+    async fn detach(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.shared
+            .authorize(connection, &header, Action::Drive)
+            .await?;
+        self.shared
+            .check_write(
+                connection,
+                &header,
+                &[(self.controller.clone(), self.channel.clone())],
+            )
+            .await?;
+        let shared = &self.shared;
+        shared.tasks.stop(&self.controller, &self.channel).await;
+        shared
+            .pwm
+            .lock()
+            .await
+            .disable(self.controller.clone(), self.channel.clone())
+            .map_err(|e| {
+                warn!("{:?}", e);
+                Error::Pwm(e)
+            })?;
+        shared.tree.refresh();
+        Ok(())
+    }
+}
+
+fn us(us: u32) -> Duration {
+    Duration::from_micros(us.into())
+}
+
+/// Drives the servo until the move is over.
+async fn run_move(
+    shared: Arc<Shared>,
+    controller: Controller,
+    channel: Channel,
+    servo: Servo,
+    motion: Move,
+) {
+    let start = Instant::now();
+    let mut steps = tokio::time::interval(tasks::STEP);
+    loop {
+        steps.tick().await;
+        let elapsed = start.elapsed();
+        let angle = motion.angle_at(elapsed);
+        if let Err(e) =
+            shared
+                .pwm
+                .lock()
+                .await
+                .set_angle(controller.clone(), channel.clone(), &servo, angle)
+        {
+            warn!(
+                "{:?} on {:?}/{:?} stopped: {}",
+                motion, controller, channel, e
+            );
+            break;
+        }
+        if elapsed >= motion.duration() {
+            break;
+        }
+    }
+    // Intermediate steps are picked up by polling, the final one is reported
+    // right away.
+    shared.tree.refresh();
+}
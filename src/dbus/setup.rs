@@ -0,0 +1,155 @@
+use tracing::{instrument, warn};
+
+use crate::{
+    config::{Config, NamedChannel},
+    limits::Limits,
+    pwm::{Channel, Controller, Pwm, PwmError, PwmState},
+};
+
+/// Sets up the channels named in the configuration file at startup.
+impl Pwm {
+    /// Exports the named channel and applies its period and polarity. Whether
+    /// it's enabled is left as it is, e.g., as restored from the state file,
+    /// and so is the duty cycle, unless it's longer than the new period. The
+    /// channel's limits apply to all writes after that.
+    #[instrument]
+    pub fn set_up(&mut self, named: &NamedChannel) -> Result<(), PwmError> {
+        let (controller, channel) = self.locate(named)?;
+        let res = self.apply_settings(&controller, &channel, named);
+        if named.limits != Limits::default() {
+            self.set_limits(&controller, &channel, named.limits.clone());
+        }
+        res
+    }
+
+    fn apply_settings(
+        &mut self,
+        controller: &Controller,
+        channel: &Channel,
+        named: &NamedChannel,
+    ) -> Result<(), PwmError> {
+        if !self.is_exported(controller, channel)? {
+            self.export(controller.clone(), channel.clone())?;
+        }
+        if named.period.is_none() && named.polarity.is_none() {
+            return Ok(());
+        }
+        let current = self.state(controller, channel)?;
+        let period = named.period.unwrap_or(current.period);
+        let state = PwmState {
+            period,
+            duty_cycle: current.duty_cycle.min(period),
+            polarity: named.polarity.unwrap_or(current.polarity),
+            enabled: current.enabled,
+        };
+        self.apply(controller.clone(), channel.clone(), state)
+    }
+
+    /// Sets up all named channels. Channels that cannot be set up, e.g.,
+    /// because their controller is missing, are skipped.
+    pub fn set_up_all(&mut self, config: &Config) {
+        for (name, named) in &config.channels {
+            if let Err(e) = self.set_up(named) {
+                warn!("failed to set up channel {:?}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::{
+        access::Rule,
+        config::{Fallback, Role},
+        pwm::{fake_sysfs, Polarity},
+    };
+    use temp_dir::TempDir;
+
+    #[test]
+    fn set_up_period_and_polarity() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 5000000, 2000000);
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        let named = NamedChannel {
+            chip: "pwmchip0".to_owned(),
+            channel: Channel(0),
+            role: Role::Led,
+            period: Some(Duration::from_millis(1)),
+            polarity: Some(Polarity::Inversed),
+            shutdown: Fallback::Keep,
+            disconnect: Fallback::Duty(0.0),
+            limits: Limits {
+                read_only: true,
+                ..Limits::default()
+            },
+            access: Rule::default(),
+        };
+        pwm.set_up(&named).unwrap();
+        assert_eq!(
+            fs::read_to_string(channel.join("period")).unwrap(),
+            "1000000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "1000000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel.join("polarity")).unwrap(),
+            "inversed"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "0");
+        // the limits apply once the channel is set up:
+        assert!(pwm.limits(&Controller(0), &Channel(0)).unwrap().read_only);
+        assert!(matches!(
+            pwm.enable(Controller(0), Channel(0)),
+            Err(PwmError::LimitExceeded(..))
+        ));
+
+        let config = Config {
+            channels: vec![("status_led".to_owned(), named)].into_iter().collect(),
+            ..Config::default()
+        };
+        assert_eq!(
+            pwm.name_of(&config, &Controller(0), &Channel(0)),
+            Some("status_led")
+        );
+        assert_eq!(pwm.name_of(&config, &Controller(0), &Channel(1)), None);
+    }
+
+    #[test]
+    fn keep_enabled_channels_enabled() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 5000000, 2000000);
+        fs::write(channel.join("enable"), "1").unwrap();
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        let named = NamedChannel {
+            chip: "pwmchip0".to_owned(),
+            channel: Channel(0),
+            role: Role::Fan,
+            period: Some(Duration::from_micros(40)),
+            polarity: Some(Polarity::Inversed),
+            shutdown: Fallback::Keep,
+            disconnect: Fallback::Keep,
+            limits: Limits::default(),
+            access: Rule::default(),
+        };
+        pwm.set_up(&named).unwrap();
+        assert_eq!(fs::read_to_string(channel.join("period")).unwrap(), "40000");
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "40000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel.join("polarity")).unwrap(),
+            "inversed"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+
+use tracing::{instrument, warn};
+
+use crate::{
+    config::{Config, NamedChannel},
+    pwm::{Pwm, PwmError},
+};
+
+/// Leaves the channels named in the configuration file as their shutdown
+/// policies say when pwmd quits.
+impl Pwm {
+    /// Applies the shutdown policy of a named channel. `exported_by_pwmd` tells
+    /// whether pwmd exported the channel, which it may only unexport then.
+    #[instrument]
+    pub fn shut_down(
+        &mut self,
+        named: &NamedChannel,
+        exported_by_pwmd: bool,
+    ) -> Result<(), PwmError> {
+        let (controller, channel) = self.locate(named)?;
+        self.fall_back(controller, channel, named.shutdown, exported_by_pwmd)
+    }
+
+    /// Applies the shutdown policies of all named channels. Channels that are
+    /// in `exported_before` were exported before pwmd started, so they stay
+    /// exported.
+    pub fn shut_down_all(&mut self, config: &Config, exported_before: &HashSet<(u32, u32)>) {
+        for (name, named) in &config.channels {
+            let exported_by_pwmd = match self.locate(named) {
+                Ok((controller, channel)) => !exported_before.contains(&(controller.0, channel.0)),
+                Err(_) => false,
+            };
+            if let Err(e) = self.shut_down(named, exported_by_pwmd) {
+                warn!("failed to shut down channel {:?}: {}", name, e);
+            }
+        }
+    }
+
+    /// Returns the channels that are currently exported, as pairs of
+    /// controller and channel numbers.
+    pub fn exported_pairs(&self) -> Result<HashSet<(u32, u32)>, PwmError> {
+        let mut exported = HashSet::new();
+        for info in self.controllers()? {
+            for channel in info.exported {
+                exported.insert((info.controller.0, channel.0));
+            }
+        }
+        Ok(exported)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        access::Rule,
+        config::{Fallback, Role},
+        limits::Limits,
+        pwm::{fake_sysfs, Channel, Controller},
+    };
+    use temp_dir::TempDir;
+
+    #[test]
+    fn apply_shutdown_policies() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 3);
+        for i in 0..3 {
+            let channel = fake_sysfs::channel(&chip, i, 1000, 500);
+            fs::write(channel.join("enable"), "1").unwrap();
+        }
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let named = |channel, shutdown| NamedChannel {
+            chip: "0".to_owned(),
+            channel: Channel(channel),
+            role: Role::Raw,
+            period: None,
+            polarity: None,
+            shutdown,
+            disconnect: Fallback::Disable,
+            limits: Limits::default(),
+            access: Rule::default(),
+        };
+        let read = |file: &str| fs::read_to_string(chip.join(file)).unwrap();
+
+        pwm.shut_down(&named(0, Fallback::Disable), true).unwrap();
+        assert_eq!(read("pwm0/enable"), "0");
+
+        pwm.shut_down(&named(1, Fallback::Duty(0.25)), true)
+            .unwrap();
+        assert_eq!(read("pwm1/duty_cycle"), "250");
+        assert_eq!(read("pwm1/enable"), "1");
+
+        // channels exported by someone else stay exported:
+        pwm.shut_down(&named(2, Fallback::Unexport), false).unwrap();
+        assert_eq!(read("pwm2/enable"), "0");
+        assert_eq!(read("unexport"), "");
+        pwm.shut_down(&named(2, Fallback::Unexport), true).unwrap();
+        assert_eq!(read("unexport"), "2");
+    }
+
+    #[test]
+    fn apply_shutdown_policies_to_read_only_channels() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 500);
+        fs::write(channel.join("enable"), "1").unwrap();
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let read_only = Limits {
+            max_duty_ratio: Some(0.5),
+            read_only: true,
+            ..Limits::default()
+        };
+        pwm.set_limits(&Controller(0), &Channel(0), read_only.clone());
+        let named = |shutdown| NamedChannel {
+            chip: "0".to_owned(),
+            channel: Channel(0),
+            role: Role::Raw,
+            period: None,
+            polarity: None,
+            shutdown,
+            disconnect: Fallback::Disable,
+            limits: read_only.clone(),
+            access: Rule::default(),
+        };
+
+        // the other limits still apply:
+        assert!(matches!(
+            pwm.shut_down(&named(Fallback::Duty(0.75)), true),
+            Err(PwmError::LimitExceeded(..))
+        ));
+        pwm.shut_down(&named(Fallback::Disable), true).unwrap();
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "0");
+        // ...and the channel stays read-only for everyone else:
+        assert_eq!(pwm.limits(&Controller(0), &Channel(0)), Some(&read_only));
+        assert!(pwm.enable(Controller(0), Channel(0)).is_err());
+    }
+}
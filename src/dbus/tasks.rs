@@ -0,0 +1,59 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use tokio::{runtime::Handle, sync::Mutex, task::JoinHandle};
+
+use crate::pwm::{Channel, Controller};
+
+/// How often fades, effects and other tasks update the duty cycle.
+pub(super) const STEP: Duration = Duration::from_millis(20);
+
+/// A task and the handle to stop it with. Shared by the channels the task
+/// drives together, e.g., those of an RGB(W) LED.
+type Task = Arc<JoinHandle<()>>;
+
+/// The tasks driving channels in the background, e.g., LED fades and effects.
+///
+/// At most one task drives a channel at a time. Writing to a channel directly
+/// stops its task, so the task doesn't overwrite the change.
+#[derive(Debug, Default)]
+pub(super) struct Tasks {
+    tasks: Mutex<HashMap<(u32, u32), Task>>,
+}
+
+impl Tasks {
+    /// Stops the task currently driving the channel, if any.
+    pub async fn stop(&self, controller: &Controller, channel: &Channel) {
+        let mut tasks = self.tasks.lock().await;
+        if let Some(task) = tasks.remove(&(controller.0, channel.0)) {
+            task.abort();
+        }
+    }
+
+    /// Stops all tasks, e.g., before pwmd quits.
+    pub async fn stop_all(&self) {
+        for (_, task) in self.tasks.lock().await.drain() {
+            task.abort();
+        }
+    }
+
+    /// Lets `task` drive the given channels, stopping the tasks that did so
+    /// before.
+    pub async fn start(
+        &self,
+        runtime: &Handle,
+        channels: &[(Controller, Channel)],
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let mut tasks = self.tasks.lock().await;
+        for (controller, channel) in channels {
+            if let Some(task) = tasks.remove(&(controller.0, channel.0)) {
+                task.abort();
+            }
+        }
+        // Writing to any of the channels stops the task for all of them.
+        let task = Arc::new(runtime.spawn(task));
+        for (controller, channel) in channels {
+            tasks.insert((controller.0, channel.0), task.clone());
+        }
+    }
+}
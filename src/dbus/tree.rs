@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, warn};
+use zbus::{
+    dbus_interface, fdo,
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue},
+    Connection, Interface, MessageHeader, ObjectServer, SignalContext,
+};
+
+use super::{
+    buzzer::BuzzerApi,
+    channel::{self, ChannelApi, ChannelState},
+    controller::ControllerApi,
+    fan::FanApi,
+    led::LedApi,
+    light::{self, RgbLightApi},
+    motor::{self, MotorApi},
+    properties::{self, Properties},
+    save_state,
+    servo::ServoApi,
+    PwmApi, Shared, ROOT_PATH,
+};
+use crate::{
+    config::Role,
+    pwm::{Channel, Controller},
+};
+
+/// Interface names mapped to their properties.
+type Interfaces = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// Published channels by controller, with the channel settings last reported.
+type Published = BTreeMap<u32, BTreeMap<u32, ChannelState>>;
+
+/// The roles of the named channels, by controller and channel.
+type Roles = HashMap<(u32, u32), Role>;
+
+/// Keeps track of the published controller, channel, light and motor
+/// objects.
+///
+/// Every change to the channels ends up here, whether it's made by a method
+/// handler, a background task or outside of pwmd, so this is also where the
+/// channel settings are recorded in the state file.
+#[derive(Debug, Default)]
+pub(super) struct Tree {
+    published: Mutex<Published>,
+    published_lights: Mutex<BTreeSet<String>>,
+    published_motors: Mutex<BTreeSet<String>>,
+    outdated: Notify,
+    /// Whether a change was recorded that is yet to be saved.
+    unsaved: AtomicBool,
+}
+
+impl Tree {
+    /// Requests the objects to be synced with sysfs.
+    pub fn refresh(&self) {
+        self.outdated.notify_one();
+    }
+
+    /// Remembers the settings of a published channel, after the change that
+    /// led to them has been reported already, and requests them to be saved.
+    pub async fn record(&self, controller: &Controller, channel: &Channel, state: ChannelState) {
+        let mut published = self.published.lock().await;
+        if let Some(known) = published
+            .get_mut(&controller.0)
+            .and_then(|channels| channels.get_mut(&channel.0))
+        {
+            *known = state;
+        }
+        self.unsaved.store(true, Ordering::Relaxed);
+        self.refresh();
+    }
+}
+
+/// Regularly requests the objects to be synced with sysfs, to pick up changes
+/// made outside of pwmd, e.g., by shell scripts or the kernel's LED triggers.
+///
+/// sysfs attributes don't emit inotify events when they are written to, so
+/// polling is the only way to find out about such changes.
+pub(super) async fn poll(shared: Arc<Shared>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        shared.tree.refresh();
+    }
+}
+
+/// Syncs the objects with sysfs whenever a refresh is requested.
+///
+/// Objects can't be added or removed while a method call is being handled, so
+/// handlers request a refresh instead of syncing themselves.
+pub(super) async fn keep_in_sync(shared: Arc<Shared>, connection: Connection) {
+    loop {
+        shared.tree.outdated.notified().await;
+        if let Err(e) = sync(&shared, &connection).await {
+            warn!("failed to sync objects: {:?}", e);
+        }
+    }
+}
+
+/// Publishes objects for controllers and exported channels that showed up in
+/// sysfs, removes the objects of those that are gone and emits
+/// `PropertiesChanged` for channel settings that were changed. Does the same
+/// for the RGB(W) LEDs and motors added and removed by clients. Saves the
+/// channel settings if any of them changed.
+pub(super) async fn sync(shared: &Arc<Shared>, connection: &Connection) -> anyhow::Result<()> {
+    let (wanted, roles): (Published, Roles) = {
+        let pwm = shared.pwm.lock().await;
+        let wanted = pwm
+            .controllers()?
+            .into_iter()
+            .map(|info| {
+                let controller = info.controller;
+                let channels = info.exported.into_iter().map(|channel| {
+                    let state = ChannelState::read(&pwm, &controller, &channel);
+                    (channel.0, state)
+                });
+                (controller.0, channels.collect())
+            })
+            .collect();
+        let roles = shared
+            .config
+            .channels
+            .values()
+            .filter_map(|named| {
+                let (controller, channel) = pwm.locate(named).ok()?;
+                Some(((controller.0, channel.0), named.role))
+            })
+            .collect();
+        (wanted, roles)
+    };
+    let wanted_lights = shared.lights.names().await;
+    let wanted_motors = shared.motors.names().await;
+
+    // Lock order: object server before the published objects, same as in
+    // method handlers.
+    let mut server = connection.object_server_mut().await;
+    let mut published = shared.tree.published.lock().await;
+    let ctxt = SignalContext::new(connection, ROOT_PATH)?;
+    let mut changed = shared.tree.unsaved.swap(false, Ordering::Relaxed);
+
+    for (controller, channels) in published.iter() {
+        for channel in channels.keys() {
+            if !wanted
+                .get(controller)
+                .is_some_and(|c| c.contains_key(channel))
+            {
+                changed = true;
+                let path = channel_path(*controller, *channel);
+                debug!("removing {}", path);
+                let interfaces = interfaces(&server, &path).await;
+                remove_if_present::<LedApi>(&mut server, &path)?;
+                remove_if_present::<ServoApi>(&mut server, &path)?;
+                remove_if_present::<FanApi>(&mut server, &path)?;
+                remove_if_present::<BuzzerApi>(&mut server, &path)?;
+                server.remove::<ChannelApi, _>(path.as_str())?;
+                emit_removed(&ctxt, &path, interfaces).await?;
+                shared
+                    .leases
+                    .forget(&Controller(*controller), &Channel(*channel))
+                    .await;
+            }
+        }
+        if !wanted.contains_key(controller) {
+            let path = controller_path(*controller);
+            debug!("removing {}", path);
+            let interfaces = interfaces(&server, &path).await;
+            server.remove::<ControllerApi, _>(path.as_str())?;
+            emit_removed(&ctxt, &path, interfaces).await?;
+        }
+    }
+
+    for (controller, channels) in wanted.iter() {
+        let known = published.get(controller);
+        if known.is_none() {
+            let path = controller_path(*controller);
+            debug!("adding {}", path);
+            let iface = ControllerApi::new(shared.clone(), Controller(*controller));
+            server.at(path.as_str(), iface)?;
+            Properties::publish(&mut server, &path, shared)?;
+            emit_added(&ctxt, &path, interfaces(&server, &path).await).await?;
+        }
+        for (channel, state) in channels {
+            let (controller, channel) = (Controller(*controller), Channel(*channel));
+            match known.and_then(|c| c.get(&channel.0)) {
+                None => {
+                    changed = true;
+                    let path = channel_path(controller.0, channel.0);
+                    debug!("adding {}", path);
+                    let iface =
+                        ChannelApi::new(shared.clone(), controller.clone(), channel.clone());
+                    server.at(path.as_str(), iface)?;
+                    Properties::publish(&mut server, &path, shared)?;
+                    // Named channels only get the interface of their role;
+                    // raw ones may be used for anything:
+                    let role = roles
+                        .get(&(controller.0, channel.0))
+                        .copied()
+                        .unwrap_or_default();
+                    if matches!(role, Role::Led | Role::Raw) {
+                        server.at(
+                            path.as_str(),
+                            LedApi::new(shared.clone(), controller.clone(), channel.clone()),
+                        )?;
+                    }
+                    if matches!(role, Role::Servo | Role::Raw) {
+                        server.at(
+                            path.as_str(),
+                            ServoApi::new(shared.clone(), controller.clone(), channel.clone()),
+                        )?;
+                    }
+                    if matches!(role, Role::Fan | Role::Raw) {
+                        server.at(
+                            path.as_str(),
+                            FanApi::new(shared.clone(), controller.clone(), channel.clone()),
+                        )?;
+                    }
+                    if role == Role::Raw {
+                        server.at(
+                            path.as_str(),
+                            BuzzerApi::new(shared.clone(), controller, channel),
+                        )?;
+                    }
+                    emit_added(&ctxt, &path, interfaces(&server, &path).await).await?;
+                }
+                Some(known_state) => {
+                    for property in known_state.changes(state) {
+                        changed = true;
+                        debug!("{:?}/{:?}: {:?} changed", controller, channel, property);
+                        channel::emit_changed(&server, &controller, &channel, property).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    *published = wanted;
+
+    let mut published_lights = shared.tree.published_lights.lock().await;
+    for name in published_lights.difference(&wanted_lights) {
+        let path = light::light_path(name);
+        debug!("removing {}", path);
+        let interfaces = interfaces(&server, &path).await;
+        server.remove::<RgbLightApi, _>(path.as_str())?;
+        emit_removed(&ctxt, &path, interfaces).await?;
+    }
+    for name in wanted_lights.difference(&published_lights) {
+        let path = light::light_path(name);
+        debug!("adding {}", path);
+        server.at(
+            path.as_str(),
+            RgbLightApi::new(shared.clone(), name.clone()),
+        )?;
+        Properties::publish(&mut server, &path, shared)?;
+        emit_added(&ctxt, &path, interfaces(&server, &path).await).await?;
+    }
+    *published_lights = wanted_lights;
+
+    let mut published_motors = shared.tree.published_motors.lock().await;
+    for name in published_motors.difference(&wanted_motors) {
+        let path = motor::motor_path(name);
+        debug!("removing {}", path);
+        let interfaces = interfaces(&server, &path).await;
+        server.remove::<MotorApi, _>(path.as_str())?;
+        emit_removed(&ctxt, &path, interfaces).await?;
+    }
+    for name in wanted_motors.difference(&published_motors) {
+        let path = motor::motor_path(name);
+        debug!("adding {}", path);
+        server.at(path.as_str(), MotorApi::new(shared.clone(), name.clone()))?;
+        Properties::publish(&mut server, &path, shared)?;
+        emit_added(&ctxt, &path, interfaces(&server, &path).await).await?;
+    }
+    *published_motors = wanted_motors;
+
+    if changed {
+        save_state(shared).await;
+    }
+    Ok(())
+}
+
+/// Published at `/com/kevinbader/pwmd`. Lets clients discover all objects and
+/// get notified when objects are added or removed.
+#[derive(Debug)]
+pub(super) struct ObjectManager {
+    shared: Arc<Shared>,
+}
+
+impl ObjectManager {
+    pub fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.DBus.ObjectManager")]
+impl ObjectManager {
+    /// Leaves out the properties of the objects the caller may not read.
+    async fn get_managed_objects(
+        &self,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> fdo::Result<HashMap<OwnedObjectPath, Interfaces>> {
+        let caller = self.shared.reader(connection, &header).await?;
+        let mut paths = vec![format!("{}/pwm1", ROOT_PATH)];
+        for (controller, channels) in self.shared.tree.published.lock().await.iter() {
+            paths.push(controller_path(*controller));
+            for channel in channels.keys() {
+                paths.push(channel_path(*controller, *channel));
+            }
+        }
+        for name in self.shared.tree.published_lights.lock().await.iter() {
+            paths.push(light::light_path(name));
+        }
+        for name in self.shared.tree.published_motors.lock().await.iter() {
+            paths.push(motor::motor_path(name));
+        }
+
+        let mut objects = HashMap::new();
+        for path in paths {
+            let mut interfaces = interfaces(server, &path).await;
+            let path: OwnedObjectPath = path.try_into().map_err(zbus::Error::from)?;
+            if let Some(caller) = &caller {
+                for (name, properties) in interfaces.iter_mut() {
+                    if !properties::readable(&self.shared, server, &path, name, caller).await {
+                        properties.clear();
+                    }
+                }
+            }
+            objects.insert(path, interfaces);
+        }
+        Ok(objects)
+    }
+
+    #[dbus_interface(signal)]
+    async fn interfaces_added(
+        ctxt: &SignalContext<'_>,
+        object_path: ObjectPath<'_>,
+        interfaces_and_properties: Interfaces,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn interfaces_removed(
+        ctxt: &SignalContext<'_>,
+        object_path: ObjectPath<'_>,
+        interfaces: Vec<String>,
+    ) -> zbus::Result<()>;
+}
+
+pub(super) fn controller_path(controller: u32) -> String {
+    format!("{}/pwmchip{}", ROOT_PATH, controller)
+}
+
+pub(super) fn channel_path(controller: u32, channel: u32) -> String {
+    format!("{}/pwmchip{}/pwm{}", ROOT_PATH, controller, channel)
+}
+
+/// Removes an interface that, depending on the channel's role, may not have
+/// been published at `path`.
+fn remove_if_present<I: Interface>(server: &mut ObjectServer, path: &str) -> zbus::Result<()> {
+    match server.remove::<I, _>(path) {
+        Ok(_) | Err(zbus::Error::InterfaceNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Collects the properties of all of our interfaces published at `path`.
+async fn interfaces(server: &ObjectServer, path: &str) -> Interfaces {
+    let mut interfaces = Interfaces::new();
+    add_properties::<PwmApi>(server, path, &mut interfaces).await;
+    add_properties::<ControllerApi>(server, path, &mut interfaces).await;
+    add_properties::<ChannelApi>(server, path, &mut interfaces).await;
+    add_properties::<LedApi>(server, path, &mut interfaces).await;
+    add_properties::<RgbLightApi>(server, path, &mut interfaces).await;
+    add_properties::<MotorApi>(server, path, &mut interfaces).await;
+    add_properties::<ServoApi>(server, path, &mut interfaces).await;
+    add_properties::<FanApi>(server, path, &mut interfaces).await;
+    add_properties::<BuzzerApi>(server, path, &mut interfaces).await;
+    interfaces
+}
+
+async fn add_properties<I: Interface>(
+    server: &ObjectServer,
+    path: &str,
+    interfaces: &mut Interfaces,
+) {
+    if let Ok(iface) = server.get_interface::<_, I>(path).await {
+        interfaces.insert(I::name().to_string(), iface.get_all().await);
+    }
+}
+
+async fn emit_added(
+    ctxt: &SignalContext<'_>,
+    path: &str,
+    interfaces: Interfaces,
+) -> zbus::Result<()> {
+    let path: ObjectPath<'_> = path.try_into()?;
+    ObjectManager::interfaces_added(ctxt, path, interfaces).await
+}
+
+async fn emit_removed(
+    ctxt: &SignalContext<'_>,
+    path: &str,
+    interfaces: Interfaces,
+) -> zbus::Result<()> {
+    let path: ObjectPath<'_> = path.try_into()?;
+    let names = interfaces.into_keys().collect();
+    ObjectManager::interfaces_removed(ctxt, path, names).await
+}
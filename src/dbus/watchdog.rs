@@ -0,0 +1,168 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+use zbus::{Connection, SignalContext};
+
+use super::{change_channel, PwmApi, Shared, ROOT_PATH};
+use crate::{
+    config::Fallback,
+    pwm::{Channel, Controller},
+};
+
+/// An armed watchdog: kicking it restarts its timer, and the task applies the
+/// fallback once the timer runs out.
+#[derive(Debug)]
+struct Watchdog {
+    kicks: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+/// The watchdogs armed for channels driving something that must not keep
+/// running if its client hangs, e.g., heaters or motors.
+///
+/// Unlike the tasks driving channels, watchdogs keep running when their
+/// channel is written to; only `disarm` or the watchdog expiring ends them.
+#[derive(Debug, Default)]
+pub(super) struct Watchdogs {
+    watchdogs: Mutex<HashMap<(u32, u32), Watchdog>>,
+}
+
+impl Watchdogs {
+    /// Arms the watchdog of a channel, replacing the one armed before, if
+    /// any. Unless kicked within `timeout`, it applies `fallback` to the
+    /// channel and emits `WatchdogExpired`.
+    pub async fn arm(
+        &self,
+        shared: Arc<Shared>,
+        connection: Connection,
+        controller: Controller,
+        channel: Channel,
+        timeout: Duration,
+        fallback: Fallback,
+    ) {
+        let mut watchdogs = self.watchdogs.lock().await;
+        if let Some(watchdog) = watchdogs.remove(&(controller.0, channel.0)) {
+            watchdog.task.abort();
+        }
+        let kicks = Arc::new(Notify::new());
+        let task = shared.runtime.spawn(watch(
+            shared.clone(),
+            connection,
+            controller.clone(),
+            channel.clone(),
+            timeout,
+            fallback,
+            kicks.clone(),
+        ));
+        watchdogs.insert((controller.0, channel.0), Watchdog { kicks, task });
+    }
+
+    /// Restarts the timer of a channel's watchdog. Returns false if no
+    /// watchdog is armed for the channel, e.g., because it expired already.
+    pub async fn kick(&self, controller: &Controller, channel: &Channel) -> bool {
+        match self.watchdogs.lock().await.get(&(controller.0, channel.0)) {
+            Some(watchdog) => {
+                watchdog.kicks.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Disarms the watchdog of a channel. Returns false if none was armed.
+    pub async fn disarm(&self, controller: &Controller, channel: &Channel) -> bool {
+        match self
+            .watchdogs
+            .lock()
+            .await
+            .remove(&(controller.0, channel.0))
+        {
+            Some(watchdog) => {
+                watchdog.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Disarms all watchdogs, e.g., before pwmd quits.
+    pub async fn disarm_all(&self) {
+        for (_, watchdog) in self.watchdogs.lock().await.drain() {
+            watchdog.task.abort();
+        }
+    }
+
+    /// Forgets the watchdog that expired, unless it was replaced meanwhile.
+    async fn expired(&self, controller: &Controller, channel: &Channel, kicks: &Arc<Notify>) {
+        let mut watchdogs = self.watchdogs.lock().await;
+        if let Some(watchdog) = watchdogs.get(&(controller.0, channel.0)) {
+            if Arc::ptr_eq(&watchdog.kicks, kicks) {
+                watchdogs.remove(&(controller.0, channel.0));
+            }
+        }
+    }
+}
+
+/// Waits for kicks until one doesn't come in time, then applies the
+/// fallback.
+async fn watch(
+    shared: Arc<Shared>,
+    connection: Connection,
+    controller: Controller,
+    channel: Channel,
+    timeout: Duration,
+    fallback: Fallback,
+    kicks: Arc<Notify>,
+) {
+    loop {
+        tokio::select! {
+            _ = kicks.notified() => continue,
+            _ = tokio::time::sleep(timeout) => break,
+        }
+    }
+    shared
+        .watchdogs
+        .expired(&controller, &channel, &kicks)
+        .await;
+    info!(
+        "watchdog of pwmchip{}/pwm{} expired, applying {:?}",
+        controller.0, channel.0, fallback
+    );
+
+    let exported_by_pwmd = !shared.exported_before.contains(&(controller.0, channel.0));
+    let res = change_channel(
+        &shared,
+        &*connection.object_server().await,
+        &controller,
+        &channel,
+        |pwm| {
+            pwm.fall_back(
+                controller.clone(),
+                channel.clone(),
+                fallback,
+                exported_by_pwmd,
+            )
+        },
+    )
+    .await;
+    if let Err(e) = res {
+        warn!(
+            "failed to fall back pwmchip{}/pwm{}: {:?}",
+            controller.0, channel.0, e
+        );
+    }
+    shared.tree.refresh();
+
+    let path = format!("{}/pwm1", ROOT_PATH);
+    let res = match SignalContext::new(&connection, path.as_str()) {
+        Ok(ctxt) => PwmApi::watchdog_expired(&ctxt, controller.0, channel.0).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!("failed to emit expiry of watchdog: {:?}", e);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{f64::consts::PI, time::Duration};
+
+use tracing::instrument;
+
+use crate::{
+    led::{Fade, Gamma},
+    pwm::{Channel, Controller, Pwm, PwmError},
+};
+
+/// An animation of an LED's brightness.
+///
+/// Effects with a `count` repeat that many times; a count of 0 repeats them
+/// until they are stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    /// Changes the brightness gradually, see [`Fade`].
+    Fade(Fade),
+    /// Switches between full brightness and off.
+    Blink {
+        on: Duration,
+        off: Duration,
+        count: u32,
+    },
+    /// Fades in and out smoothly, like a sleeping laptop.
+    Breathe { period: Duration },
+    /// Two short flashes per period, like the kernel's heartbeat LED trigger.
+    Heartbeat { period: Duration },
+    /// Fades in, then out again.
+    Pulse {
+        rise: Duration,
+        fall: Duration,
+        count: u32,
+    },
+}
+
+impl Effect {
+    pub fn blink(on: Duration, off: Duration, count: u32) -> Result<Self, PwmError> {
+        check_cycle(on + off)?;
+        Ok(Effect::Blink { on, off, count })
+    }
+
+    pub fn breathe(period: Duration) -> Result<Self, PwmError> {
+        check_cycle(period)?;
+        Ok(Effect::Breathe { period })
+    }
+
+    pub fn heartbeat(period: Duration) -> Result<Self, PwmError> {
+        check_cycle(period)?;
+        Ok(Effect::Heartbeat { period })
+    }
+
+    pub fn pulse(rise: Duration, fall: Duration, count: u32) -> Result<Self, PwmError> {
+        check_cycle(rise + fall)?;
+        Ok(Effect::Pulse { rise, fall, count })
+    }
+
+    /// How long the effect runs, or `None` if it runs until it is stopped.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Effect::Fade(fade) => Some(fade.duration),
+            Effect::Blink { count: 0, .. } | Effect::Pulse { count: 0, .. } => None,
+            Effect::Blink { on, off, count } => Some((*on + *off) * *count),
+            Effect::Pulse { rise, fall, count } => Some((*rise + *fall) * *count),
+            Effect::Breathe { .. } | Effect::Heartbeat { .. } => None,
+        }
+    }
+
+    /// The brightness after `elapsed` time has passed since the effect
+    /// started. Effects that are over stay at their final brightness.
+    pub fn brightness_at(&self, elapsed: Duration) -> f64 {
+        match self {
+            Effect::Fade(fade) => fade.brightness_at(elapsed),
+            Effect::Blink { .. } | Effect::Pulse { .. }
+                if self.duration().is_some_and(|d| elapsed >= d) =>
+            {
+                0.0
+            }
+            Effect::Blink { on, off, .. } => {
+                if phase(elapsed, *on + *off) < on.as_secs_f64() {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Effect::Breathe { period } => {
+                let phase = phase(elapsed, *period) / period.as_secs_f64();
+                (1.0 - (2.0 * PI * phase).cos()) / 2.0
+            }
+            Effect::Heartbeat { period } => {
+                let phase = phase(elapsed, *period) / period.as_secs_f64();
+                if phase < 0.07 || (0.25..0.32).contains(&phase) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Effect::Pulse { rise, fall, .. } => {
+                let phase = phase(elapsed, *rise + *fall);
+                let (rise, fall) = (rise.as_secs_f64(), fall.as_secs_f64());
+                if phase < rise {
+                    phase / rise
+                } else {
+                    1.0 - (phase - rise) / fall
+                }
+            }
+        }
+    }
+}
+
+impl Pwm {
+    /// Sets an LED to the brightness an effect starts with. Running the
+    /// effect is up to the caller.
+    #[instrument]
+    pub fn start_effect(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        effect: Effect,
+        gamma: Gamma,
+    ) -> Result<Effect, PwmError> {
+        let brightness = effect.brightness_at(Duration::ZERO);
+        self.set_brightness(controller, channel, brightness, gamma)?;
+        Ok(effect)
+    }
+}
+
+fn check_cycle(cycle: Duration) -> Result<(), PwmError> {
+    if cycle.is_zero() {
+        Err(PwmError::InvalidEffect("duration must not be zero"))
+    } else {
+        Ok(())
+    }
+}
+
+/// The time passed since the current cycle started, in seconds.
+fn phase(elapsed: Duration, cycle: Duration) -> f64 {
+    elapsed.as_secs_f64() % cycle.as_secs_f64()
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn blink_the_given_number_of_times() {
+        let blink = Effect::blink(ms(100), ms(300), 2).unwrap();
+        assert_eq!(blink.duration(), Some(ms(800)));
+        assert_eq!(blink.brightness_at(ms(0)), 1.0);
+        assert_eq!(blink.brightness_at(ms(99)), 1.0);
+        assert_eq!(blink.brightness_at(ms(100)), 0.0);
+        assert_eq!(blink.brightness_at(ms(450)), 1.0);
+        assert_eq!(blink.brightness_at(ms(850)), 0.0);
+
+        let forever = Effect::blink(ms(100), ms(300), 0).unwrap();
+        assert_eq!(forever.duration(), None);
+        assert_eq!(forever.brightness_at(ms(4050)), 1.0);
+
+        assert!(matches!(
+            Effect::blink(ms(0), ms(0), 1),
+            Err(PwmError::InvalidEffect(_))
+        ));
+    }
+
+    #[test]
+    fn breathe_smoothly() {
+        let breathe = Effect::breathe(ms(1000)).unwrap();
+        assert_eq!(breathe.duration(), None);
+        assert_eq!(breathe.brightness_at(ms(0)), 0.0);
+        assert!((breathe.brightness_at(ms(250)) - 0.5).abs() < 1e-9);
+        assert_eq!(breathe.brightness_at(ms(500)), 1.0);
+        assert!(breathe.brightness_at(ms(999)) < 0.01);
+    }
+
+    #[test]
+    fn beat_twice_per_period() {
+        let heartbeat = Effect::heartbeat(ms(1000)).unwrap();
+        let on: Vec<u64> = (0..2000)
+            .step_by(10)
+            .filter(|t| heartbeat.brightness_at(ms(*t)) > 0.0)
+            .collect();
+        assert_eq!(on.first(), Some(&0));
+        assert!(on.contains(&250));
+        assert!(!on.contains(&100));
+        assert!(!on.contains(&500));
+        assert!(on.contains(&1000));
+    }
+
+    #[test]
+    fn pulse_up_and_down() {
+        let pulse = Effect::pulse(ms(100), ms(400), 1).unwrap();
+        assert_eq!(pulse.duration(), Some(ms(500)));
+        assert_eq!(pulse.brightness_at(ms(50)), 0.5);
+        assert_eq!(pulse.brightness_at(ms(100)), 1.0);
+        assert_eq!(pulse.brightness_at(ms(300)), 0.5);
+        assert_eq!(pulse.brightness_at(ms(600)), 0.0);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fs, path::Path};
+
+use tracing::{instrument, warn};
+
+use crate::pwm::{Access, Channel, Controller, Pwm, PwmError};
+
+/// A temperature sensor, given relative to the sysfs class directory: either
+/// a thermal zone (`thermal/thermal_zoneN/temp`) or a hwmon input
+/// (`hwmon/hwmonN/tempM_input`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor(String);
+
+impl Sensor {
+    pub fn new(name: &str) -> Result<Self, PwmError> {
+        let number = |s: Option<&str>| {
+            s.is_some_and(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+        };
+        let valid = match name.split('/').collect::<Vec<_>>()[..] {
+            ["thermal", zone, "temp"] => number(zone.strip_prefix("thermal_zone")),
+            ["hwmon", hwmon, input] => {
+                number(hwmon.strip_prefix("hwmon"))
+                    && number(
+                        input
+                            .strip_prefix("temp")
+                            .and_then(|s| s.strip_suffix("_input")),
+                    )
+            }
+            _ => false,
+        };
+        if valid {
+            Ok(Self(name.to_owned()))
+        } else {
+            Err(PwmError::InvalidSensor(name.to_owned()))
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Reads the temperature in degrees Celsius. `root` is the sysfs class
+    /// directory, usually `/sys/class`.
+    pub fn read(&self, root: &Path) -> Result<f64, PwmError> {
+        let path = root.join(&self.0);
+        let content = fs::read_to_string(&path)
+            .map_err(|e| PwmError::Sysfs(Access::Read(path.clone()), e))?;
+        let millidegrees = content
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| PwmError::NotATemperature(content.clone()))?;
+        Ok(millidegrees as f64 / 1000.0)
+    }
+}
+
+/// Maps temperatures onto the duty ratio of a fan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanCurve {
+    /// Pairs of temperature in degrees Celsius and duty ratio, ordered by
+    /// temperature. The duty ratio is interpolated linearly in between and
+    /// stays at the first or last value outside of the curve.
+    points: Vec<(f64, f64)>,
+    /// How many degrees the temperature needs to fall before the fan slows
+    /// down, so it doesn't keep speeding up and slowing down around a point.
+    pub hysteresis: f64,
+    /// The duty ratio below which the fan doesn't spin up. Lower duty ratios
+    /// other than zero are raised to it.
+    pub min_duty: f64,
+}
+
+impl FanCurve {
+    pub fn new(points: Vec<(f64, f64)>, hysteresis: f64, min_duty: f64) -> Result<Self, PwmError> {
+        if points.is_empty() {
+            return Err(PwmError::InvalidFanCurve("expected at least one point"));
+        }
+        if !points
+            .iter()
+            .all(|(temperature, _)| temperature.is_finite())
+            || !points.windows(2).all(|pair| pair[0].0 < pair[1].0)
+        {
+            return Err(PwmError::InvalidFanCurve(
+                "temperatures must be finite and increasing",
+            ));
+        }
+        if let Some((_, duty)) = points.iter().find(|(_, duty)| !(0.0..=1.0).contains(duty)) {
+            return Err(PwmError::InvalidDutyRatio(*duty));
+        }
+        if !(hysteresis.is_finite() && hysteresis >= 0.0) {
+            return Err(PwmError::InvalidFanCurve("hysteresis must not be negative"));
+        }
+        if !(0.0..=1.0).contains(&min_duty) {
+            return Err(PwmError::InvalidDutyRatio(min_duty));
+        }
+        Ok(Self {
+            points,
+            hysteresis,
+            min_duty,
+        })
+    }
+
+    pub fn points(&self) -> &[(f64, f64)] {
+        &self.points
+    }
+
+    /// The duty ratio the curve gives for `temperature`, not taking
+    /// hysteresis and minimum duty ratio into account.
+    pub fn interpolate(&self, temperature: f64) -> f64 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if temperature <= first.0 {
+            return first.1;
+        }
+        if temperature >= last.0 {
+            return last.1;
+        }
+        let upper = self
+            .points
+            .iter()
+            .position(|(t, _)| *t > temperature)
+            .unwrap_or(self.points.len() - 1);
+        let ((t0, d0), (t1, d1)) = (self.points[upper - 1], self.points[upper]);
+        d0 + (d1 - d0) * (temperature - t0) / (t1 - t0)
+    }
+
+    /// The duty ratio to change to from `current` at `temperature`.
+    ///
+    /// Rising temperatures speed the fan up right away. Falling temperatures
+    /// only slow it down once they are `hysteresis` degrees below the point
+    /// that gives the current duty ratio.
+    pub fn duty_ratio(&self, temperature: f64, current: f64) -> f64 {
+        let rising = self.interpolate(temperature);
+        let duty = if rising >= current {
+            rising
+        } else {
+            self.interpolate(temperature + self.hysteresis).min(current)
+        };
+        if duty > 0.0 {
+            duty.max(self.min_duty)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Treats a channel as a fan.
+impl Pwm {
+    /// Sets a fan's duty ratio according to the curve and the temperature
+    /// read from the sensor. If the sensor cannot be read, the fan runs at
+    /// full speed. Either way, the duty ratio is capped at the channel's
+    /// `max_duty_ratio` limit. The channel's period needs to be set already;
+    /// the channel is enabled if it isn't yet. Returns the new duty ratio.
+    #[instrument]
+    pub fn control_fan(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        curve: &FanCurve,
+        temperature: Result<f64, PwmError>,
+    ) -> Result<f64, PwmError> {
+        let timing = self.timing(&controller, &channel)?;
+        if timing.period.is_zero() {
+            return Err(PwmError::PeriodNotSet(controller, channel));
+        }
+        let duty_ratio = match temperature {
+            Ok(temperature) => curve.duty_ratio(temperature, timing.duty_ratio()),
+            Err(e) => {
+                warn!("running the fan at full speed: {}", e);
+                1.0
+            }
+        };
+        let max_duty_ratio = self
+            .limits(&controller, &channel)
+            .and_then(|limits| limits.max_duty_ratio)
+            .unwrap_or(1.0);
+        let duty_ratio = duty_ratio.min(max_duty_ratio);
+        self.set_duty_ratio(controller.clone(), channel.clone(), duty_ratio)?;
+        if !self.is_enabled(&controller, &channel)? {
+            self.enable(controller, channel)?;
+        }
+        Ok(duty_ratio)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::{limits::Limits, pwm::fake_sysfs};
+    use temp_dir::TempDir;
+
+    #[test]
+    fn interpolate_between_points() {
+        let curve = FanCurve::new(vec![(30.0, 0.2), (60.0, 1.0)], 0.0, 0.0).unwrap();
+        assert_eq!(curve.interpolate(20.0), 0.2);
+        assert!((curve.interpolate(45.0) - 0.6).abs() < 1e-9);
+        assert_eq!(curve.interpolate(70.0), 1.0);
+
+        assert!(FanCurve::new(vec![], 0.0, 0.0).is_err());
+        assert!(FanCurve::new(vec![(60.0, 0.2), (30.0, 1.0)], 0.0, 0.0).is_err());
+        assert!(FanCurve::new(vec![(30.0, 1.2)], 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn slow_down_only_after_the_hysteresis() {
+        let curve = FanCurve::new(vec![(30.0, 0.0), (40.0, 0.4), (60.0, 1.0)], 5.0, 0.3).unwrap();
+        // rising temperatures apply right away:
+        assert!((curve.duty_ratio(50.0, 0.4) - 0.7).abs() < 1e-9);
+        // falling a little keeps the fan at its speed:
+        assert_eq!(curve.duty_ratio(47.0, 0.7), 0.7);
+        // falling further slows it down, but only by as much as needed:
+        assert!((curve.duty_ratio(40.0, 0.7) - 0.55).abs() < 1e-9);
+        // slow speeds are raised to the spin-up minimum, zero turns it off:
+        assert_eq!(curve.duty_ratio(32.0, 0.0), 0.3);
+        assert_eq!(curve.duty_ratio(20.0, 0.3), 0.0);
+    }
+
+    #[test]
+    fn read_sensors_and_fail_safe() {
+        let tmp = TempDir::new().unwrap();
+        let zone = tmp.child("thermal/thermal_zone0");
+        fs::create_dir_all(&zone).unwrap();
+        fs::write(zone.join("temp"), "45000\n").unwrap();
+        let chip = fake_sysfs::chip(&tmp.child("pwm"), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 0);
+        let mut pwm = Pwm::with_sysfs_root(tmp.child("pwm"));
+        let curve = FanCurve::new(vec![(30.0, 0.2), (60.0, 1.0)], 0.0, 0.0).unwrap();
+
+        let sensor = Sensor::new("thermal/thermal_zone0/temp").unwrap();
+        assert_eq!(sensor.read(tmp.path()).unwrap(), 45.0);
+        let temperature = sensor.read(tmp.path());
+        pwm.control_fan(Controller(0), Channel(0), &curve, temperature)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "600"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+
+        let missing = Sensor::new("hwmon/hwmon1/temp2_input").unwrap();
+        let temperature = missing.read(tmp.path());
+        assert!(temperature.is_err());
+        pwm.control_fan(Controller(0), Channel(0), &curve, temperature)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "1000"
+        );
+
+        // neither the curve nor the failsafe exceed the duty cycle limit:
+        let limits = Limits {
+            max_duty_ratio: Some(0.8),
+            ..Limits::default()
+        };
+        pwm.set_limits(&Controller(0), &Channel(0), limits);
+        for temperature in [Ok(70.0), missing.read(tmp.path())] {
+            pwm.control_fan(Controller(0), Channel(0), &curve, temperature)
+                .unwrap();
+            assert_eq!(
+                fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+                "800"
+            );
+        }
+
+        assert!(Sensor::new("thermal/thermal_zone0/../../etc/passwd").is_err());
+        assert!(Sensor::new("hwmon/hwmon0/temp_input").is_err());
+    }
+}
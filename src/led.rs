@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use tracing::instrument;
+
+use crate::pwm::{Channel, Controller, Pwm, PwmError};
+
+/// Maps brightness onto the duty ratio, such that equal steps in brightness
+/// are perceived as equal steps by the human eye.
+///
+/// The duty ratio is `brightness ^ gamma`; a gamma of 1 maps brightness
+/// linearly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gamma(f64);
+
+impl Gamma {
+    pub fn new(gamma: f64) -> Result<Self, PwmError> {
+        if gamma.is_finite() && gamma > 0.0 {
+            Ok(Self(gamma))
+        } else {
+            Err(PwmError::InvalidGamma(gamma))
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+
+    /// The duty ratio that makes an LED appear at the given brightness.
+    pub fn duty_ratio(&self, brightness: f64) -> f64 {
+        brightness.powf(self.0)
+    }
+
+    /// The brightness an LED appears at for the given duty ratio.
+    pub fn brightness(&self, duty_ratio: f64) -> f64 {
+        duty_ratio.powf(1.0 / self.0)
+    }
+}
+
+impl Default for Gamma {
+    /// The gamma commonly used for LEDs.
+    fn default() -> Self {
+        Self(2.2)
+    }
+}
+
+/// A transition from one brightness to another that is linear in perceived
+/// brightness.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fade {
+    pub from: f64,
+    pub to: f64,
+    pub duration: Duration,
+}
+
+impl Fade {
+    /// The brightness after `elapsed` time has passed since the fade started.
+    pub fn brightness_at(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.duration {
+            return self.to;
+        }
+        let progress = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        self.from + (self.to - self.from) * progress
+    }
+}
+
+/// Treats a channel as an LED.
+impl Pwm {
+    /// Returns the brightness of an LED, between 0 and 1.
+    #[instrument]
+    pub fn brightness(
+        &self,
+        controller: &Controller,
+        channel: &Channel,
+        gamma: Gamma,
+    ) -> Result<f64, PwmError> {
+        let timing = self.timing(controller, channel)?;
+        Ok(gamma.brightness(timing.duty_ratio()))
+    }
+
+    /// Sets the brightness of an LED, between 0 and 1. The channel's period
+    /// needs to be set already; the channel is enabled if it isn't yet.
+    #[instrument]
+    pub fn set_brightness(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        brightness: f64,
+        gamma: Gamma,
+    ) -> Result<(), PwmError> {
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(PwmError::InvalidBrightness(brightness));
+        }
+        if self.period(&controller, &channel)?.is_zero() {
+            return Err(PwmError::PeriodNotSet(controller, channel));
+        }
+        self.set_duty_ratio(
+            controller.clone(),
+            channel.clone(),
+            gamma.duty_ratio(brightness),
+        )?;
+        if !self.is_enabled(&controller, &channel)? {
+            self.enable(controller, channel)?;
+        }
+        Ok(())
+    }
+
+    /// Prepares a fade of an LED from its current brightness. The channel is
+    /// enabled if it isn't yet; the fade itself is up to the caller.
+    #[instrument]
+    pub fn start_fade(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        brightness: f64,
+        duration: Duration,
+        gamma: Gamma,
+    ) -> Result<Fade, PwmError> {
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(PwmError::InvalidBrightness(brightness));
+        }
+        let from = self.brightness(&controller, &channel, gamma)?;
+        // Checks the period and enables the channel:
+        self.set_brightness(controller, channel, from, gamma)?;
+        Ok(Fade {
+            from,
+            to: brightness,
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use std::fs;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn map_brightness_onto_duty_ratio() {
+        let gamma = Gamma::default();
+        assert_eq!(gamma.duty_ratio(0.0), 0.0);
+        assert_eq!(gamma.duty_ratio(1.0), 1.0);
+        assert!((gamma.duty_ratio(0.5) - 0.2176).abs() < 1e-4);
+        assert!((gamma.brightness(gamma.duty_ratio(0.3)) - 0.3).abs() < 1e-9);
+
+        let linear = Gamma::new(1.0).unwrap();
+        assert_eq!(linear.duty_ratio(0.5), 0.5);
+
+        assert!(matches!(Gamma::new(0.0), Err(PwmError::InvalidGamma(_))));
+    }
+
+    #[test]
+    fn fade_linearly() {
+        let fade = Fade {
+            from: 1.0,
+            to: 0.5,
+            duration: Duration::from_millis(100),
+        };
+        assert_eq!(fade.brightness_at(Duration::ZERO), 1.0);
+        assert_eq!(fade.brightness_at(Duration::from_millis(50)), 0.75);
+        assert_eq!(fade.brightness_at(Duration::from_millis(100)), 0.5);
+        assert_eq!(fade.brightness_at(Duration::from_secs(1)), 0.5);
+    }
+
+    #[test]
+    fn set_brightness_and_enable_the_channel() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 0, 0);
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let gamma = Gamma::new(2.0).unwrap();
+
+        assert!(matches!(
+            pwm.set_brightness(Controller(0), Channel(0), 0.5, gamma),
+            Err(PwmError::PeriodNotSet(..))
+        ));
+
+        fs::write(channel.join("period"), "1000").unwrap();
+        pwm.set_brightness(Controller(0), Channel(0), 0.5, gamma)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "250"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+        assert_eq!(
+            pwm.brightness(&Controller(0), &Channel(0), gamma).unwrap(),
+            0.5
+        );
+
+        assert!(matches!(
+            pwm.set_brightness(Controller(0), Channel(0), 1.1, gamma),
+            Err(PwmError::InvalidBrightness(_))
+        ));
+    }
+}
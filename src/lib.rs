@@ -1,11 +1,33 @@
 #![doc = include_str!("../README.md")]
 
+/// Who may read from and write to channels.
+mod access;
 /// Global options
 pub mod args;
+/// Colors and RGB(W) LEDs made up of several channels.
+mod color;
+/// Named channels from the configuration file.
+mod config;
 /// DBUS interface
 pub mod dbus;
+/// Animations of an LED's brightness.
+mod effect;
+/// Fans controlled by temperature sensors.
+mod fan;
+/// Brightness and fades for LEDs driven by a PWM channel.
+mod led;
+/// Safety limits of channels.
+mod limits;
+/// DC motors driven by a pair of channels.
+mod motor;
 /// Wraps/exposes the Linux Kernel's PWM functionality.
 mod pwm;
+/// Hobby servos driven by a PWM channel.
+mod servo;
+/// Channel settings that survive restarts.
+mod state;
+/// Tones and melodies for buzzers.
+mod tone;
 
 pub use args::Args;
 use tracing_subscriber::EnvFilter;
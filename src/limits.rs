@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use crate::pwm::{Polarity, PwmState};
+
+/// Safety limits of a channel, e.g., to keep a buggy client from overdriving
+/// an LED or a motor. `Pwm` checks them on every write to the channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// The largest share of the period the signal may be active, between 0
+    /// and 1.
+    pub max_duty_ratio: Option<f64>,
+    pub min_period: Option<Duration>,
+    pub max_period: Option<Duration>,
+    /// The only polarity the channel may have.
+    pub polarity: Option<Polarity>,
+    /// Rejects all writes, including unexporting the channel. pwmd's own
+    /// fallbacks, e.g., on shutdown, still apply.
+    pub read_only: bool,
+}
+
+impl Limits {
+    /// Checks a write that changes the channel's settings from `before` to
+    /// `after`. A limit is only checked if the write changes the setting it
+    /// applies to or enables the channel, so a channel that breaks its limits
+    /// already, e.g., because of a change made outside of pwmd, can still be
+    /// disabled or brought back within them. Returns what's wrong otherwise.
+    pub fn check(&self, before: &PwmState, after: &PwmState) -> Result<(), String> {
+        if self.read_only {
+            return Err("channel is read-only".to_owned());
+        }
+        let enabling = after.enabled && !before.enabled;
+
+        if let Some(polarity) = self.polarity {
+            if after.polarity != polarity && (enabling || after.polarity != before.polarity) {
+                return Err(format!("polarity is locked to {}", polarity));
+            }
+        }
+
+        let period_changed = after.period != before.period;
+        if let Some(min_period) = self.min_period {
+            if after.period < min_period && (enabling || period_changed) {
+                return Err(format!(
+                    "period of {} ns is shorter than the minimum of {} ns",
+                    after.period.as_nanos(),
+                    min_period.as_nanos()
+                ));
+            }
+        }
+        if let Some(max_period) = self.max_period {
+            if after.period > max_period && (enabling || period_changed) {
+                return Err(format!(
+                    "period of {} ns is longer than the maximum of {} ns",
+                    after.period.as_nanos(),
+                    max_period.as_nanos()
+                ));
+            }
+        }
+
+        if let Some(max_duty_ratio) = self.max_duty_ratio {
+            let duty_changed = after.duty_cycle != before.duty_cycle;
+            // Rounded like `Pwm::set_duty_ratio` rounds, so setting the
+            // maximum ratio itself is fine:
+            let max_duty_cycle = (after.period.as_nanos() as f64 * max_duty_ratio).round() as u128;
+            if after.duty_cycle.as_nanos() > max_duty_cycle
+                && (enabling || period_changed || duty_changed)
+            {
+                return Err(format!(
+                    "duty cycle of {} ns exceeds the maximum of {}% of the period",
+                    after.duty_cycle.as_nanos(),
+                    max_duty_ratio * 100.0
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn state(period_ns: u64, duty_cycle_ns: u64, polarity: Polarity, enabled: bool) -> PwmState {
+        PwmState {
+            period: Duration::from_nanos(period_ns),
+            duty_cycle: Duration::from_nanos(duty_cycle_ns),
+            polarity,
+            enabled,
+        }
+    }
+
+    #[test]
+    fn reject_writes_that_break_a_limit() {
+        let limits = Limits {
+            max_duty_ratio: Some(0.5),
+            min_period: Some(Duration::from_nanos(100)),
+            max_period: Some(Duration::from_nanos(1000)),
+            polarity: Some(Polarity::Normal),
+            read_only: false,
+        };
+        let before = state(1000, 100, Polarity::Normal, false);
+        let check = |after| limits.check(&before, &after);
+
+        assert!(check(state(1000, 500, Polarity::Normal, true)).is_ok());
+        assert!(check(state(1000, 501, Polarity::Normal, false)).is_err());
+        assert!(check(state(150, 100, Polarity::Normal, false)).is_err());
+        assert!(check(state(50, 0, Polarity::Normal, false)).is_err());
+        assert!(check(state(2000, 100, Polarity::Normal, false)).is_err());
+        assert!(check(state(1000, 100, Polarity::Inversed, false)).is_err());
+
+        let read_only = Limits {
+            read_only: true,
+            ..Limits::default()
+        };
+        assert_eq!(
+            read_only.check(&before, &before),
+            Err("channel is read-only".to_owned())
+        );
+    }
+
+    #[test]
+    fn allow_disabling_a_channel_that_breaks_its_limits() {
+        let limits = Limits {
+            max_duty_ratio: Some(0.5),
+            polarity: Some(Polarity::Normal),
+            ..Limits::default()
+        };
+        let before = state(1000, 900, Polarity::Inversed, true);
+
+        assert!(limits
+            .check(&before, &state(1000, 900, Polarity::Inversed, false))
+            .is_ok());
+        assert!(limits
+            .check(&before, &state(1000, 400, Polarity::Inversed, true))
+            .is_ok());
+        // ...but not enabling it again:
+        let disabled = state(1000, 900, Polarity::Normal, false);
+        assert!(limits
+            .check(&disabled, &state(1000, 900, Polarity::Normal, true))
+            .is_err());
+    }
+}
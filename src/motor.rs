@@ -0,0 +1,305 @@
+use std::{convert::TryInto, fmt::Display, str::FromStr, time::Duration};
+
+use tracing::instrument;
+
+use crate::pwm::{Channel, Controller, Pwm, PwmError};
+
+/// How a motor driver, e.g., an H-bridge, is wired to the two channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wiring {
+    /// The first channel sets the speed, the second one the direction: fully
+    /// on for forward, off for reverse.
+    Direction,
+    /// The first channel drives the motor forward, the second one in reverse.
+    Dual,
+}
+
+impl FromStr for Wiring {
+    type Err = PwmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direction" => Ok(Wiring::Direction),
+            "dual" => Ok(Wiring::Dual),
+            _ => Err(PwmError::InvalidMotor(
+                "legal wiring values: 'direction', 'dual'",
+            )),
+        }
+    }
+}
+
+impl Display for Wiring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Wiring::Direction => write!(f, "direction"),
+            Wiring::Dual => write!(f, "dual"),
+        }
+    }
+}
+
+/// A DC motor driven by two channels.
+#[derive(Debug, Clone)]
+pub struct Motor {
+    channels: [(Controller, Channel); 2],
+    wiring: Wiring,
+    /// How fast the speed may increase, in full speeds per second.
+    acceleration: f64,
+    /// How fast the speed may decrease, in full speeds per second.
+    deceleration: f64,
+}
+
+impl Motor {
+    pub fn new(channels: Vec<(Controller, Channel)>, wiring: Wiring) -> Result<Self, PwmError> {
+        let channels: [(Controller, Channel); 2] = channels
+            .try_into()
+            .map_err(|_| PwmError::InvalidMotor("expected 2 channels"))?;
+        let [(c0, ch0), (c1, ch1)] = &channels;
+        if c0.0 == c1.0 && ch0.0 == ch1.0 {
+            return Err(PwmError::InvalidMotor("channels must not repeat"));
+        }
+        Ok(Self {
+            channels,
+            wiring,
+            acceleration: 1.0,
+            deceleration: 1.0,
+        })
+    }
+
+    pub fn channels(&self) -> &[(Controller, Channel)] {
+        &self.channels
+    }
+
+    pub fn wiring(&self) -> Wiring {
+        self.wiring
+    }
+
+    pub fn acceleration(&self) -> f64 {
+        self.acceleration
+    }
+
+    pub fn set_acceleration(&mut self, rate: f64) -> Result<(), PwmError> {
+        self.acceleration = check_rate(rate)?;
+        Ok(())
+    }
+
+    pub fn deceleration(&self) -> f64 {
+        self.deceleration
+    }
+
+    pub fn set_deceleration(&mut self, rate: f64) -> Result<(), PwmError> {
+        self.deceleration = check_rate(rate)?;
+        Ok(())
+    }
+
+    /// The duty ratios of both channels for the given speed.
+    fn duty_ratios(&self, speed: f64) -> [f64; 2] {
+        let forward = speed >= 0.0;
+        match self.wiring {
+            Wiring::Direction => [speed.abs(), if forward { 1.0 } else { 0.0 }],
+            Wiring::Dual if forward => [speed, 0.0],
+            Wiring::Dual => [0.0, -speed],
+        }
+    }
+}
+
+fn check_rate(rate: f64) -> Result<f64, PwmError> {
+    if rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(PwmError::InvalidMotor("rates must be greater than zero"))
+    }
+}
+
+/// A change of a motor's speed at its rates of acceleration and deceleration.
+/// Reversing slows the motor down to zero first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ramp {
+    pub from: f64,
+    pub to: f64,
+    pub acceleration: f64,
+    pub deceleration: f64,
+}
+
+impl Ramp {
+    /// The speeds the ramp goes through, each reached at the given rate.
+    fn phases(&self) -> Vec<(f64, f64, f64)> {
+        if self.from * self.to < 0.0 {
+            vec![
+                (self.from, 0.0, self.deceleration),
+                (0.0, self.to, self.acceleration),
+            ]
+        } else if self.to.abs() >= self.from.abs() {
+            vec![(self.from, self.to, self.acceleration)]
+        } else {
+            vec![(self.from, self.to, self.deceleration)]
+        }
+    }
+
+    /// How long the ramp takes.
+    pub fn duration(&self) -> Duration {
+        let secs = self
+            .phases()
+            .iter()
+            .map(|(from, to, rate)| (to - from).abs() / rate)
+            .sum();
+        Duration::from_secs_f64(secs)
+    }
+
+    /// The speed after `elapsed` time has passed since the ramp started.
+    pub fn speed_at(&self, elapsed: Duration) -> f64 {
+        let mut left = elapsed.as_secs_f64();
+        for (from, to, rate) in self.phases() {
+            let needed = (to - from).abs() / rate;
+            if left < needed {
+                return from + (to - from).signum() * left * rate;
+            }
+            left -= needed;
+        }
+        self.to
+    }
+}
+
+/// Treats a pair of channels as a motor.
+impl Pwm {
+    /// Returns the speed of a motor, between -1 (full reverse) and 1 (full
+    /// forward).
+    #[instrument]
+    pub fn motor_speed(&self, motor: &Motor) -> Result<f64, PwmError> {
+        let mut ratios = [0.0; 2];
+        for (ratio, (controller, channel)) in ratios.iter_mut().zip(&motor.channels) {
+            if self.is_enabled(controller, channel)? {
+                *ratio = self.timing(controller, channel)?.duty_ratio();
+            }
+        }
+        Ok(match motor.wiring {
+            Wiring::Direction if ratios[1] >= 0.5 => ratios[0],
+            Wiring::Direction => -ratios[0],
+            Wiring::Dual => ratios[0] - ratios[1],
+        })
+    }
+
+    /// Sets the speed of a motor right away. Reversing stops the motor before
+    /// its direction is changed. The periods of both channels need to be set
+    /// already; the channels are enabled if they aren't yet.
+    #[instrument]
+    pub fn set_motor_speed(&mut self, motor: &Motor, speed: f64) -> Result<(), PwmError> {
+        if !(-1.0..=1.0).contains(&speed) {
+            return Err(PwmError::InvalidSpeed(speed));
+        }
+        for (controller, channel) in &motor.channels {
+            if self.period(controller, channel)?.is_zero() {
+                return Err(PwmError::PeriodNotSet(controller.clone(), channel.clone()));
+            }
+        }
+        let reversing = self.motor_speed(motor)? * speed < 0.0;
+        if reversing {
+            self.drive(&motor.channels[0], 0.0)?;
+            if motor.wiring == Wiring::Dual {
+                self.drive(&motor.channels[1], 0.0)?;
+            }
+        }
+        // The direction before the speed:
+        let [speed_ratio, direction_ratio] = motor.duty_ratios(speed);
+        self.drive(&motor.channels[1], direction_ratio)?;
+        self.drive(&motor.channels[0], speed_ratio)
+    }
+
+    /// Prepares a ramp of a motor from its current speed. The channels are
+    /// enabled if they aren't yet; the ramp itself is up to the caller.
+    #[instrument]
+    pub fn start_ramp(&mut self, motor: &Motor, speed: f64) -> Result<Ramp, PwmError> {
+        if !(-1.0..=1.0).contains(&speed) {
+            return Err(PwmError::InvalidSpeed(speed));
+        }
+        let from = self.motor_speed(motor)?;
+        // Checks the periods and enables the channels:
+        self.set_motor_speed(motor, from)?;
+        Ok(Ramp {
+            from,
+            to: speed,
+            acceleration: motor.acceleration,
+            deceleration: motor.deceleration,
+        })
+    }
+
+    fn drive(
+        &mut self,
+        (controller, channel): &(Controller, Channel),
+        duty_ratio: f64,
+    ) -> Result<(), PwmError> {
+        self.set_duty_ratio(controller.clone(), channel.clone(), duty_ratio)?;
+        if !self.is_enabled(controller, channel)? {
+            self.enable(controller.clone(), channel.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use std::fs;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn ramp_through_zero() {
+        let ramp = Ramp {
+            from: 0.5,
+            to: -1.0,
+            acceleration: 1.0,
+            deceleration: 2.0,
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(ramp.duration(), ms(1250));
+        assert_eq!(ramp.speed_at(Duration::ZERO), 0.5);
+        assert_eq!(ramp.speed_at(ms(125)), 0.25);
+        assert_eq!(ramp.speed_at(ms(250)), 0.0);
+        assert_eq!(ramp.speed_at(ms(750)), -0.5);
+        assert_eq!(ramp.speed_at(ms(2000)), -1.0);
+
+        let slower = Ramp {
+            from: 1.0,
+            to: 0.5,
+            acceleration: 1.0,
+            deceleration: 2.0,
+        };
+        assert_eq!(slower.duration(), ms(250));
+    }
+
+    #[test]
+    fn drive_both_wirings() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 2);
+        for i in 0..2 {
+            fake_sysfs::channel(&chip, i, 1000, 0);
+        }
+        let duty_cycles = || {
+            (0..2)
+                .map(|i| fs::read_to_string(chip.join(format!("pwm{}/duty_cycle", i))).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let channels = vec![(Controller(0), Channel(0)), (Controller(0), Channel(1))];
+
+        let motor = Motor::new(channels.clone(), Wiring::Direction).unwrap();
+        pwm.set_motor_speed(&motor, 0.5).unwrap();
+        assert_eq!(duty_cycles(), ["500", "1000"]);
+        pwm.set_motor_speed(&motor, -0.25).unwrap();
+        assert_eq!(duty_cycles(), ["250", "0"]);
+        assert_eq!(pwm.motor_speed(&motor).unwrap(), -0.25);
+
+        let motor = Motor::new(channels, Wiring::Dual).unwrap();
+        pwm.set_motor_speed(&motor, 0.5).unwrap();
+        assert_eq!(duty_cycles(), ["500", "0"]);
+        pwm.set_motor_speed(&motor, -0.75).unwrap();
+        assert_eq!(duty_cycles(), ["0", "750"]);
+        assert_eq!(pwm.motor_speed(&motor).unwrap(), -0.75);
+
+        assert!(matches!(
+            pwm.set_motor_speed(&motor, 1.5),
+            Err(PwmError::InvalidSpeed(_))
+        ));
+        assert!(Motor::new(vec![(Controller(0), Channel(0))], Wiring::Dual).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
     fs,
     path::{Path, PathBuf},
@@ -7,18 +8,24 @@ use std::{
 };
 
 use thiserror::Error;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
+
+use crate::limits::Limits;
 
 /// Everything that can go wrong.
 #[derive(Error, Debug)]
 pub enum PwmError {
     #[error("{0:?} not found")]
     ControllerNotFound(Controller),
+    #[error("no controller matches {0:?}")]
+    UnknownController(String),
+    #[error("several controllers match {0:?}")]
+    AmbiguousController(String),
     #[error("{0:?}/{1:?} not found")]
     ChannelNotFound(Controller, Channel),
-    #[error("{0:?} not exported")]
-    NotExported(Controller),
-    #[error("failed to {0:?}: {1}")]
+    #[error("{0:?}/{1:?} not exported")]
+    NotExported(Controller, Channel),
+    #[error("failed to {0}: {1}")]
     Sysfs(Access, #[source] std::io::Error),
     #[error("duty cycle value must not be greater than the period value")]
     DutyCycleGreaterThanPeriod,
@@ -30,6 +37,40 @@ pub enum PwmError {
     NotBoolean(String),
     #[error("expected a duration in nanoseconds, got {0:?}: {1}")]
     NotADuration(String, #[source] std::num::ParseIntError),
+    #[error("frequency must be greater than zero and at most 1 GHz, got {0} Hz")]
+    InvalidFrequency(f64),
+    #[error("duty ratio must be between 0 and 1, got {0}")]
+    InvalidDutyRatio(f64),
+    #[error("brightness must be between 0 and 1, got {0}")]
+    InvalidBrightness(f64),
+    #[error("gamma must be greater than zero, got {0}")]
+    InvalidGamma(f64),
+    #[error("{0:?}/{1:?} has no period set")]
+    PeriodNotSet(Controller, Channel),
+    #[error("invalid effect: {0}")]
+    InvalidEffect(&'static str),
+    #[error("invalid color: {0}")]
+    InvalidColor(&'static str),
+    #[error("invalid light: {0}")]
+    InvalidLight(&'static str),
+    #[error("angle must be between 0 and {1} degrees, got {0}")]
+    InvalidAngle(f64, f64),
+    #[error("invalid servo: {0}")]
+    InvalidServo(&'static str),
+    #[error("expected a thermal zone or hwmon temperature input, got {0:?}")]
+    InvalidSensor(String),
+    #[error("expected a temperature in millidegrees Celsius, got {0:?}")]
+    NotATemperature(String),
+    #[error("invalid fan curve: {0}")]
+    InvalidFanCurve(&'static str),
+    #[error("invalid RTTTL melody: {0}")]
+    InvalidMelody(String),
+    #[error("speed must be between -1 and 1, got {0}")]
+    InvalidSpeed(f64),
+    #[error("invalid motor: {0}")]
+    InvalidMotor(&'static str),
+    #[error("{0:?}/{1:?}: {2}")]
+    LimitExceeded(Controller, Channel, String),
 }
 
 /// Used in PwmError to format sysfs related errors.
@@ -39,6 +80,16 @@ pub enum Access {
     Write(PathBuf),
 }
 
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Access::*;
+        match self {
+            Read(path) => write!(f, "read {:?}", path),
+            Write(path) => write!(f, "write {:?}", path),
+        }
+    }
+}
+
 /// Exposes PWM functionality.
 ///
 /// Since the Linux kernel exposes PWM controllers and their settings through
@@ -51,6 +102,9 @@ pub enum Access {
 #[derive(Debug)]
 pub struct Pwm {
     sysfs_root: PathBuf,
+    /// The safety limits of the channels that have any, by controller and
+    /// channel number.
+    limits: HashMap<(u32, u32), Limits>,
 }
 
 /// A PWM controller (a.k.a. PWM chip) is identified by a non-negative number.
@@ -61,6 +115,54 @@ pub struct Controller(pub u32);
 #[derive(Debug, Clone)]
 pub struct Channel(pub u32);
 
+/// What is known about a PWM controller that is present in sysfs.
+#[derive(Debug, Clone)]
+pub struct ControllerInfo {
+    pub controller: Controller,
+    /// The number of channels.
+    pub npwm: u32,
+    /// The channels that are currently exported.
+    pub exported: Vec<Channel>,
+    /// The resolved target of the controller's `device` symlink, if any.
+    pub device: Option<PathBuf>,
+}
+
+/// The complete configuration of a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PwmState {
+    pub period: Duration,
+    pub duty_cycle: Duration,
+    pub polarity: Polarity,
+    pub enabled: bool,
+}
+
+/// Period and duty cycle of a channel, as actually written to sysfs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    pub period: Duration,
+    pub duty_cycle: Duration,
+}
+
+impl Timing {
+    /// The frequency that corresponds to the period.
+    pub fn frequency_hz(&self) -> f64 {
+        if self.period.is_zero() {
+            0.0
+        } else {
+            1e9 / self.period.as_nanos() as f64
+        }
+    }
+
+    /// The share of the period the signal is active, between 0 and 1.
+    pub fn duty_ratio(&self) -> f64 {
+        if self.period.is_zero() {
+            0.0
+        } else {
+            self.duty_cycle.as_nanos() as f64 / self.period.as_nanos() as f64
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, PwmError>;
 
 impl Pwm {
@@ -74,7 +176,106 @@ impl Pwm {
         if !sysfs_root.exists() {
             panic!("sysfs root does not exist: {:?}", sysfs_root);
         }
-        Self { sysfs_root }
+        Self {
+            sysfs_root,
+            limits: HashMap::new(),
+        }
+    }
+
+    /// Returns all controllers found in sysfs, ordered by their number.
+    #[instrument]
+    pub fn controllers(&self) -> Result<Vec<ControllerInfo>> {
+        self.controller_numbers()?
+            .into_iter()
+            .map(|controller| {
+                let npwm = self.npwm(&controller)?;
+                let exported = self.exported_channels(&controller)?;
+                let device = self.device(&controller)?;
+                Ok(ControllerInfo {
+                    controller,
+                    npwm,
+                    exported,
+                    device,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the resolved target of the controller's `device` symlink.
+    #[instrument]
+    pub fn device(&self, controller: &Controller) -> Result<Option<PathBuf>> {
+        self.controller_dir(controller)
+            .map(|dir| fs::canonicalize(dir.join("device")).ok())
+    }
+
+    /// Returns the resolved device-tree node of the controller's device, if
+    /// any.
+    #[instrument]
+    pub fn of_node(&self, controller: &Controller) -> Result<Option<PathBuf>> {
+        self.controller_dir(controller)
+            .map(|dir| fs::canonicalize(dir.join("device/of_node")).ok())
+    }
+
+    /// Returns the name of the driver bound to the controller's device, if
+    /// any.
+    #[instrument]
+    pub fn driver(&self, controller: &Controller) -> Result<Option<String>> {
+        self.controller_dir(controller).map(|dir| {
+            fs::canonicalize(dir.join("device/driver"))
+                .ok()
+                .and_then(|driver| Some(driver.file_name()?.to_str()?.to_owned()))
+        })
+    }
+
+    /// Finds a controller by an identifier that, unlike its number, doesn't
+    /// depend on the order in which the controllers were probed:
+    ///
+    /// - the resolved path of its device, e.g.,
+    ///   `/sys/devices/platform/soc/1c21400.pwm`, or just the device name,
+    ///   e.g., `1c21400.pwm`,
+    /// - its device-tree node, either the resolved path or the path within the
+    ///   device tree, e.g., `/soc/pwm@1c21400`, or
+    /// - the name of its driver, e.g., `sun4i-pwm`, as long as no other
+    ///   controller uses the same driver.
+    ///
+    /// Numbers, e.g., `0` or `pwmchip0`, are accepted as well.
+    #[instrument]
+    pub fn find_controller(&self, id: &str) -> Result<Controller> {
+        let number = id.strip_prefix("pwmchip").unwrap_or(id);
+        if let Ok(n) = number.parse() {
+            let controller = Controller(n);
+            self.controller_dir(&controller)?;
+            return Ok(controller);
+        }
+
+        let path = Path::new(id);
+        let resolved = fs::canonicalize(path).ok();
+        let is_path = |candidate: &Path| {
+            candidate == path
+                || resolved
+                    .as_deref()
+                    .is_some_and(|resolved| candidate == resolved)
+        };
+        let mut by_driver = vec![];
+        for controller in self.controller_numbers()? {
+            let device_matches = self.device(&controller)?.is_some_and(|device| {
+                is_path(&device) || device.file_name().is_some_and(|name| name == id)
+            });
+            let of_node_matches = self.of_node(&controller)?.is_some_and(|node| {
+                is_path(&node) || devicetree_path(&node).is_some_and(|node| node == id)
+            });
+            if device_matches || of_node_matches {
+                return Ok(controller);
+            }
+            if self.driver(&controller)?.is_some_and(|driver| driver == id) {
+                by_driver.push(controller);
+            }
+        }
+        match by_driver.len() {
+            0 => Err(PwmError::UnknownController(id.to_owned())),
+            1 => Ok(by_driver.remove(0)),
+            _ => Err(PwmError::AmbiguousController(id.to_owned())),
+        }
     }
 
     /// Returns the number of channels for the given controller.
@@ -89,31 +290,73 @@ impl Pwm {
             })
     }
 
-    /// Returns whether a controller's channels are ready to be used.
+    /// Returns whether a channel is exported, i.e., ready to be used.
     #[instrument]
-    pub fn is_exported(&self, controller: &Controller) -> Result<bool> {
-        // A controller is exported if the channel subdirectories are there.
-        // Since a controller without any channel doesn't make sense, it's
-        // enough to check for the existance of the first channel's enable file.
-        match self.channel_dir(controller, &Channel(0)) {
+    pub fn is_exported(&self, controller: &Controller, channel: &Channel) -> Result<bool> {
+        // A channel is exported if its subdirectory is there.
+        match self.channel_dir(controller, channel) {
             Ok(_) => Ok(true),
-            Err(PwmError::NotExported(_)) => Ok(false),
+            Err(PwmError::NotExported(..)) => Ok(false),
             Err(e) => Err(e),
         }
     }
 
-    /// Export a PWM controller, which enables access to its channels.
+    /// Returns the channels of a controller that are currently exported.
+    #[instrument]
+    pub fn exported_channels(&self, controller: &Controller) -> Result<Vec<Channel>> {
+        let mut exported = vec![];
+        for channel in (0..self.npwm(controller)?).map(Channel) {
+            if self.is_exported(controller, &channel)? {
+                exported.push(channel);
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Export a channel, which makes its settings available in sysfs.
     #[instrument]
-    pub fn export(&mut self, controller: Controller) -> Result<()> {
+    pub fn export(&mut self, controller: Controller, channel: Channel) -> Result<()> {
+        self.check_channel(&controller, &channel)?;
         self.controller_file(&controller, "export")
-            .and_then(|path| write(&path, "1"))
+            .and_then(|path| write(&path, &channel.0.to_string()))
     }
 
-    /// Unexport a PWM controller, which disables access to its channels.
+    /// Unexport a channel, which removes its settings from sysfs.
     #[instrument]
-    pub fn unexport(&mut self, controller: Controller) -> Result<()> {
+    pub fn unexport(&mut self, controller: Controller, channel: Channel) -> Result<()> {
+        self.check_channel(&controller, &channel)?;
+        if self
+            .limits(&controller, &channel)
+            .is_some_and(|limits| limits.read_only)
+        {
+            return Err(PwmError::LimitExceeded(
+                controller,
+                channel,
+                "channel is read-only".to_owned(),
+            ));
+        }
         self.controller_file(&controller, "unexport")
-            .and_then(|path| write(&path, "1"))
+            .and_then(|path| write(&path, &channel.0.to_string()))
+    }
+
+    /// Export all channels of a controller that are not exported yet.
+    #[instrument]
+    pub fn export_all(&mut self, controller: Controller) -> Result<()> {
+        for channel in (0..self.npwm(&controller)?).map(Channel) {
+            if !self.is_exported(&controller, &channel)? {
+                self.export(controller.clone(), channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unexport all channels of a controller that are currently exported.
+    #[instrument]
+    pub fn unexport_all(&mut self, controller: Controller) -> Result<()> {
+        for channel in self.exported_channels(&controller)? {
+            self.unexport(controller.clone(), channel)?;
+        }
+        Ok(())
     }
 
     /// Returns whether a controller's channel is enabled.
@@ -127,6 +370,7 @@ impl Pwm {
     /// Enable a channel.
     #[instrument]
     pub fn enable(&mut self, controller: Controller, channel: Channel) -> Result<()> {
+        self.check_limits(&controller, &channel, |state| state.enabled = true)?;
         self.channel_file(&controller, &channel, "enable")
             .and_then(|path| write(&path, "1"))
     }
@@ -134,10 +378,35 @@ impl Pwm {
     /// Disable a channel.
     #[instrument]
     pub fn disable(&mut self, controller: Controller, channel: Channel) -> Result<()> {
+        self.check_limits(&controller, &channel, |state| state.enabled = false)?;
         self.channel_file(&controller, &channel, "enable")
             .and_then(|path| write(&path, "0"))
     }
 
+    /// Returns the total period of the PWM signal.
+    #[instrument]
+    pub fn period(&self, controller: &Controller, channel: &Channel) -> Result<Duration> {
+        self.channel_file(controller, channel, "period")
+            .and_then(|path| read(&path))
+            .and_then(parse_duration)
+    }
+
+    /// Returns the active time of the PWM signal.
+    #[instrument]
+    pub fn duty_cycle(&self, controller: &Controller, channel: &Channel) -> Result<Duration> {
+        self.channel_file(controller, channel, "duty_cycle")
+            .and_then(|path| read(&path))
+            .and_then(parse_duration)
+    }
+
+    /// Returns the polarity of the PWM signal.
+    #[instrument]
+    pub fn polarity(&self, controller: &Controller, channel: &Channel) -> Result<Polarity> {
+        self.channel_file(controller, channel, "polarity")
+            .and_then(|path| read(&path))
+            .and_then(|s| s.trim_end().parse())
+    }
+
     /// The total period of the PWM signal (read/write). Value is in nanoseconds
     /// and is the sum of the active and inactive time of the PWM.
     #[instrument]
@@ -147,14 +416,12 @@ impl Pwm {
         channel: Channel,
         period: Duration,
     ) -> Result<()> {
-        let duty_cycle = self
-            .channel_file(&controller, &channel, "duty_cycle")
-            .and_then(|path| read(&path))
-            .and_then(parse_duration)?;
+        let duty_cycle = self.duty_cycle(&controller, &channel)?;
 
         if duty_cycle > period {
             return Err(PwmError::DutyCycleGreaterThanPeriod);
         }
+        self.check_limits(&controller, &channel, |state| state.period = period)?;
 
         self.channel_file(&controller, &channel, "period")
             .and_then(|path| write(&path, &period.as_nanos().to_string()))
@@ -169,14 +436,12 @@ impl Pwm {
         channel: Channel,
         duty_cycle: Duration,
     ) -> Result<()> {
-        let period = self
-            .channel_file(&controller, &channel, "period")
-            .and_then(|path| read(&path))
-            .and_then(parse_duration)?;
+        let period = self.period(&controller, &channel)?;
 
         if duty_cycle > period {
             return Err(PwmError::DutyCycleGreaterThanPeriod);
         }
+        self.check_limits(&controller, &channel, |state| state.duty_cycle = duty_cycle)?;
 
         self.channel_file(&controller, &channel, "duty_cycle")
             .and_then(|path| write(&path, &duty_cycle.as_nanos().to_string()))
@@ -197,11 +462,190 @@ impl Pwm {
         if self.is_enabled(&controller, &channel)? {
             return Err(PwmError::IllegalChangeWhileEnabled("polarity"));
         }
+        self.check_limits(&controller, &channel, |state| state.polarity = polarity)?;
 
         self.channel_file(&controller, &channel, "polarity")
             .and_then(|path| write(&path, &polarity.to_string()))
     }
 
+    /// Sets the period from a frequency, keeping the duty ratio. As sysfs
+    /// takes nanoseconds, both values are rounded; the returned timing tells
+    /// what has actually been written.
+    #[instrument]
+    pub fn set_frequency(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        frequency_hz: f64,
+    ) -> Result<Timing> {
+        let current = self.timing(&controller, &channel)?;
+        self.configure(controller, channel, frequency_hz, current.duty_ratio())
+    }
+
+    /// Sets the duty cycle as a share of the period, between 0 and 1. The
+    /// returned timing reflects the rounding to nanoseconds.
+    #[instrument]
+    pub fn set_duty_ratio(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        duty_ratio: f64,
+    ) -> Result<Timing> {
+        let period = self.period(&controller, &channel)?;
+        let timing = Timing {
+            duty_cycle: duty_cycle_from_ratio(period, duty_ratio)?,
+            period,
+        };
+        self.set_duty_cycle(controller, channel, timing.duty_cycle)?;
+        Ok(timing)
+    }
+
+    /// Sets frequency and duty ratio at once. The returned timing reflects the
+    /// rounding to nanoseconds.
+    #[instrument]
+    pub fn configure(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        frequency_hz: f64,
+        duty_ratio: f64,
+    ) -> Result<Timing> {
+        let period = period_from_frequency(frequency_hz)?;
+        let timing = Timing {
+            duty_cycle: duty_cycle_from_ratio(period, duty_ratio)?,
+            period,
+        };
+        let state = PwmState {
+            period: timing.period,
+            duty_cycle: timing.duty_cycle,
+            ..self.state(&controller, &channel)?
+        };
+        self.apply(controller, channel, state)?;
+        Ok(timing)
+    }
+
+    /// Returns period and duty cycle of a channel.
+    #[instrument]
+    pub fn timing(&self, controller: &Controller, channel: &Channel) -> Result<Timing> {
+        Ok(Timing {
+            period: self.period(controller, channel)?,
+            duty_cycle: self.duty_cycle(controller, channel)?,
+        })
+    }
+
+    /// Returns the complete configuration of a channel.
+    #[instrument]
+    pub fn state(&self, controller: &Controller, channel: &Channel) -> Result<PwmState> {
+        Ok(PwmState {
+            period: self.period(controller, channel)?,
+            duty_cycle: self.duty_cycle(controller, channel)?,
+            polarity: self.polarity(controller, channel)?,
+            enabled: self.is_enabled(controller, channel)?,
+        })
+    }
+
+    /// Changes all settings of a channel at once.
+    ///
+    /// The sysfs writes are ordered such that every intermediate state is
+    /// valid, i.e., the duty cycle never exceeds the period and the polarity
+    /// is only changed while the channel is disabled. If one of the writes
+    /// fails, the writes that succeeded already are undone.
+    #[instrument]
+    pub fn apply(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        state: PwmState,
+    ) -> Result<()> {
+        if state.duty_cycle > state.period {
+            return Err(PwmError::DutyCycleGreaterThanPeriod);
+        }
+        self.check_limits(&controller, &channel, |target| *target = state.clone())?;
+
+        let current = self.state(&controller, &channel)?;
+        let steps = plan(&current, &state);
+
+        for (i, (step, _)) in steps.iter().enumerate() {
+            if let Err(e) = self.write_step(&controller, &channel, step) {
+                for (_, undo) in steps[..i].iter().rev() {
+                    if let Err(e) = self.write_step(&controller, &channel, undo) {
+                        warn!("failed to roll back {:?}: {}", undo, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the safety limits of a channel, replacing the ones set before.
+    /// Writes that would break them fail with `PwmError::LimitExceeded`.
+    pub fn set_limits(&mut self, controller: &Controller, channel: &Channel, limits: Limits) {
+        self.limits.insert((controller.0, channel.0), limits);
+    }
+
+    /// Returns the safety limits of a channel, if it has any.
+    pub fn limits(&self, controller: &Controller, channel: &Channel) -> Option<&Limits> {
+        self.limits.get(&(controller.0, channel.0))
+    }
+
+    /// Fails if the channel has limits and `change` makes its settings break
+    /// them.
+    fn check_limits(
+        &self,
+        controller: &Controller,
+        channel: &Channel,
+        change: impl FnOnce(&mut PwmState),
+    ) -> Result<()> {
+        let limits = match self.limits(controller, channel) {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        let before = self.state(controller, channel)?;
+        let mut after = before.clone();
+        change(&mut after);
+        limits
+            .check(&before, &after)
+            .map_err(|e| PwmError::LimitExceeded(controller.clone(), channel.clone(), e))
+    }
+
+    fn write_step(
+        &mut self,
+        controller: &Controller,
+        channel: &Channel,
+        step: &Step,
+    ) -> Result<()> {
+        let (fname, value) = match step {
+            Step::Period(period) => ("period", period.as_nanos().to_string()),
+            Step::DutyCycle(duty_cycle) => ("duty_cycle", duty_cycle.as_nanos().to_string()),
+            Step::Polarity(polarity) => ("polarity", polarity.to_string()),
+            Step::Enabled(enabled) => ("enable", if *enabled { "1" } else { "0" }.to_owned()),
+        };
+        self.channel_file(controller, channel, fname)
+            .and_then(|path| write(&path, &value))
+    }
+
+    /// Returns the numbers of all controllers found in sysfs, in ascending
+    /// order.
+    fn controller_numbers(&self) -> Result<Vec<Controller>> {
+        let entries = fs::read_dir(&self.sysfs_root)
+            .map_err(|e| PwmError::Sysfs(Access::Read(self.sysfs_root.clone()), e))?;
+
+        let mut controllers: Vec<Controller> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("pwmchip"))
+                    .and_then(|n| n.parse().ok())
+            })
+            .map(Controller)
+            .collect();
+        controllers.sort_by_key(|controller| controller.0);
+        Ok(controllers)
+    }
+
     fn controller_dir(&self, controller: &Controller) -> Result<PathBuf> {
         let path = self.sysfs_root.join(format!("pwmchip{}", controller.0));
         if path.is_dir() {
@@ -222,14 +666,20 @@ impl Pwm {
         }
     }
 
-    fn channel_dir(&self, controller: &Controller, channel: &Channel) -> Result<PathBuf> {
+    fn check_channel(&self, controller: &Controller, channel: &Channel) -> Result<()> {
         let n_pwm = self.npwm(controller)?;
-        if channel.0 >= n_pwm {
-            return Err(PwmError::ChannelNotFound(
+        if channel.0 < n_pwm {
+            Ok(())
+        } else {
+            Err(PwmError::ChannelNotFound(
                 controller.clone(),
                 channel.clone(),
-            ));
+            ))
         }
+    }
+
+    fn channel_dir(&self, controller: &Controller, channel: &Channel) -> Result<PathBuf> {
+        self.check_channel(controller, channel)?;
 
         let path = self
             .controller_dir(controller)
@@ -237,7 +687,7 @@ impl Pwm {
         if path.is_dir() {
             Ok(path)
         } else {
-            Err(PwmError::NotExported(controller.clone()))
+            Err(PwmError::NotExported(controller.clone(), channel.clone()))
         }
     }
 
@@ -253,11 +703,84 @@ impl Pwm {
         if path.is_file() {
             Ok(path)
         } else {
-            Err(PwmError::NotExported(controller.clone()))
+            Err(PwmError::NotExported(controller.clone(), channel.clone()))
         }
     }
 }
 
+/// A single sysfs write performed by `Pwm::apply`.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Period(Duration),
+    DutyCycle(Duration),
+    Polarity(Polarity),
+    Enabled(bool),
+}
+
+/// Orders the writes needed to get from `current` to `target`, each paired
+/// with the write that undoes it.
+fn plan(current: &PwmState, target: &PwmState) -> Vec<(Step, Step)> {
+    let mut steps = vec![];
+    let mut enabled = current.enabled;
+
+    let polarity_changes = target.polarity != current.polarity;
+    if polarity_changes && enabled {
+        steps.push((Step::Enabled(false), Step::Enabled(true)));
+        enabled = false;
+    }
+
+    let period = (target.period != current.period)
+        .then_some((Step::Period(target.period), Step::Period(current.period)));
+    let duty_cycle = (target.duty_cycle != current.duty_cycle).then_some((
+        Step::DutyCycle(target.duty_cycle),
+        Step::DutyCycle(current.duty_cycle),
+    ));
+    // Shrinking the period below the current duty cycle is only possible
+    // after the duty cycle has been lowered.
+    if target.period >= current.duty_cycle {
+        steps.extend(period.into_iter().chain(duty_cycle));
+    } else {
+        steps.extend(duty_cycle.into_iter().chain(period));
+    }
+
+    if polarity_changes {
+        steps.push((
+            Step::Polarity(target.polarity),
+            Step::Polarity(current.polarity),
+        ));
+    }
+
+    if target.enabled != enabled {
+        steps.push((Step::Enabled(target.enabled), Step::Enabled(enabled)));
+    }
+
+    steps
+}
+
+/// Returns the path of a device-tree node within the device tree, e.g.,
+/// `/soc/pwm@1c21400` for `/sys/firmware/devicetree/base/soc/pwm@1c21400`.
+fn devicetree_path(node: &Path) -> Option<&str> {
+    node.to_str()?
+        .split_once("/devicetree/base")
+        .map(|(_, path)| path)
+}
+
+fn period_from_frequency(frequency_hz: f64) -> Result<Duration> {
+    if !(frequency_hz > 0.0 && frequency_hz <= 1e9) {
+        return Err(PwmError::InvalidFrequency(frequency_hz));
+    }
+    // Very low frequencies saturate at the longest period sysfs can take.
+    Ok(Duration::from_nanos((1e9 / frequency_hz).round() as u64))
+}
+
+fn duty_cycle_from_ratio(period: Duration, duty_ratio: f64) -> Result<Duration> {
+    if !(0.0..=1.0).contains(&duty_ratio) {
+        return Err(PwmError::InvalidDutyRatio(duty_ratio));
+    }
+    let duty_cycle = (period.as_nanos() as f64 * duty_ratio).round() as u64;
+    Ok(Duration::from_nanos(duty_cycle).min(period))
+}
+
 fn read(path: &Path) -> Result<String> {
     fs::read_to_string(path).map_err(|e| PwmError::Sysfs(Access::Read(path.to_owned()), e))
 }
@@ -283,7 +806,7 @@ fn parse_duration(s: String) -> Result<Duration> {
         .map(Duration::from_nanos)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Polarity {
     Normal,
     Inversed,
@@ -312,6 +835,39 @@ impl FromStr for Polarity {
     }
 }
 
+/// Fake sysfs directories for tests.
+#[cfg(test)]
+pub(crate) mod fake_sysfs {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    /// Fakes `pwmchipN` below `sysfs_root`, with `npwm` channels and the
+    /// `export` and `unexport` files. Returns its directory.
+    pub fn chip(sysfs_root: &Path, controller: u32, npwm: u32) -> PathBuf {
+        let chip = sysfs_root.join(format!("pwmchip{}", controller));
+        fs::create_dir_all(&chip).unwrap();
+        fs::write(chip.join("npwm"), npwm.to_string()).unwrap();
+        fs::write(chip.join("export"), "").unwrap();
+        fs::write(chip.join("unexport"), "").unwrap();
+        chip
+    }
+
+    /// Fakes an exported channel of a fake chip: disabled, with normal
+    /// polarity and the given period and duty cycle in nanoseconds. Returns
+    /// its directory.
+    pub fn channel(chip: &Path, channel: u32, period_ns: u64, duty_cycle_ns: u64) -> PathBuf {
+        let channel = chip.join(format!("pwm{}", channel));
+        fs::create_dir(&channel).unwrap();
+        fs::write(channel.join("period"), period_ns.to_string()).unwrap();
+        fs::write(channel.join("duty_cycle"), duty_cycle_ns.to_string()).unwrap();
+        fs::write(channel.join("polarity"), "normal").unwrap();
+        fs::write(channel.join("enable"), "0").unwrap();
+        channel
+    }
+}
+
 #[cfg(test)]
 mod should {
     use super::*;
@@ -323,33 +879,359 @@ mod should {
         let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
 
         assert!(matches!(
-            pwm.export(Controller(4)),
+            pwm.export(Controller(4), Channel(0)),
             Err(PwmError::ControllerNotFound(Controller(4)))
         ));
         assert!(matches!(
-            pwm.unexport(Controller(4)),
+            pwm.unexport(Controller(4), Channel(0)),
             Err(PwmError::ControllerNotFound(Controller(4)))
         ));
     }
 
     #[test]
-    fn export_and_unexport_a_controller() {
+    fn export_and_unexport_a_channel() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 4);
+        let export = chip.join("export");
+        let unexport = chip.join("unexport");
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        pwm.export(Controller(0), Channel(2)).unwrap();
+        assert_eq!(fs::read_to_string(&export).unwrap(), "2");
+
+        pwm.unexport(Controller(0), Channel(3)).unwrap();
+        assert_eq!(fs::read_to_string(&unexport).unwrap(), "3");
+
+        assert!(matches!(
+            pwm.export(Controller(0), Channel(4)),
+            Err(PwmError::ChannelNotFound(Controller(0), Channel(4)))
+        ));
+    }
+
+    #[test]
+    fn tell_exported_channels_apart() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 2);
+        fake_sysfs::channel(&chip, 1, 1000, 0);
+        let pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        assert!(!pwm.is_exported(&Controller(0), &Channel(0)).unwrap());
+        assert!(pwm.is_exported(&Controller(0), &Channel(1)).unwrap());
+    }
+
+    #[test]
+    fn only_export_channels_that_are_not_exported_yet() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 2);
+        fake_sysfs::channel(&chip, 1, 1000, 0);
+        let export = chip.join("export");
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        pwm.export_all(Controller(0)).unwrap();
+        assert_eq!(fs::read_to_string(&export).unwrap(), "0");
+    }
+
+    #[test]
+    fn read_back_channel_settings() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 250);
+        // sysfs terminates values with a newline:
+        fs::write(channel.join("period"), "1000\n").unwrap();
+        fs::write(channel.join("duty_cycle"), "250\n").unwrap();
+        fs::write(channel.join("polarity"), "inversed\n").unwrap();
+        let pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        assert_eq!(
+            pwm.period(&Controller(0), &Channel(0)).unwrap(),
+            Duration::from_nanos(1000)
+        );
+        assert_eq!(
+            pwm.duty_cycle(&Controller(0), &Channel(0)).unwrap(),
+            Duration::from_nanos(250)
+        );
+        assert_eq!(
+            pwm.polarity(&Controller(0), &Channel(0)).unwrap(),
+            Polarity::Inversed
+        );
+    }
+
+    #[test]
+    fn list_controllers() {
+        let tmp = TempDir::new().unwrap();
+        let device = tmp.child("devices/1c21400.pwm");
+        fs::create_dir_all(&device).unwrap();
+        let class = tmp.child("class");
+        for (n, npwm) in [(4, 1), (0, 2)] {
+            fake_sysfs::chip(&class, n, npwm);
+        }
+        fake_sysfs::channel(&class.join("pwmchip0"), 1, 1000, 0);
+        std::os::unix::fs::symlink(&device, class.join("pwmchip0/device")).unwrap();
+        fs::create_dir(class.join("not-a-pwmchip")).unwrap();
+        let pwm = Pwm::with_sysfs_root(class);
+
+        let controllers = pwm.controllers().unwrap();
+
+        assert_eq!(controllers.len(), 2);
+        assert!(matches!(controllers[0].controller, Controller(0)));
+        assert_eq!(controllers[0].npwm, 2);
+        assert!(matches!(controllers[0].exported[..], [Channel(1)]));
+        assert_eq!(
+            controllers[0].device,
+            Some(fs::canonicalize(&device).unwrap())
+        );
+        assert!(matches!(controllers[1].controller, Controller(4)));
+        assert_eq!(controllers[1].npwm, 1);
+        assert!(controllers[1].exported.is_empty());
+        assert_eq!(controllers[1].device, None);
+    }
+
+    #[test]
+    fn find_controllers_by_stable_identifiers() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new().unwrap();
+        let driver = tmp.child("bus/platform/drivers/sun4i-pwm");
+        fs::create_dir_all(&driver).unwrap();
+        let class = tmp.child("class");
+        // probed in reverse order, so the numbers don't match the addresses:
+        for (n, address) in [(1, "1c21400"), (0, "1c21800")] {
+            let device = tmp.child(format!("devices/platform/soc/{}.pwm", address));
+            let node = tmp.child(format!("firmware/devicetree/base/soc/pwm@{}", address));
+            fs::create_dir_all(&device).unwrap();
+            fs::create_dir_all(&node).unwrap();
+            symlink(&node, device.join("of_node")).unwrap();
+            symlink(&driver, device.join("driver")).unwrap();
+            let chip = fake_sysfs::chip(&class, n, 1);
+            symlink(&device, chip.join("device")).unwrap();
+        }
+        let pwm = Pwm::with_sysfs_root(class);
+        let find = |id: &str| pwm.find_controller(id).map(|controller| controller.0);
+
+        assert_eq!(find("1c21400.pwm").unwrap(), 1);
+        assert_eq!(find("1c21800.pwm").unwrap(), 0);
+        let device = tmp.child("devices/platform/soc/1c21400.pwm");
+        assert_eq!(find(device.to_str().unwrap()).unwrap(), 1);
+        assert_eq!(find("/soc/pwm@1c21800").unwrap(), 0);
+        let node = tmp.child("firmware/devicetree/base/soc/pwm@1c21400");
+        assert_eq!(find(node.to_str().unwrap()).unwrap(), 1);
+        assert_eq!(find("pwmchip1").unwrap(), 1);
+        assert_eq!(find("0").unwrap(), 0);
+
+        assert!(matches!(
+            find("sun4i-pwm"),
+            Err(PwmError::AmbiguousController(_))
+        ));
+        assert!(matches!(
+            find("1c22000.pwm"),
+            Err(PwmError::UnknownController(_))
+        ));
+        assert!(matches!(
+            find("pwmchip2"),
+            Err(PwmError::ControllerNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn order_writes_such_that_every_intermediate_state_is_valid() {
+        let current = PwmState {
+            period: Duration::from_nanos(100),
+            duty_cycle: Duration::from_nanos(70),
+            polarity: Polarity::Normal,
+            enabled: true,
+        };
+
+        let shrink = PwmState {
+            period: Duration::from_nanos(50),
+            duty_cycle: Duration::from_nanos(40),
+            ..current.clone()
+        };
+        let steps: Vec<Step> = plan(&current, &shrink)
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                Step::DutyCycle(Duration::from_nanos(40)),
+                Step::Period(Duration::from_nanos(50))
+            ]
+        );
+
+        let grow = PwmState {
+            period: Duration::from_nanos(200),
+            duty_cycle: Duration::from_nanos(150),
+            ..current.clone()
+        };
+        let steps: Vec<Step> = plan(&current, &grow).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(
+            steps,
+            [
+                Step::Period(Duration::from_nanos(200)),
+                Step::DutyCycle(Duration::from_nanos(150))
+            ]
+        );
+
+        let invert = PwmState {
+            polarity: Polarity::Inversed,
+            ..current.clone()
+        };
+        let steps: Vec<Step> = plan(&current, &invert)
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                Step::Enabled(false),
+                Step::Polarity(Polarity::Inversed),
+                Step::Enabled(true)
+            ]
+        );
+
+        assert!(plan(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn apply_a_channel_state() {
         let tmp = TempDir::new().unwrap();
-        let chip = tmp.child("pwmchip0");
-        fs::create_dir(&chip).unwrap();
-        let export = touch(chip.join("export"));
-        let unexport = touch(chip.join("unexport"));
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 100, 70);
+        fs::write(channel.join("enable"), "1").unwrap();
         let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
 
-        pwm.export(Controller(0)).unwrap();
-        assert_eq!(fs::read_to_string(&export).unwrap(), "1");
+        let state = PwmState {
+            period: Duration::from_nanos(50),
+            duty_cycle: Duration::from_nanos(40),
+            polarity: Polarity::Inversed,
+            enabled: true,
+        };
+        pwm.apply(Controller(0), Channel(0), state.clone()).unwrap();
+        assert_eq!(pwm.state(&Controller(0), &Channel(0)).unwrap(), state);
 
-        pwm.unexport(Controller(0)).unwrap();
-        assert_eq!(fs::read_to_string(&unexport).unwrap(), "1");
+        let invalid = PwmState {
+            duty_cycle: Duration::from_nanos(60),
+            ..state.clone()
+        };
+        assert!(matches!(
+            pwm.apply(Controller(0), Channel(0), invalid),
+            Err(PwmError::DutyCycleGreaterThanPeriod)
+        ));
+        assert_eq!(pwm.state(&Controller(0), &Channel(0)).unwrap(), state);
     }
 
-    fn touch(path: PathBuf) -> PathBuf {
-        fs::write(&path, b"").unwrap();
-        path
+    #[test]
+    fn roll_back_if_applying_a_channel_state_fails() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 100);
+        // A file that can be read but not written to, not even by root:
+        fs::remove_file(channel.join("period")).unwrap();
+        std::os::unix::fs::symlink("/proc/sys/kernel/random/poolsize", channel.join("period"))
+            .unwrap();
+        let period = fs::read_to_string(channel.join("period")).unwrap();
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        let state = PwmState {
+            period: Duration::from_nanos(50),
+            duty_cycle: Duration::from_nanos(40),
+            polarity: Polarity::Normal,
+            enabled: false,
+        };
+        assert!(matches!(
+            pwm.apply(Controller(0), Channel(0), state),
+            Err(PwmError::Sysfs(Access::Write(_), _))
+        ));
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "100"
+        );
+        assert_eq!(fs::read_to_string(channel.join("period")).unwrap(), period);
+    }
+
+    #[test]
+    fn keep_the_duty_ratio_when_changing_the_frequency() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000000, 400000);
+        fs::write(channel.join("enable"), "1").unwrap();
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        let timing = pwm
+            .set_frequency(Controller(0), Channel(0), 30_000.0)
+            .unwrap();
+        // 33333.33.. ns, rounded:
+        assert_eq!(timing.period, Duration::from_nanos(33333));
+        assert_eq!(timing.duty_cycle, Duration::from_nanos(13333));
+        assert_eq!(pwm.timing(&Controller(0), &Channel(0)).unwrap(), timing);
+
+        let timing = pwm.set_duty_ratio(Controller(0), Channel(0), 0.25).unwrap();
+        assert_eq!(timing.period, Duration::from_nanos(33333));
+        assert_eq!(timing.duty_cycle, Duration::from_nanos(8333));
+
+        let timing = pwm
+            .configure(Controller(0), Channel(0), 25_000.0, 0.4)
+            .unwrap();
+        assert_eq!(timing.period, Duration::from_nanos(40000));
+        assert_eq!(timing.duty_cycle, Duration::from_nanos(16000));
+        assert_eq!(timing.frequency_hz(), 25_000.0);
+        assert_eq!(timing.duty_ratio(), 0.4);
+
+        assert!(matches!(
+            pwm.set_frequency(Controller(0), Channel(0), 0.0),
+            Err(PwmError::InvalidFrequency(_))
+        ));
+        assert!(matches!(
+            pwm.set_duty_ratio(Controller(0), Channel(0), 1.5),
+            Err(PwmError::InvalidDutyRatio(_))
+        ));
+    }
+
+    #[test]
+    fn enforce_limits_on_writes() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel_dir = fake_sysfs::channel(&chip, 0, 1000, 0);
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let (controller, channel) = (Controller(0), Channel(0));
+        pwm.set_limits(
+            &controller,
+            &channel,
+            Limits {
+                max_duty_ratio: Some(0.5),
+                min_period: Some(Duration::from_nanos(500)),
+                max_period: None,
+                polarity: Some(Polarity::Normal),
+                read_only: false,
+            },
+        );
+
+        assert!(pwm
+            .set_duty_ratio(controller.clone(), channel.clone(), 0.5)
+            .is_ok());
+        assert!(matches!(
+            pwm.set_duty_ratio(controller.clone(), channel.clone(), 0.6),
+            Err(PwmError::LimitExceeded(..))
+        ));
+        assert!(matches!(
+            pwm.set_frequency(controller.clone(), channel.clone(), 1e7),
+            Err(PwmError::LimitExceeded(..))
+        ));
+        assert!(matches!(
+            pwm.set_polarity(controller.clone(), channel.clone(), Polarity::Inversed),
+            Err(PwmError::LimitExceeded(..))
+        ));
+        assert_eq!(
+            fs::read_to_string(channel_dir.join("duty_cycle")).unwrap(),
+            "500"
+        );
+        assert_eq!(
+            fs::read_to_string(channel_dir.join("period")).unwrap(),
+            "1000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel_dir.join("polarity")).unwrap(),
+            "normal"
+        );
     }
 }
@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use tracing::instrument;
+
+use crate::pwm::{Channel, Controller, Pwm, PwmError, PwmState};
+
+/// How a hobby servo maps angles onto pulse widths.
+///
+/// The servo turns to 0 degrees for the minimum pulse width and to `range`
+/// degrees for the maximum one, with the angles in between mapped linearly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Servo {
+    /// The period of the PWM signal, 20 ms for most servos.
+    pub period: Duration,
+    /// The pulse width at 0 degrees.
+    pub min_pulse: Duration,
+    /// The pulse width at `range` degrees.
+    pub max_pulse: Duration,
+    /// The angle between the minimum and maximum pulse width, in degrees.
+    pub range: f64,
+    /// Added to every angle, in degrees, to calibrate servos that are
+    /// mounted or centered slightly off.
+    pub offset: f64,
+}
+
+impl Default for Servo {
+    /// The timing most hobby servos use.
+    fn default() -> Self {
+        Self {
+            period: Duration::from_millis(20),
+            min_pulse: Duration::from_micros(1000),
+            max_pulse: Duration::from_micros(2000),
+            range: 180.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl Servo {
+    /// Checks that the settings make sense together.
+    pub fn check(&self) -> Result<(), PwmError> {
+        if self.min_pulse >= self.max_pulse {
+            return Err(PwmError::InvalidServo(
+                "the minimum pulse width must be less than the maximum",
+            ));
+        }
+        if self.max_pulse > self.period {
+            return Err(PwmError::InvalidServo(
+                "the maximum pulse width must not exceed the period",
+            ));
+        }
+        if !(self.range.is_finite() && self.range > 0.0) {
+            return Err(PwmError::InvalidServo(
+                "the range must be greater than zero",
+            ));
+        }
+        if !self.offset.is_finite() {
+            return Err(PwmError::InvalidServo("the offset must be finite"));
+        }
+        Ok(())
+    }
+
+    /// The pulse width that turns the servo to `angle`, including the
+    /// calibration offset.
+    pub fn pulse_width(&self, angle: f64) -> Result<Duration, PwmError> {
+        if !(0.0..=self.range).contains(&angle) {
+            return Err(PwmError::InvalidAngle(angle, self.range));
+        }
+        let calibrated = (angle + self.offset).clamp(0.0, self.range);
+        let span = (self.max_pulse - self.min_pulse).as_nanos() as f64;
+        let pulse = self.min_pulse.as_nanos() as f64 + span * calibrated / self.range;
+        Ok(Duration::from_nanos(pulse.round() as u64))
+    }
+
+    /// The angle the servo turns to for the given pulse width, i.e., the
+    /// inverse of `pulse_width`.
+    pub fn angle(&self, pulse_width: Duration) -> f64 {
+        let span = (self.max_pulse - self.min_pulse).as_nanos() as f64;
+        let pulse = pulse_width.as_nanos() as f64 - self.min_pulse.as_nanos() as f64;
+        pulse / span * self.range - self.offset
+    }
+}
+
+/// A move from one angle to another at a limited speed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub from: f64,
+    pub to: f64,
+    /// In degrees per second.
+    pub speed: f64,
+}
+
+impl Move {
+    pub fn new(from: f64, to: f64, speed: f64) -> Result<Self, PwmError> {
+        if speed.is_finite() && speed > 0.0 {
+            Ok(Self { from, to, speed })
+        } else {
+            Err(PwmError::InvalidServo(
+                "the speed must be greater than zero",
+            ))
+        }
+    }
+
+    /// How long the move takes.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64((self.to - self.from).abs() / self.speed)
+    }
+
+    /// The angle after `elapsed` time has passed since the move started.
+    pub fn angle_at(&self, elapsed: Duration) -> f64 {
+        let travelled = elapsed.as_secs_f64() * self.speed;
+        if travelled >= (self.to - self.from).abs() {
+            self.to
+        } else if self.to > self.from {
+            self.from + travelled
+        } else {
+            self.from - travelled
+        }
+    }
+}
+
+/// Treats a channel as a servo.
+impl Pwm {
+    /// Returns the angle a servo is turned to, in degrees.
+    #[instrument]
+    pub fn angle(
+        &self,
+        controller: &Controller,
+        channel: &Channel,
+        servo: &Servo,
+    ) -> Result<f64, PwmError> {
+        Ok(servo.angle(self.duty_cycle(controller, channel)?))
+    }
+
+    /// Turns a servo to `angle`, in degrees. Sets the servo's period and
+    /// enables the channel if needed.
+    #[instrument]
+    pub fn set_angle(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        servo: &Servo,
+        angle: f64,
+    ) -> Result<(), PwmError> {
+        let state = PwmState {
+            period: servo.period,
+            duty_cycle: servo.pulse_width(angle)?,
+            enabled: true,
+            ..self.state(&controller, &channel)?
+        };
+        self.apply(controller, channel, state)
+    }
+
+    /// Prepares a move of a servo from its current angle. A servo that isn't
+    /// driven yet has no known angle, so it is turned to `angle` right away
+    /// instead. The move itself is up to the caller.
+    #[instrument]
+    pub fn start_move(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        servo: &Servo,
+        angle: f64,
+        speed: f64,
+    ) -> Result<Move, PwmError> {
+        servo.pulse_width(angle)?;
+        let state = self.state(&controller, &channel)?;
+        let from = if state.enabled && state.period == servo.period {
+            servo.angle(state.duty_cycle).clamp(0.0, servo.range)
+        } else {
+            angle
+        };
+        let motion = Move::new(from, angle, speed)?;
+        self.set_angle(controller, channel, servo, from)?;
+        Ok(motion)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use std::fs;
+    use temp_dir::TempDir;
+
+    fn us(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    #[test]
+    fn map_angles_onto_pulse_widths() {
+        let servo = Servo::default();
+        assert_eq!(servo.pulse_width(0.0).unwrap(), us(1000));
+        assert_eq!(servo.pulse_width(90.0).unwrap(), us(1500));
+        assert_eq!(servo.pulse_width(180.0).unwrap(), us(2000));
+        assert_eq!(servo.angle(us(1500)), 90.0);
+        assert!(matches!(
+            servo.pulse_width(181.0),
+            Err(PwmError::InvalidAngle(..))
+        ));
+
+        let calibrated = Servo {
+            min_pulse: us(500),
+            max_pulse: us(2500),
+            offset: 9.0,
+            ..Servo::default()
+        };
+        assert_eq!(calibrated.pulse_width(90.0).unwrap(), us(1600));
+        assert_eq!(calibrated.pulse_width(180.0).unwrap(), us(2500));
+        assert!((calibrated.angle(us(1600)) - 90.0).abs() < 1e-9);
+
+        let invalid = Servo {
+            max_pulse: us(30_000),
+            ..Servo::default()
+        };
+        assert!(matches!(invalid.check(), Err(PwmError::InvalidServo(_))));
+    }
+
+    #[test]
+    fn move_at_the_given_speed() {
+        let motion = Move::new(90.0, 0.0, 45.0).unwrap();
+        assert_eq!(motion.duration(), Duration::from_secs(2));
+        assert_eq!(motion.angle_at(Duration::ZERO), 90.0);
+        assert_eq!(motion.angle_at(Duration::from_secs(1)), 45.0);
+        assert_eq!(motion.angle_at(Duration::from_secs(3)), 0.0);
+
+        assert!(Move::new(0.0, 90.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn set_the_period_and_enable_the_channel() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 500);
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+        let servo = Servo::default();
+
+        // Not driven yet, so there's nothing to move from:
+        let motion = pwm
+            .start_move(Controller(0), Channel(0), &servo, 45.0, 90.0)
+            .unwrap();
+        assert_eq!(motion.from, 45.0);
+        assert_eq!(
+            fs::read_to_string(channel.join("period")).unwrap(),
+            "20000000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "1250000"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+
+        let motion = pwm
+            .start_move(Controller(0), Channel(0), &servo, 135.0, 90.0)
+            .unwrap();
+        assert_eq!(motion.from, 45.0);
+        assert_eq!(motion.duration(), Duration::from_secs(1));
+
+        pwm.set_angle(Controller(0), Channel(0), &servo, 180.0)
+            .unwrap();
+        assert_eq!(
+            pwm.angle(&Controller(0), &Channel(0), &servo).unwrap(),
+            180.0
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use crate::pwm::{Channel, Controller, Polarity, Pwm, PwmError, PwmState};
+
+/// The settings of a channel as recorded in the state file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelRecord {
+    /// The controller, identified like the chip of a named channel, so it's
+    /// found again even if the controllers are numbered differently after a
+    /// reboot: by the resolved path of its device, or by its number if it
+    /// has no device.
+    pub chip: String,
+    pub channel: u32,
+    pub exported: bool,
+    /// The remaining settings are only meaningful if the channel is exported.
+    pub enabled: bool,
+    pub period_ns: u64,
+    pub duty_cycle_ns: u64,
+    pub polarity: String,
+}
+
+/// The settings of all channels, as written to the state file, e.g.,
+/// `/var/lib/pwmd/state.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub channels: Vec<ChannelRecord>,
+}
+
+impl Snapshot {
+    /// Reads the state file. Returns `None` if there is none yet.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {:?}", path)),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("invalid state file {:?}", path))
+    }
+
+    /// Replaces the state file. The file is written next to its final
+    /// location first, so a crash never leaves a truncated file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+        }
+        let tmp = path.with_extension("tmp");
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(&tmp, contents).with_context(|| format!("failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("failed to replace {:?}", path))
+    }
+}
+
+/// Records and restores the settings of all channels.
+impl Pwm {
+    /// Reads the settings of all channels of all controllers.
+    #[instrument]
+    pub fn snapshot(&self) -> Result<Snapshot, PwmError> {
+        let mut channels = vec![];
+        for info in self.controllers()? {
+            let chip = match &info.device {
+                Some(device) => device.to_string_lossy().into_owned(),
+                None => format!("pwmchip{}", info.controller.0),
+            };
+            for channel in (0..info.npwm).map(Channel) {
+                let exported = info.exported.iter().any(|exported| exported.0 == channel.0);
+                let state = if exported {
+                    self.state(&info.controller, &channel)?
+                } else {
+                    PwmState {
+                        period: Duration::ZERO,
+                        duty_cycle: Duration::ZERO,
+                        polarity: Polarity::Normal,
+                        enabled: false,
+                    }
+                };
+                channels.push(ChannelRecord {
+                    chip: chip.clone(),
+                    channel: channel.0,
+                    exported,
+                    enabled: state.enabled,
+                    period_ns: state.period.as_nanos() as u64,
+                    duty_cycle_ns: state.duty_cycle.as_nanos() as u64,
+                    polarity: state.polarity.to_string(),
+                });
+            }
+        }
+        Ok(Snapshot { channels })
+    }
+
+    /// Brings the channels back to the recorded settings, exporting and
+    /// unexporting them as needed. Channels that cannot be restored, e.g.,
+    /// because their controller is gone, are skipped.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for record in &snapshot.channels {
+            if let Err(e) = self.restore_channel(record) {
+                warn!(
+                    "failed to restore {}/pwm{}: {}",
+                    record.chip, record.channel, e
+                );
+            }
+        }
+    }
+
+    /// Disables the recorded channels that were exported and sets their duty
+    /// cycle to zero. Other channels are left alone.
+    pub fn reset(&mut self, snapshot: &Snapshot) {
+        for record in snapshot.channels.iter().filter(|record| record.exported) {
+            let res = self
+                .locate_record(record)
+                .and_then(|(controller, channel)| {
+                    let current = self.state(&controller, &channel)?;
+                    let state = PwmState {
+                        duty_cycle: Duration::ZERO,
+                        enabled: false,
+                        ..current
+                    };
+                    self.apply(controller, channel, state)
+                });
+            if let Err(e) = res {
+                warn!(
+                    "failed to reset {}/pwm{}: {}",
+                    record.chip, record.channel, e
+                );
+            }
+        }
+    }
+
+    /// Finds the recorded channel, which fails if its controller is gone.
+    fn locate_record(&self, record: &ChannelRecord) -> Result<(Controller, Channel), PwmError> {
+        let controller = self.find_controller(&record.chip)?;
+        Ok((controller, Channel(record.channel)))
+    }
+
+    #[instrument]
+    fn restore_channel(&mut self, record: &ChannelRecord) -> Result<(), PwmError> {
+        let (controller, channel) = self.locate_record(record)?;
+        let exported = self.is_exported(&controller, &channel)?;
+        if !record.exported {
+            if exported {
+                info!("unexporting {:?}/{:?}", controller, channel);
+                self.unexport(controller, channel)?;
+            }
+            return Ok(());
+        }
+        if !exported {
+            info!("exporting {:?}/{:?}", controller, channel);
+            self.export(controller.clone(), channel.clone())?;
+        }
+        let state = PwmState {
+            period: Duration::from_nanos(record.period_ns),
+            duty_cycle: Duration::from_nanos(record.duty_cycle_ns),
+            polarity: record.polarity.parse()?,
+            enabled: record.enabled,
+        };
+        let current = self.state(&controller, &channel)?;
+        if current != state {
+            info!(
+                "restoring {:?}/{:?}: {:?} -> {:?}",
+                controller, channel, current, state
+            );
+            self.apply(controller, channel, state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use temp_dir::TempDir;
+
+    /// An enabled, inversed channel 0 of a fake pwmchip0 with 2 channels.
+    fn fake_channel(tmp: &TempDir) -> std::path::PathBuf {
+        let chip = fake_sysfs::chip(&tmp.child("pwm"), 0, 2);
+        let channel = fake_sysfs::channel(&chip, 0, 1000, 250);
+        fs::write(channel.join("polarity"), "inversed").unwrap();
+        fs::write(channel.join("enable"), "1").unwrap();
+        channel
+    }
+
+    #[test]
+    fn record_and_restore_channels() {
+        let tmp = TempDir::new().unwrap();
+        let channel = fake_channel(&tmp);
+        let mut pwm = Pwm::with_sysfs_root(tmp.child("pwm"));
+        let state_file = tmp.child("lib/state.json");
+
+        let snapshot = pwm.snapshot().unwrap();
+        assert_eq!(
+            snapshot.channels,
+            [
+                ChannelRecord {
+                    chip: "pwmchip0".to_owned(),
+                    channel: 0,
+                    exported: true,
+                    enabled: true,
+                    period_ns: 1000,
+                    duty_cycle_ns: 250,
+                    polarity: "inversed".to_owned(),
+                },
+                ChannelRecord {
+                    chip: "pwmchip0".to_owned(),
+                    channel: 1,
+                    exported: false,
+                    enabled: false,
+                    period_ns: 0,
+                    duty_cycle_ns: 0,
+                    polarity: "normal".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(Snapshot::load(&state_file).unwrap(), None);
+        snapshot.save(&state_file).unwrap();
+        assert_eq!(Snapshot::load(&state_file).unwrap(), Some(snapshot.clone()));
+
+        fs::write(channel.join("duty_cycle"), "0").unwrap();
+        fs::write(channel.join("enable"), "0").unwrap();
+        pwm.restore(&snapshot);
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "250"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+    }
+
+    #[test]
+    fn find_recorded_controllers_by_device() {
+        let tmp = TempDir::new().unwrap();
+        let channel = fake_channel(&tmp);
+        let device = tmp.child("devices/platform/soc/1c21400.pwm");
+        fs::create_dir_all(&device).unwrap();
+        std::os::unix::fs::symlink(&device, tmp.child("pwm/pwmchip0/device")).unwrap();
+        let mut pwm = Pwm::with_sysfs_root(tmp.child("pwm"));
+
+        let mut snapshot = pwm.snapshot().unwrap();
+        assert_eq!(
+            snapshot.channels[0].chip,
+            fs::canonicalize(&device).unwrap().to_string_lossy()
+        );
+
+        // the controller is numbered differently after a reboot:
+        fs::rename(tmp.child("pwm/pwmchip0"), tmp.child("pwm/pwmchip1")).unwrap();
+        let channel = tmp.child("pwm/pwmchip1").join(channel.file_name().unwrap());
+        fs::write(channel.join("duty_cycle"), "0").unwrap();
+        // ...and records of controllers that are gone are skipped:
+        snapshot.channels.insert(
+            0,
+            ChannelRecord {
+                chip: "/sys/devices/platform/soc/gone.pwm".to_owned(),
+                ..snapshot.channels[0].clone()
+            },
+        );
+        pwm.restore(&snapshot);
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "250"
+        );
+    }
+
+    #[test]
+    fn reset_recorded_channels() {
+        let tmp = TempDir::new().unwrap();
+        let channel = fake_channel(&tmp);
+        let mut pwm = Pwm::with_sysfs_root(tmp.child("pwm"));
+
+        let snapshot = pwm.snapshot().unwrap();
+        pwm.reset(&snapshot);
+        assert_eq!(fs::read_to_string(channel.join("duty_cycle")).unwrap(), "0");
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "0");
+        assert_eq!(fs::read_to_string(channel.join("period")).unwrap(), "1000");
+    }
+}
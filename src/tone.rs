@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use tracing::instrument;
+
+use crate::pwm::{Channel, Controller, Pwm, PwmError};
+
+/// A tone of a melody, or a pause if it has no frequency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub frequency: Option<f64>,
+    pub duration: Duration,
+}
+
+/// A sequence of notes, e.g., parsed from RTTTL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Melody {
+    pub name: String,
+    pub notes: Vec<Note>,
+}
+
+impl Melody {
+    /// A melody made up of a single tone.
+    pub fn tone(frequency: f64, duration: Duration) -> Self {
+        Self {
+            name: String::new(),
+            notes: vec![Note {
+                frequency: Some(frequency),
+                duration,
+            }],
+        }
+    }
+
+    /// Parses a melody in the Ring Tone Text Transfer Language, e.g.,
+    /// `tetris:d=4,o=5,b=160:e6,8b,8c6,8d6,16e6,16d6,8c6,8b`.
+    ///
+    /// The name is followed by the defaults for duration, octave and beats
+    /// per minute and the comma-separated notes, each made up of an optional
+    /// duration, the note (`p` for a pause), an optional `#`, an optional
+    /// octave and an optional `.` that makes the note 1.5 times as long.
+    pub fn from_rtttl(rtttl: &str) -> Result<Self, PwmError> {
+        let invalid = |reason: &str| PwmError::InvalidMelody(reason.to_owned());
+        let mut sections = rtttl.trim().splitn(3, ':');
+        let (name, defaults, notes) = match (sections.next(), sections.next(), sections.next()) {
+            (Some(name), Some(defaults), Some(notes)) => (name, defaults, notes),
+            _ => {
+                return Err(invalid(
+                    "expected name, defaults and notes separated by colons",
+                ))
+            }
+        };
+
+        let (mut duration, mut octave, mut bpm) = (4, 6, 63);
+        for default in defaults.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let value = |value: &str| {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| invalid(&format!("invalid default {:?}", default)))
+            };
+            match default.split_once('=') {
+                Some(("d", d)) => duration = check_duration(value(d)?)?,
+                Some(("o", o)) => octave = check_octave(value(o)?)?,
+                Some(("b", b)) if value(b)? > 0 => bpm = value(b)?,
+                _ => return Err(invalid(&format!("invalid default {:?}", default))),
+            }
+        }
+
+        // The beat is a quarter note.
+        let whole = Duration::from_secs_f64(4.0 * 60.0 / f64::from(bpm));
+        let notes = notes
+            .split(',')
+            .map(|note| parse_note(note.trim(), whole, duration, octave))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name: name.trim().to_owned(),
+            notes,
+        })
+    }
+}
+
+fn check_duration(duration: u32) -> Result<u32, PwmError> {
+    if [1, 2, 4, 8, 16, 32].contains(&duration) {
+        Ok(duration)
+    } else {
+        Err(PwmError::InvalidMelody(format!(
+            "invalid duration {}",
+            duration
+        )))
+    }
+}
+
+fn check_octave(octave: u32) -> Result<u32, PwmError> {
+    if (4..=7).contains(&octave) {
+        Ok(octave)
+    } else {
+        Err(PwmError::InvalidMelody(format!(
+            "invalid octave {}",
+            octave
+        )))
+    }
+}
+
+fn parse_note(
+    note: &str,
+    whole: Duration,
+    default_duration: u32,
+    default_octave: u32,
+) -> Result<Note, PwmError> {
+    let invalid = || PwmError::InvalidMelody(format!("invalid note {:?}", note));
+    let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+    let n = digits(note);
+    let duration = match n {
+        0 => default_duration,
+        _ => check_duration(note[..n].parse().map_err(|_| invalid())?)?,
+    };
+    let mut rest = note[n..].to_ascii_lowercase();
+
+    let mut dotted = false;
+    if rest.ends_with('.') {
+        dotted = true;
+        rest.pop();
+    }
+    let mut chars = rest.chars();
+    // Semitones above C.
+    let semitone = match chars.next().ok_or_else(invalid)? {
+        'p' => None,
+        'c' => Some(0),
+        'd' => Some(2),
+        'e' => Some(4),
+        'f' => Some(5),
+        'g' => Some(7),
+        'a' => Some(9),
+        'b' | 'h' => Some(11),
+        _ => return Err(invalid()),
+    };
+    let mut rest = chars.as_str();
+    let sharp = rest.starts_with('#');
+    if sharp {
+        rest = &rest[1..];
+    }
+    if rest.starts_with('.') {
+        // The dot may also precede the octave.
+        dotted = true;
+        rest = &rest[1..];
+    }
+    let octave = match rest {
+        "" => default_octave,
+        _ if digits(rest) == rest.len() => check_octave(rest.parse().map_err(|_| invalid())?)?,
+        _ => return Err(invalid()),
+    };
+
+    let mut duration = whole / duration;
+    if dotted {
+        duration = duration * 3 / 2;
+    }
+    let frequency = semitone.map(|semitone| {
+        // MIDI numbering: A4 is 69 at 440 Hz.
+        let key = (octave + 1) * 12 + semitone + u32::from(sharp);
+        440.0 * 2f64.powf((f64::from(key) - 69.0) / 12.0)
+    });
+    Ok(Note {
+        frequency,
+        duration,
+    })
+}
+
+/// Treats a channel as a buzzer.
+impl Pwm {
+    /// Lets a buzzer sound at the given frequency, at 50% duty cycle, or
+    /// silences it if there is no frequency.
+    #[instrument]
+    pub fn set_tone(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        frequency: Option<f64>,
+    ) -> Result<(), PwmError> {
+        match frequency {
+            Some(frequency) => {
+                self.configure(controller.clone(), channel.clone(), frequency, 0.5)?;
+                if !self.is_enabled(&controller, &channel)? {
+                    self.enable(controller, channel)?;
+                }
+                Ok(())
+            }
+            None => self.disable(controller, channel),
+        }
+    }
+
+    /// Plays the first note of a melody. Playing the rest is up to the
+    /// caller.
+    #[instrument]
+    pub fn start_melody(
+        &mut self,
+        controller: Controller,
+        channel: Channel,
+        melody: Melody,
+    ) -> Result<Melody, PwmError> {
+        let first = melody.notes.first().and_then(|note| note.frequency);
+        self.set_tone(controller, channel, first)?;
+        Ok(melody)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::pwm::fake_sysfs;
+    use std::fs;
+    use temp_dir::TempDir;
+
+    fn assert_note(note: &Note, frequency: Option<f64>, duration_ms: u64) {
+        match (note.frequency, frequency) {
+            (Some(actual), Some(expected)) => assert!(
+                (actual - expected).abs() < 0.01,
+                "expected {} Hz, got {:?}",
+                expected,
+                note
+            ),
+            (actual, expected) => assert_eq!(actual, expected),
+        }
+        let actual_ms = note.duration.as_secs_f64() * 1000.0;
+        assert!(
+            (actual_ms - duration_ms as f64).abs() < 1.0,
+            "expected {} ms, got {:?}",
+            duration_ms,
+            note
+        );
+    }
+
+    #[test]
+    fn parse_rtttl() {
+        let melody = Melody::from_rtttl("Test:d=4,o=5,b=120:8a,p,c6.,2g#4,16h.7").unwrap();
+        assert_eq!(melody.name, "Test");
+        assert_note(&melody.notes[0], Some(880.0), 250);
+        assert_note(&melody.notes[1], None, 500);
+        assert_note(&melody.notes[2], Some(1046.50), 750);
+        assert_note(&melody.notes[3], Some(415.30), 1000);
+        assert_note(&melody.notes[4], Some(3951.07), 187);
+
+        // defaults are optional:
+        let melody = Melody::from_rtttl("x::a").unwrap();
+        assert_note(&melody.notes[0], Some(1760.0), 952);
+
+        for invalid in [
+            "no colons",
+            "x:d=3:a",
+            "x:o=5,z=1:a",
+            "x:b=0:a",
+            "x::x",
+            "x::a9",
+            "x::a,,b",
+        ] {
+            assert!(
+                matches!(Melody::from_rtttl(invalid), Err(PwmError::InvalidMelody(_))),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn sound_at_half_duty_cycle() {
+        let tmp = TempDir::new().unwrap();
+        let chip = fake_sysfs::chip(tmp.path(), 0, 1);
+        let channel = fake_sysfs::channel(&chip, 0, 0, 0);
+        let mut pwm = Pwm::with_sysfs_root(tmp.path().to_owned());
+
+        pwm.set_tone(Controller(0), Channel(0), Some(1000.0))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(channel.join("period")).unwrap(),
+            "1000000"
+        );
+        assert_eq!(
+            fs::read_to_string(channel.join("duty_cycle")).unwrap(),
+            "500000"
+        );
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "1");
+
+        pwm.set_tone(Controller(0), Channel(0), None).unwrap();
+        assert_eq!(fs::read_to_string(channel.join("enable")).unwrap(), "0");
+    }
+}
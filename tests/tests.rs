@@ -1,15 +1,31 @@
 use std::{
-    convert::TryInto,
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
     fs,
     path::{Path, PathBuf},
-    sync::mpsc::channel,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
     time::Duration,
 };
 
-use pwmd::{args::Bus, Args};
+use pwmd::{
+    args::{Authority, Bus, Startup},
+    Args,
+};
 use rand::Rng;
 use temp_dir::TempDir;
-use zbus::{blocking::Connection, names::BusName};
+use zbus::{
+    blocking::{Connection, MessageStream},
+    names::BusName,
+    zvariant::{OwnedObjectPath, OwnedValue, Value},
+    Message, MessageType,
+};
+
+/// Result of `org.freedesktop.DBus.ObjectManager.GetManagedObjects`.
+type ManagedObjects = HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>>;
 
 #[test]
 fn happy_flow() -> anyhow::Result<()> {
@@ -27,7 +43,15 @@ fn happy_flow() -> anyhow::Result<()> {
         let args = Args {
             bus: Bus::Session,
             dbus_service_name: dbus_service_name2,
+            config: None,
+            state_file: None,
+            startup: Startup::Leave,
+            authority: Authority::Allow,
+            polkit_service_name: "org.freedesktop.PolicyKit1".to_owned(),
             sysfs_root,
+            sensor_root: None,
+            poll_interval_ms: 1000,
+            fan_interval_ms: 1000,
         };
         tokio::runtime::Runtime::new().unwrap().block_on(async {
             pwmd::dbus::listen(args, || tx.send(()).unwrap())
@@ -48,11 +72,9 @@ fn happy_flow() -> anyhow::Result<()> {
     //
 
     // fake controller pwmchip0:
-    let chip_dir = tmpdir.child("pwmchip0");
-    fs::create_dir(&chip_dir).unwrap();
-    let _npwm_file = write(chip_dir.join("npwm"), "1");
-    let export_file = touch(chip_dir.join("export"));
-    let unexport_file = touch(chip_dir.join("unexport"));
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let export_file = chip_dir.join("export");
+    let unexport_file = chip_dir.join("unexport");
 
     // channel count is 1:
     assert_eq!(
@@ -69,15 +91,14 @@ fn happy_flow() -> anyhow::Result<()> {
 
     // export the chip:
     let _ = connection.call_method(destination, path, iface, "Export", &(0u32,))?;
-    check_file(&export_file, "1");
+    check_file(&export_file, "0");
 
     // fake channel pwm0:
-    let channel_dir = chip_dir.join("pwm0");
-    fs::create_dir(&channel_dir).unwrap();
-    let enable_file = touch(channel_dir.join("enable"));
-    let period_file = write(channel_dir.join("period"), "100");
-    let duty_cycle_file = write(channel_dir.join("duty_cycle"), "70");
-    let polarity_file = write(channel_dir.join("polarity"), "normal");
+    let channel_dir = fake_channel(&chip_dir, 0, 100, 70);
+    let enable_file = channel_dir.join("enable");
+    let period_file = channel_dir.join("period");
+    let duty_cycle_file = channel_dir.join("duty_cycle");
+    let polarity_file = channel_dir.join("polarity");
 
     // should be exported afterwards:
     assert!(connection
@@ -96,6 +117,12 @@ fn happy_flow() -> anyhow::Result<()> {
         &(0u32, 0u32, 1000u64),
     )?;
     check_file(&period_file, "1000");
+    assert_eq!(
+        1000,
+        connection
+            .call_method(destination, path, iface, "GetPeriodNs", &(0u32, 0u32))
+            .and_then(|res| res.body::<u64>())?
+    );
 
     let _ = connection.call_method(
         destination,
@@ -105,6 +132,12 @@ fn happy_flow() -> anyhow::Result<()> {
         &(0u32, 0u32, 700u64),
     )?;
     check_file(&duty_cycle_file, "700");
+    assert_eq!(
+        700,
+        connection
+            .call_method(destination, path, iface, "GetDutyCycleNs", &(0u32, 0u32))
+            .and_then(|res| res.body::<u64>())?
+    );
 
     let _ = connection.call_method(
         destination,
@@ -114,6 +147,12 @@ fn happy_flow() -> anyhow::Result<()> {
         &(0u32, 0u32, "inversed"),
     )?;
     check_file(&polarity_file, "inversed");
+    assert_eq!(
+        "inversed",
+        connection
+            .call_method(destination, path, iface, "GetPolarity", &(0u32, 0u32))
+            .and_then(|res| res.body::<String>())?
+    );
 
     //
     // Enable pwmchip0/pwm0:
@@ -142,7 +181,7 @@ fn happy_flow() -> anyhow::Result<()> {
     //
 
     let _ = connection.call_method(destination, path, iface, "Unexport", &(0u32,))?;
-    check_file(&unexport_file, "1");
+    check_file(&unexport_file, "0");
 
     let _ = connection.call_method(destination, path, iface, "Quit", &())?;
 
@@ -163,7 +202,15 @@ fn test_duty_cycle_cannot_be_larger_than_period() -> anyhow::Result<()> {
         let args = Args {
             bus: Bus::Session,
             dbus_service_name: dbus_service_name2,
+            config: None,
+            state_file: None,
+            startup: Startup::Leave,
+            authority: Authority::Allow,
+            polkit_service_name: "org.freedesktop.PolicyKit1".to_owned(),
             sysfs_root,
+            sensor_root: None,
+            poll_interval_ms: 1000,
+            fan_interval_ms: 1000,
         };
         tokio::runtime::Runtime::new().unwrap().block_on(async {
             pwmd::dbus::listen(args, || tx.send(()).unwrap())
@@ -180,20 +227,15 @@ fn test_duty_cycle_cannot_be_larger_than_period() -> anyhow::Result<()> {
     let iface = Some("com.kevinbader.pwmd.pwm1");
 
     // fake controller pwmchip0:
-    let chip_dir = tmpdir.child("pwmchip0");
-    fs::create_dir(&chip_dir).unwrap();
-    let _ = write(chip_dir.join("npwm"), "1");
-    let _ = touch(chip_dir.join("export"));
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
 
     // export the chip:
     let _ = connection.call_method(destination, path, iface, "Export", &(0u32,))?;
 
     // fake channel pwm0:
-    let channel_dir = chip_dir.join("pwm0");
-    fs::create_dir(&channel_dir).unwrap();
-    let _ = touch(channel_dir.join("enable"));
-    let period_file = write(channel_dir.join("period"), "100");
-    let duty_cycle_file = write(channel_dir.join("duty_cycle"), "70");
+    let channel_dir = fake_channel(&chip_dir, 0, 100, 70);
+    let period_file = channel_dir.join("period");
+    let duty_cycle_file = channel_dir.join("duty_cycle");
 
     // setting duty_cycle to a value greater than the period should fail:
     let error = match connection.call_method(
@@ -203,7 +245,13 @@ fn test_duty_cycle_cannot_be_larger_than_period() -> anyhow::Result<()> {
         "SetDutyCycleNs",
         &(0u32, 0u32, 101u64),
     ) {
-        Err(zbus::Error::MethodError(_, Some(error), _)) => error.to_lowercase(),
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(
+                name.as_str(),
+                "com.kevinbader.pwmd.Error.DutyCycleGreaterThanPeriod"
+            );
+            error.to_lowercase()
+        }
         x => panic!("expected MethodError, got: {:?}", x),
     };
     assert!(
@@ -223,7 +271,13 @@ fn test_duty_cycle_cannot_be_larger_than_period() -> anyhow::Result<()> {
         "SetPeriodNs",
         &(0u32, 0u32, 69u64),
     ) {
-        Err(zbus::Error::MethodError(_, Some(error), _)) => error.to_lowercase(),
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(
+                name.as_str(),
+                "com.kevinbader.pwmd.Error.DutyCycleGreaterThanPeriod"
+            );
+            error.to_lowercase()
+        }
         x => panic!("expected MethodError, got: {:?}", x),
     };
     assert!(
@@ -253,7 +307,15 @@ fn test_polarity_cannot_be_changed_if_channel_is_enabled() -> anyhow::Result<()>
         let args = Args {
             bus: Bus::Session,
             dbus_service_name: dbus_service_name2,
+            config: None,
+            state_file: None,
+            startup: Startup::Leave,
+            authority: Authority::Allow,
+            polkit_service_name: "org.freedesktop.PolicyKit1".to_owned(),
             sysfs_root,
+            sensor_root: None,
+            poll_interval_ms: 1000,
+            fan_interval_ms: 1000,
         };
         tokio::runtime::Runtime::new().unwrap().block_on(async {
             pwmd::dbus::listen(args, || tx.send(()).unwrap())
@@ -270,19 +332,14 @@ fn test_polarity_cannot_be_changed_if_channel_is_enabled() -> anyhow::Result<()>
     let iface = Some("com.kevinbader.pwmd.pwm1");
 
     // fake controller pwmchip0:
-    let chip_dir = tmpdir.child("pwmchip0");
-    fs::create_dir(&chip_dir).unwrap();
-    let _ = write(chip_dir.join("npwm"), "1");
-    let _ = touch(chip_dir.join("export"));
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
 
     // export the chip:
     let _ = connection.call_method(destination, path, iface, "Export", &(0u32,))?;
 
     // fake channel pwm0:
-    let channel_dir = chip_dir.join("pwm0");
-    fs::create_dir(&channel_dir).unwrap();
-    let _ = touch(channel_dir.join("enable"));
-    let polarity_file = write(channel_dir.join("polarity"), "normal");
+    let channel_dir = fake_channel(&chip_dir, 0, 0, 0);
+    let polarity_file = channel_dir.join("polarity");
 
     // enable pwmchip0/pwm0:
     let _ = connection.call_method(destination, path, iface, "Enable", &(0u32, 0u32))?;
@@ -311,6 +368,2253 @@ fn test_polarity_cannot_be_changed_if_channel_is_enabled() -> anyhow::Result<()>
     Ok(())
 }
 
+#[test]
+fn test_export_and_unexport_individual_channels() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory:
+    let tmpdir = TempDir::new().unwrap();
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwm1";
+    let iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // fake controller pwmchip0 with 4 channels, of which pwm1 is exported already:
+    let chip_dir = fake_chip(tmpdir.path(), 0, 4);
+    let export_file = chip_dir.join("export");
+    let unexport_file = chip_dir.join("unexport");
+    fs::create_dir(chip_dir.join("pwm1")).unwrap();
+
+    let is_channel_exported = |channel: u32| -> anyhow::Result<bool> {
+        Ok(connection
+            .call_method(
+                destination,
+                path,
+                iface,
+                "IsChannelExported",
+                &(0u32, channel),
+            )
+            .and_then(|res| res.body::<bool>())?)
+    };
+    assert!(!is_channel_exported(0)?);
+    assert!(is_channel_exported(1)?);
+    assert!(!connection
+        .call_method(destination, path, iface, "IsExported", &(0u32,))
+        .and_then(|res| res.body::<bool>())?);
+
+    // the channel index is written to the export file:
+    let _ = connection.call_method(destination, path, iface, "ExportChannel", &(0u32, 2u32))?;
+    check_file(&export_file, "2");
+
+    // ...and to the unexport file:
+    let _ = connection.call_method(destination, path, iface, "UnexportChannel", &(0u32, 1u32))?;
+    check_file(&unexport_file, "1");
+
+    // channels that don't exist cannot be exported:
+    assert!(connection
+        .call_method(destination, path, iface, "ExportChannel", &(0u32, 4u32))
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(destination, path, iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_list_controllers() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory:
+    let tmpdir = TempDir::new().unwrap();
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwm1";
+    let iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // fake controllers pwmchip0 (2 channels, pwm1 exported) and pwmchip2 (1 channel):
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    fs::create_dir(chip_dir.join("pwm1")).unwrap();
+    fake_chip(tmpdir.path(), 2, 1);
+
+    let controllers = connection
+        .call_method(destination, path, iface, "ListControllers", &())
+        .and_then(|res| res.body::<Vec<(u32, u32, Vec<u32>, String)>>())?;
+    assert_eq!(
+        controllers,
+        vec![
+            (0, 2, vec![1], String::new()),
+            (2, 1, vec![], String::new())
+        ]
+    );
+
+    // quit:
+    let _ = connection.call_method(destination, path, iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_objects_per_controller_and_channel() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0, which has 2 channels and
+    // pwm1 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let channel_dir = fake_channel(&chip_dir, 1, 100, 70);
+    let enable_file = channel_dir.join("enable");
+    let duty_cycle_file = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+
+    // the object manager knows about the controller and the exported channel:
+    let objects = connection
+        .call_method(
+            destination,
+            "/com/kevinbader/pwmd",
+            Some("org.freedesktop.DBus.ObjectManager"),
+            "GetManagedObjects",
+            &(),
+        )
+        .and_then(|res| res.body::<ManagedObjects>())?;
+    let mut paths: Vec<&str> = objects.keys().map(|path| path.as_str()).collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        vec![
+            "/com/kevinbader/pwmd/pwm1",
+            "/com/kevinbader/pwmd/pwmchip0",
+            "/com/kevinbader/pwmd/pwmchip0/pwm1",
+        ]
+    );
+    let channel1 =
+        &objects[&"/com/kevinbader/pwmd/pwmchip0/pwm1".try_into()?]["com.kevinbader.pwmd.Channel1"];
+    assert_eq!(u64::try_from(channel1["Period"].clone())?, 100);
+    assert_eq!(u64::try_from(channel1["DutyCycle"].clone())?, 70);
+    assert_eq!(String::try_from(channel1["Polarity"].clone())?, "normal");
+    assert!(!bool::try_from(channel1["Enabled"].clone())?);
+
+    // properties can be read...
+    let path = "/com/kevinbader/pwmd/pwmchip0";
+    let properties = Some("org.freedesktop.DBus.Properties");
+    let npwm = connection
+        .call_method(
+            destination,
+            path,
+            properties,
+            "Get",
+            &("com.kevinbader.pwmd.Controller1", "Npwm"),
+        )
+        .and_then(|res| res.body::<OwnedValue>())?;
+    assert_eq!(u32::try_from(npwm)?, 2);
+
+    // ...and written:
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm1";
+    let _ = connection.call_method(
+        destination,
+        path,
+        properties,
+        "Set",
+        &(
+            "com.kevinbader.pwmd.Channel1",
+            "DutyCycle",
+            Value::from(50u64),
+        ),
+    )?;
+    check_file(&duty_cycle_file, "50");
+    let _ = connection.call_method(
+        destination,
+        path,
+        properties,
+        "Set",
+        &("com.kevinbader.pwmd.Channel1", "Enabled", Value::from(true)),
+    )?;
+    check_file(&enable_file, "1");
+
+    // invalid values are rejected:
+    assert!(connection
+        .call_method(
+            destination,
+            path,
+            properties,
+            "Set",
+            &(
+                "com.kevinbader.pwmd.Channel1",
+                "DutyCycle",
+                Value::from(101u64)
+            ),
+        )
+        .is_err());
+    check_file(&duty_cycle_file, "50");
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_changes_are_signalled() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    fake_channel(&chip_dir, 0, 100, 70);
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwm1";
+    let iface = Some("com.kevinbader.pwmd.pwm1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',\
+             path='/com/kevinbader/pwmd/pwmchip0/pwm0'",
+            dbus_service_name
+        ),
+    )?;
+
+    let _ = connection.call_method(
+        destination,
+        path,
+        iface,
+        "SetDutyCycleNs",
+        &(0u32, 0u32, 50u64),
+    )?;
+    let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.Channel1")?;
+    assert_eq!(changed.len(), 1);
+    assert_eq!(u64::try_from(changed["DutyCycle"].clone())?, 50);
+
+    let _ = connection.call_method(destination, path, iface, "Enable", &(0u32, 0u32))?;
+    let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.Channel1")?;
+    assert_eq!(changed.len(), 1);
+    assert!(bool::try_from(changed["Enabled"].clone())?);
+
+    // quit:
+    let _ = connection.call_method(destination, path, iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_apply_state() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported and enabled:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 100, 70);
+    let enable = write(channel_dir.join("enable"), "1");
+    let period = channel_dir.join("period");
+    let duty_cycle = channel_dir.join("duty_cycle");
+    let polarity = channel_dir.join("polarity");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwm1";
+    let iface = Some("com.kevinbader.pwmd.pwm1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',\
+             path='/com/kevinbader/pwmd/pwmchip0/pwm0'",
+            dbus_service_name
+        ),
+    )?;
+
+    // shrinking the period below the current duty cycle and inverting the
+    // polarity of an enabled channel takes a single call:
+    let _ = connection.call_method(
+        destination,
+        path,
+        iface,
+        "ApplyState",
+        &(0u32, 0u32, 50u64, 40u64, "inversed", true),
+    )?;
+    check_file(&period, "50");
+    check_file(&duty_cycle, "40");
+    check_file(&polarity, "inversed");
+    check_file(&enable, "1");
+
+    let mut changed = HashMap::new();
+    while changed.len() < 3 {
+        changed.extend(next_properties_changed(
+            &signals,
+            "com.kevinbader.pwmd.Channel1",
+        )?);
+    }
+    assert_eq!(u64::try_from(changed["Period"].clone())?, 50);
+    assert_eq!(u64::try_from(changed["DutyCycle"].clone())?, 40);
+    assert_eq!(String::try_from(changed["Polarity"].clone())?, "inversed");
+    assert!(!changed.contains_key("Enabled"));
+
+    // invalid states are rejected without touching the channel:
+    assert!(connection
+        .call_method(
+            destination,
+            path,
+            iface,
+            "ApplyState",
+            &(0u32, 0u32, 50u64, 60u64, "normal", false),
+        )
+        .is_err());
+    check_file(&period, "50");
+    check_file(&duty_cycle, "40");
+    check_file(&polarity, "inversed");
+    check_file(&enable, "1");
+
+    // quit:
+    let _ = connection.call_method(destination, path, iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_frequency_and_duty_percent() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 100, 70);
+    let period = channel_dir.join("period");
+    let duty_cycle = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwm1";
+    let iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // 25 kHz at 40%:
+    let reply = connection.call_method(
+        destination,
+        path,
+        iface,
+        "Configure",
+        &(0u32, 0u32, 25_000f64, 40f64),
+    )?;
+    assert_eq!(
+        reply.body::<(u64, u64, f64, f64)>()?,
+        (40_000, 16_000, 25_000.0, 40.0)
+    );
+    check_file(&period, "40000");
+    check_file(&duty_cycle, "16000");
+
+    // the duty ratio is kept, the rounding is reported back:
+    let reply = connection.call_method(
+        destination,
+        path,
+        iface,
+        "SetFrequencyHz",
+        &(0u32, 0u32, 30_000f64),
+    )?;
+    let (period_ns, duty_cycle_ns, frequency_hz, duty_percent) =
+        reply.body::<(u64, u64, f64, f64)>()?;
+    assert_eq!((period_ns, duty_cycle_ns), (33_333, 13_333));
+    assert!((frequency_hz - 30_000.3).abs() < 0.1);
+    assert!((duty_percent - 40.0).abs() < 0.01);
+    check_file(&period, "33333");
+    check_file(&duty_cycle, "13333");
+
+    let _ = connection.call_method(
+        destination,
+        path,
+        iface,
+        "SetDutyPercent",
+        &(0u32, 0u32, 100f64),
+    )?;
+    check_file(&duty_cycle, "33333");
+
+    assert!(connection
+        .call_method(
+            destination,
+            path,
+            iface,
+            "SetDutyPercent",
+            &(0u32, 0u32, 101f64),
+        )
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(destination, path, iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_led_brightness_and_fades() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 1000, 0);
+    let enable = channel_dir.join("enable");
+    let duty_cycle = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    let properties = Some("org.freedesktop.DBus.Properties");
+    let led = "com.kevinbader.pwmd.Led1";
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',\
+             path='{}'",
+            dbus_service_name, path
+        ),
+    )?;
+
+    // with a linear curve, brightness maps directly onto the duty cycle:
+    let _ = connection.call_method(
+        destination,
+        path,
+        properties,
+        "Set",
+        &(led, "Gamma", Value::from(1f64)),
+    )?;
+    let _ = connection.call_method(
+        destination,
+        path,
+        properties,
+        "Set",
+        &(led, "Brightness", Value::from(0.5f64)),
+    )?;
+    check_file(&duty_cycle, "500");
+    check_file(&enable, "1");
+
+    // fading happens inside pwmd, the final brightness is signalled:
+    let _ = connection.call_method(destination, path, Some(led), "FadeTo", &(1f64, 100u32))?;
+    loop {
+        let changed = next_properties_changed(&signals, led)?;
+        if let Some(brightness) = changed.get("Brightness") {
+            if f64::try_from(brightness.clone())? == 1.0 {
+                break;
+            }
+        }
+    }
+    check_file(&duty_cycle, "1000");
+
+    // invalid brightness values are rejected:
+    assert!(connection
+        .call_method(destination, path, Some(led), "FadeTo", &(1.5f64, 100u32))
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_led_effects() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 1000, 0);
+    let enable = channel_dir.join("enable");
+    let duty_cycle = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    let led = Some("com.kevinbader.pwmd.Led1");
+
+    // blinking once ends with the LED switched off:
+    let _ = connection.call_method(destination, path, led, "Blink", &(100u32, 50u32, 1u32))?;
+    check_file(&enable, "1");
+    check_file(&duty_cycle, "1000");
+    std::thread::sleep(Duration::from_millis(300));
+    check_file(&duty_cycle, "0");
+
+    // an effect keeps running after the client is gone...
+    let _ = connection.call_method(destination, path, led, "Blink", &(20u32, 20u32, 0u32))?;
+    std::thread::sleep(Duration::from_millis(100));
+
+    // ...until the duty cycle is written:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "SetDutyCycleNs",
+        &(0u32, 0u32, 300u64),
+    )?;
+    std::thread::sleep(Duration::from_millis(100));
+    check_file(&duty_cycle, "300");
+
+    // StopEffect leaves the LED as it is:
+    let _ = connection.call_method(destination, path, led, "Breathe", &(200u32,))?;
+    std::thread::sleep(Duration::from_millis(50));
+    let _ = connection.call_method(destination, path, led, "StopEffect", &())?;
+    let stopped_at = fs::read_to_string(&duty_cycle).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    check_file(&duty_cycle, &stopped_at);
+
+    // so does setting the polarity, even though the LED is enabled:
+    let _ = connection.call_method(destination, path, led, "Blink", &(20u32, 20u32, 0u32))?;
+    std::thread::sleep(Duration::from_millis(50));
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "SetPolarity",
+        &(0u32, 0u32, "inversed"),
+    );
+    let stopped_at = fs::read_to_string(&duty_cycle).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    check_file(&duty_cycle, &stopped_at);
+
+    assert!(connection
+        .call_method(destination, path, led, "Heartbeat", &(0u32,))
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_rgb_lights() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1,2} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 3);
+    let mut duty_cycles = vec![];
+    for i in 0..3 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+    let light_iface = Some("com.kevinbader.pwmd.RgbLight1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',path_namespace='/com/kevinbader/pwmd'",
+            dbus_service_name
+        ),
+    )?;
+
+    // declare the light and wait for its object:
+    let path = connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "AddRgbLight",
+            &("status", vec![(0u32, 0u32), (0u32, 1u32), (0u32, 2u32)]),
+        )?
+        .body::<OwnedObjectPath>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/light/status");
+    loop {
+        let (added, _) = next_signal(&signals, "InterfacesAdded")?.body::<(
+            OwnedObjectPath,
+            HashMap<String, HashMap<String, OwnedValue>>,
+        )>()?;
+        if added == path {
+            break;
+        }
+    }
+    let path = path.as_str();
+
+    // the names of lights are unique:
+    assert!(connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "AddRgbLight",
+            &("status", vec![(0u32, 0u32), (0u32, 1u32), (0u32, 2u32)]),
+        )
+        .is_err());
+
+    // with a linear curve, colors map directly onto the duty cycles:
+    let _ = connection.call_method(
+        destination,
+        path,
+        Some("org.freedesktop.DBus.Properties"),
+        "Set",
+        &("com.kevinbader.pwmd.RgbLight1", "Gamma", Value::from(1f64)),
+    )?;
+    let _ = connection.call_method(
+        destination,
+        path,
+        light_iface,
+        "SetColorRgb",
+        &(1f64, 0.5f64, 0f64),
+    )?;
+    for (duty_cycle, expected) in duty_cycles.iter().zip(["1000", "500", "0"]) {
+        check_file(duty_cycle, expected);
+    }
+
+    // white balance scales the channels:
+    let _ = connection.call_method(
+        destination,
+        path,
+        light_iface,
+        "SetWhiteBalance",
+        &(vec![1f64, 0.8f64, 0.5f64],),
+    )?;
+    let _ = connection.call_method(
+        destination,
+        path,
+        light_iface,
+        "SetColorHsv",
+        &(0f64, 0f64, 1f64),
+    )?;
+    for (duty_cycle, expected) in duty_cycles.iter().zip(["1000", "800", "500"]) {
+        check_file(duty_cycle, expected);
+    }
+
+    // color fades happen inside pwmd:
+    let _ = connection.call_method(
+        destination,
+        path,
+        light_iface,
+        "FadeToRgb",
+        &(0f64, 0f64, 1f64, 100u32),
+    )?;
+    loop {
+        let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.RgbLight1")?;
+        if let Some(color) = changed.get("Color") {
+            if <(f64, f64, f64)>::try_from(color.clone())? == (0.0, 0.0, 1.0) {
+                break;
+            }
+        }
+    }
+    for (duty_cycle, expected) in duty_cycles.iter().zip(["0", "0", "500"]) {
+        check_file(duty_cycle, expected);
+    }
+
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "RemoveRgbLight",
+        &("status",),
+    )?;
+    let (removed, _) =
+        next_signal(&signals, "InterfacesRemoved")?.body::<(OwnedObjectPath, Vec<String>)>()?;
+    assert_eq!(removed.as_str(), path);
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_servo() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 0, 0);
+    let enable = channel_dir.join("enable");
+    let period = channel_dir.join("period");
+    let duty_cycle = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    let properties = Some("org.freedesktop.DBus.Properties");
+    let servo = "com.kevinbader.pwmd.Servo1";
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',\
+             path='{}'",
+            dbus_service_name, path
+        ),
+    )?;
+
+    // the period is set to 20 ms and the channel enabled:
+    let _ = connection.call_method(destination, path, Some(servo), "SetAngle", &(90f64))?;
+    check_file(&period, "20000000");
+    check_file(&duty_cycle, "1500000");
+    check_file(&enable, "1");
+
+    // the calibration offset is added to every angle:
+    let _ = connection.call_method(
+        destination,
+        path,
+        properties,
+        "Set",
+        &(servo, "Offset", Value::from(9f64)),
+    )?;
+    let _ = connection.call_method(destination, path, Some(servo), "SetAngle", &(90f64))?;
+    check_file(&duty_cycle, "1550000");
+
+    // moving happens inside pwmd, the final angle is signalled:
+    let _ = connection.call_method(destination, path, Some(servo), "MoveTo", &(0f64, 900f64))?;
+    loop {
+        let changed = next_properties_changed(&signals, servo)?;
+        if let Some(angle) = changed.get("Angle") {
+            if f64::try_from(angle.clone())? == 0.0 {
+                break;
+            }
+        }
+    }
+    check_file(&duty_cycle, "1050000");
+
+    // angles outside of the range are rejected:
+    assert!(connection
+        .call_method(destination, path, Some(servo), "SetAngle", &(181f64))
+        .is_err());
+
+    let _ = connection.call_method(destination, path, Some(servo), "Detach", &())?;
+    check_file(&enable, "0");
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_fan_curve() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported, running at
+    // 25 kHz, next to a thermal zone:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 40000, 0);
+    let enable = channel_dir.join("enable");
+    let zone_dir = tmpdir.child("thermal/thermal_zone0");
+    fs::create_dir_all(&zone_dir).unwrap();
+    let temp = write(zone_dir.join("temp"), "30000");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    let fan = Some("com.kevinbader.pwmd.Fan1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',\
+             path='{}'",
+            dbus_service_name, path
+        ),
+    )?;
+    let wait_for_duty_cycle = |expected: u64| -> anyhow::Result<()> {
+        loop {
+            let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.Channel1")?;
+            if let Some(duty_cycle) = changed.get("DutyCycle") {
+                if u64::try_from(duty_cycle.clone())? == expected {
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    // 20% at 30 degrees is raised to the minimum of 30%:
+    let _ = connection.call_method(
+        destination,
+        path,
+        fan,
+        "SetCurve",
+        &(
+            "thermal/thermal_zone0/temp",
+            vec![(30f64, 20f64), (60f64, 100f64)],
+            5f64,
+            30f64,
+        ),
+    )?;
+    wait_for_duty_cycle(12000)?;
+    check_file(&enable, "1");
+
+    // the fan speeds up as the temperature rises:
+    let _ = write(temp, "45000");
+    wait_for_duty_cycle(24000)?;
+
+    // the fan runs at full speed if the sensor cannot be read:
+    fs::remove_dir_all(&zone_dir).unwrap();
+    wait_for_duty_cycle(40000)?;
+
+    let sensor = || -> anyhow::Result<String> {
+        let value: OwnedValue = connection
+            .call_method(
+                destination,
+                path,
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &("com.kevinbader.pwmd.Fan1", "Sensor"),
+            )?
+            .body()?;
+        Ok(String::try_from(value)?)
+    };
+    assert_eq!(sensor()?, "thermal/thermal_zone0/temp");
+
+    // writing the duty cycle stops the control and resets the fan's settings:
+    let _ = connection.call_method(
+        destination,
+        path,
+        Some("org.freedesktop.DBus.Properties"),
+        "Set",
+        &(
+            "com.kevinbader.pwmd.Channel1",
+            "DutyCycle",
+            Value::from(20000u64),
+        ),
+    )?;
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(sensor()?, "");
+    check_file(&channel_dir.join("duty_cycle"), "20000");
+
+    // so does an error while controlling the fan:
+    fs::create_dir_all(&zone_dir).unwrap();
+    let _ = write(zone_dir.join("temp"), "30000");
+    let _ = connection.call_method(
+        destination,
+        path,
+        fan,
+        "SetCurve",
+        &(
+            "thermal/thermal_zone0/temp",
+            vec![(30f64, 20f64), (60f64, 100f64)],
+            5f64,
+            30f64,
+        ),
+    )?;
+    assert_eq!(sensor()?, "thermal/thermal_zone0/temp");
+    fs::remove_file(channel_dir.join("duty_cycle")).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(sensor()?, "");
+    let _ = write(channel_dir.join("duty_cycle"), "0");
+
+    // sensors outside of thermal and hwmon are rejected:
+    assert!(connection
+        .call_method(
+            destination,
+            path,
+            fan,
+            "SetCurve",
+            &("../pwmchip0/npwm", vec![(30f64, 20f64)], 5f64, 30f64),
+        )
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_buzzer() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 0, 0);
+    let enable = channel_dir.join("enable");
+    let period = channel_dir.join("period");
+    let duty_cycle = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let path = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    let buzzer = Some("com.kevinbader.pwmd.Buzzer1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',interface='com.kevinbader.pwmd.Buzzer1',path='{}'",
+            dbus_service_name, path
+        ),
+    )?;
+
+    // the melody plays in the background and reports when it's over:
+    let _ = connection.call_method(
+        destination,
+        path,
+        buzzer,
+        "PlayMelody",
+        &("scale:d=16,o=5,b=600:c,d,e"),
+    )?;
+    let completed: bool = next_signal(&signals, "Finished")?.body()?;
+    assert!(completed);
+    check_file(&enable, "0");
+
+    // a tone plays at half the duty cycle until it is stopped:
+    let _ = connection.call_method(destination, path, buzzer, "PlayTone", &(1000f64, 60000u32))?;
+    check_file(&period, "1000000");
+    check_file(&duty_cycle, "500000");
+    check_file(&enable, "1");
+    let _ = connection.call_method(destination, path, buzzer, "Stop", &())?;
+    let completed: bool = next_signal(&signals, "Finished")?.body()?;
+    assert!(!completed);
+    check_file(&enable, "0");
+
+    // invalid melodies are rejected:
+    assert!(connection
+        .call_method(destination, path, buzzer, "PlayMelody", &("scale:d=3:c"))
+        .is_err());
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_motor() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let mut duty_cycles = vec![];
+    let mut enables = vec![];
+    for i in 0..2 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        enables.push(channel_dir.join("enable"));
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+    let motor_iface = Some("com.kevinbader.pwmd.Motor1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',path_namespace='/com/kevinbader/pwmd'",
+            dbus_service_name
+        ),
+    )?;
+
+    // unknown wirings are rejected:
+    assert!(connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "AddMotor",
+            &("left", "sideways", vec![(0u32, 0u32), (0u32, 1u32)]),
+        )
+        .is_err());
+
+    // declare the motor and wait for its object:
+    let path = connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "AddMotor",
+            &("left", "direction", vec![(0u32, 0u32), (0u32, 1u32)]),
+        )?
+        .body::<OwnedObjectPath>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/motor/left");
+    loop {
+        let (added, _) = next_signal(&signals, "InterfacesAdded")?.body::<(
+            OwnedObjectPath,
+            HashMap<String, HashMap<String, OwnedValue>>,
+        )>()?;
+        if added == path {
+            break;
+        }
+    }
+    let path = path.as_str();
+
+    // the speed ramps up in the background:
+    let _ = connection.call_method(
+        destination,
+        path,
+        Some("org.freedesktop.DBus.Properties"),
+        "Set",
+        &(
+            "com.kevinbader.pwmd.Motor1",
+            "Acceleration",
+            Value::from(10f64),
+        ),
+    )?;
+    let _ = connection.call_method(destination, path, motor_iface, "SetSpeed", &(0.5f64,))?;
+    let wait_for_speed = |expected: f64| -> anyhow::Result<()> {
+        loop {
+            let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.Motor1")?;
+            if let Some(speed) = changed.get("Speed") {
+                if f64::try_from(speed.clone())? == expected {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    wait_for_speed(0.5)?;
+    for (duty_cycle, expected) in duty_cycles.iter().zip(["500", "1000"]) {
+        check_file(duty_cycle, expected);
+    }
+    for enable in &enables {
+        check_file(enable, "1");
+    }
+
+    // reversing goes through zero and flips the direction channel:
+    let _ = connection.call_method(
+        destination,
+        path,
+        Some("org.freedesktop.DBus.Properties"),
+        "Set",
+        &(
+            "com.kevinbader.pwmd.Motor1",
+            "Deceleration",
+            Value::from(10f64),
+        ),
+    )?;
+    let _ = connection.call_method(destination, path, motor_iface, "SetSpeed", &(-0.25f64,))?;
+    wait_for_speed(-0.25)?;
+    for (duty_cycle, expected) in duty_cycles.iter().zip(["250", "0"]) {
+        check_file(duty_cycle, expected);
+    }
+
+    // stopping is immediate:
+    let _ = connection.call_method(destination, path, motor_iface, "Stop", &())?;
+    wait_for_speed(0.0)?;
+    check_file(&duty_cycles[0], "0");
+
+    // speeds are between -1 and 1:
+    assert!(connection
+        .call_method(destination, path, motor_iface, "SetSpeed", &(1.5f64,))
+        .is_err());
+
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "RemoveMotor", &("left",))?;
+    loop {
+        let (removed, _) =
+            next_signal(&signals, "InterfacesRemoved")?.body::<(OwnedObjectPath, Vec<String>)>()?;
+        if removed.as_str() == path {
+            break;
+        }
+    }
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_named_channels() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let mut periods = vec![];
+    let mut polarities = vec![];
+    for i in 0..2 {
+        let channel_dir = fake_channel(&chip_dir, i, 0, 0);
+        periods.push(channel_dir.join("period"));
+        polarities.push(channel_dir.join("polarity"));
+    }
+    let device_dir = tmpdir.child("devices/platform/soc/1c21400.pwm");
+    fs::create_dir_all(&device_dir).unwrap();
+    std::os::unix::fs::symlink(&device_dir, chip_dir.join("device")).unwrap();
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        r#"
+        [defaults]
+        period_ns = 1000000
+
+        [channels]
+        status_led = { chip = "1c21400.pwm", channel = 1, role = "led", polarity = "inversed" }
+        "#,
+    );
+
+    let (dbus_service_name, dbus_thread) =
+        spawn_pwmd_with(tmpdir.path(), |args| args.config = Some(config));
+
+    // the named channel is set up at startup, the other one is left alone:
+    check_file(&periods[0], "0");
+    check_file(&polarities[0], "normal");
+    check_file(&periods[1], "1000000");
+    check_file(&polarities[1], "inversed");
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // controllers can be found by their device:
+    let controller: u32 = connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "FindController",
+            &("1c21400.pwm",),
+        )?
+        .body()?;
+    assert_eq!(controller, 0);
+    assert!(connection
+        .call_method(destination, pwm1, pwm1_iface, "FindController", &("foo",))
+        .is_err());
+
+    // the channel can be looked up by its name:
+    let named: Vec<(String, u32, u32, String)> = connection
+        .call_method(destination, pwm1, pwm1_iface, "ListNamedChannels", &())?
+        .body()?;
+    assert_eq!(
+        named,
+        vec![("status_led".to_owned(), 0, 1, "led".to_owned())]
+    );
+    let path = connection
+        .call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "GetChannelByName",
+            &("status_led",),
+        )?
+        .body::<OwnedObjectPath>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/pwmchip0/pwm1");
+    assert!(connection
+        .call_method(destination, pwm1, pwm1_iface, "GetChannelByName", &("fan",))
+        .is_err());
+
+    // the channel objects tell their name and role:
+    for (path, name, role) in [
+        ("/com/kevinbader/pwmd/pwmchip0/pwm0", "", "raw"),
+        ("/com/kevinbader/pwmd/pwmchip0/pwm1", "status_led", "led"),
+    ] {
+        for (property, expected) in [("Name", name), ("Role", role)] {
+            let value: OwnedValue = connection
+                .call_method(
+                    destination,
+                    path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "Get",
+                    &("com.kevinbader.pwmd.Channel1", property),
+                )?
+                .body()?;
+            assert_eq!(String::try_from(value)?, expected);
+        }
+    }
+
+    // the LED only implements the interface of its role, the raw channel all
+    // of them:
+    let objects: ManagedObjects = connection
+        .call_method(
+            destination,
+            "/com/kevinbader/pwmd",
+            Some("org.freedesktop.DBus.ObjectManager"),
+            "GetManagedObjects",
+            &(),
+        )?
+        .body()?;
+    let interfaces_of = |path: &str| -> Vec<&str> {
+        let path = OwnedObjectPath::try_from(path).unwrap();
+        let mut names: Vec<&str> = objects[&path].keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    };
+    assert_eq!(
+        interfaces_of("/com/kevinbader/pwmd/pwmchip0/pwm0"),
+        vec![
+            "com.kevinbader.pwmd.Buzzer1",
+            "com.kevinbader.pwmd.Channel1",
+            "com.kevinbader.pwmd.Fan1",
+            "com.kevinbader.pwmd.Led1",
+            "com.kevinbader.pwmd.Servo1",
+        ]
+    );
+    assert_eq!(
+        interfaces_of("/com/kevinbader/pwmd/pwmchip0/pwm1"),
+        vec!["com.kevinbader.pwmd.Channel1", "com.kevinbader.pwmd.Led1"]
+    );
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_state_survives_restarts() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 0, 0);
+    let enable = channel_dir.join("enable");
+    let period = channel_dir.join("period");
+    let duty_cycle = channel_dir.join("duty_cycle");
+    let state_file = tmpdir.child("lib/state.json");
+
+    let run = |startup: Startup, calls: &[(&str, u64)]| -> anyhow::Result<()> {
+        let state_file = state_file.clone();
+        let (dbus_service_name, dbus_thread) = spawn_pwmd_with(tmpdir.path(), |args| {
+            args.state_file = Some(state_file);
+            args.startup = startup;
+        });
+        let connection = Connection::session()?;
+        let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+        let destination = Some(&destination);
+        let pwm1 = "/com/kevinbader/pwmd/pwm1";
+        let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+        for &(method, value) in calls {
+            let _ = connection.call_method(
+                destination,
+                pwm1,
+                pwm1_iface,
+                method,
+                &(0u32, 0u32, value),
+            )?;
+        }
+        let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+        dbus_thread.join().unwrap();
+        Ok(())
+    };
+
+    // changes made through pwmd are recorded:
+    run(
+        Startup::Leave,
+        &[("SetPeriodNs", 1000), ("SetDutyCycleNs", 400)],
+    )?;
+    let state = fs::read_to_string(&state_file)?;
+    assert!(state.contains("\"period_ns\": 1000"), "{}", state);
+    assert!(state.contains("\"duty_cycle_ns\": 400"), "{}", state);
+
+    // "leave" doesn't touch the channels:
+    let _ = write(duty_cycle.clone(), "100");
+    run(Startup::Leave, &[])?;
+    check_file(&duty_cycle, "100");
+
+    // the recorded settings are restored:
+    run(Startup::Leave, &[("SetDutyCycleNs", 400)])?;
+    let _ = write(duty_cycle.clone(), "100");
+    let _ = write(enable.clone(), "1");
+    run(Startup::Restore, &[])?;
+    check_file(&period, "1000");
+    check_file(&duty_cycle, "400");
+    check_file(&enable, "0");
+
+    // or reset to safe defaults:
+    let _ = write(enable.clone(), "1");
+    run(Startup::Reset, &[])?;
+    check_file(&period, "1000");
+    check_file(&duty_cycle, "0");
+    check_file(&enable, "0");
+
+    // changes made through the channel objects are recorded as well:
+    let (dbus_service_name, dbus_thread) = spawn_pwmd_with(tmpdir.path(), |args| {
+        args.state_file = Some(state_file.clone());
+    });
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwmchip0/pwm0",
+        Some("org.freedesktop.DBus.Properties"),
+        "Set",
+        &(
+            "com.kevinbader.pwmd.Channel1",
+            "DutyCycle",
+            Value::from(600u64),
+        ),
+    )?;
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    let state = fs::read_to_string(&state_file)?;
+    assert!(state.contains("\"duty_cycle_ns\": 600"), "{}", state);
+
+    // the recorded settings are restored before the limits apply:
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        "[channels]\nsensor = { chip = 0, channel = 0, read_only = true }",
+    );
+    let _ = write(duty_cycle.clone(), "100");
+    let (dbus_service_name, dbus_thread) = spawn_pwmd_with(tmpdir.path(), |args| {
+        args.state_file = Some(state_file.clone());
+        args.startup = Startup::Restore;
+        args.config = Some(config);
+    });
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let _ = connection.call_method(
+        Some(&destination),
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    check_file(&duty_cycle, "600");
+
+    Ok(())
+}
+
+#[test]
+fn test_shutdown_policy() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1,2} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 3);
+    let mut enables = vec![];
+    let mut duty_cycles = vec![];
+    for i in 0..3 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        enables.push(channel_dir.join("enable"));
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        r#"
+        [defaults]
+        on_shutdown = "disable"
+
+        [channels]
+        motor = { chip = 0, channel = 0 }
+        fan = { chip = 0, channel = 1, role = "fan", on_shutdown = { duty_percent = 100 } }
+        "#,
+    );
+
+    let (dbus_service_name, dbus_thread) =
+        spawn_pwmd_with(tmpdir.path(), |args| args.config = Some(config));
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // the motor is driven by an effect that keeps running in the background:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwmchip0/pwm0",
+        Some("com.kevinbader.pwmd.Led1"),
+        "Blink",
+        &(20u32, 20u32, 0u32),
+    )?;
+    for channel in 1..3u32 {
+        let _ = connection.call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "SetDutyCycleNs",
+            &(0u32, channel, 300u64),
+        )?;
+        let _ =
+            connection.call_method(destination, pwm1, pwm1_iface, "Enable", &(0u32, channel))?;
+    }
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+
+    // the effect is stopped and the channels are left as configured:
+    std::thread::sleep(Duration::from_millis(100));
+    check_file(&enables[0], "0");
+    check_file(&enables[1], "1");
+    check_file(&duty_cycles[1], "1000");
+    // channels without a name are kept as they are:
+    check_file(&enables[2], "1");
+    check_file(&duty_cycles[2], "300");
+    Ok(())
+}
+
+#[test]
+fn test_leases() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let mut enables = vec![];
+    let mut duty_cycles = vec![];
+    for i in 0..2 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        enables.push(channel_dir.join("enable"));
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        r#"
+        [channels]
+        fan = { chip = 0, channel = 1, role = "fan", on_disconnect = { duty_percent = 100 } }
+        "#,
+    );
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd_with(tmpdir.path(), |args| {
+        args.config = Some(config);
+        args.poll_interval_ms = 100;
+    });
+
+    let connection = Connection::session()?;
+    let owner = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // the owner leases both channels and drives them:
+    for channel in 0..2u32 {
+        let _ = owner.call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "AcquireChannel",
+            &(0u32, channel),
+        )?;
+        let _ = owner.call_method(
+            destination,
+            pwm1,
+            pwm1_iface,
+            "SetDutyCycleNs",
+            &(0u32, channel, 300u64),
+        )?;
+        let _ = owner.call_method(destination, pwm1, pwm1_iface, "Enable", &(0u32, channel))?;
+    }
+    check_file(&enables[0], "1");
+    check_file(&duty_cycles[1], "300");
+
+    // other clients cannot write to the channels or lease them:
+    for (method, args) in [
+        ("Disable", (0u32, 0u32)),
+        ("UnexportChannel", (0u32, 1u32)),
+        ("AcquireChannel", (0u32, 0u32)),
+        ("ReleaseChannel", (0u32, 0u32)),
+    ] {
+        let error = match connection.call_method(destination, pwm1, pwm1_iface, method, &args) {
+            Err(zbus::Error::MethodError(name, Some(error), _)) => {
+                assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+                error
+            }
+            x => panic!("expected MethodError, got: {:?}", x),
+        };
+        assert!(error.contains("is leased by"), "{}: {}", method, error);
+    }
+    check_file(&enables[0], "1");
+
+    // ...not even by setting properties, which the owner may:
+    let set_enabled = |client: &Connection, enabled: bool| {
+        client.call_method(
+            destination,
+            "/com/kevinbader/pwmd/pwmchip0/pwm0",
+            Some("org.freedesktop.DBus.Properties"),
+            "Set",
+            &(
+                "com.kevinbader.pwmd.Channel1",
+                "Enabled",
+                Value::from(enabled),
+            ),
+        )
+    };
+    match set_enabled(&connection, false) {
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+            assert!(error.contains("is leased by"), "{}", error);
+        }
+        x => panic!("expected MethodError, got: {:?}", x),
+    }
+    check_file(&enables[0], "1");
+    let _ = set_enabled(&owner, false)?;
+    check_file(&enables[0], "0");
+    let _ = set_enabled(&owner, true)?;
+    check_file(&enables[0], "1");
+
+    // reading is fine:
+    assert!(connection
+        .call_method(destination, pwm1, pwm1_iface, "IsEnabled", &(0u32, 0u32))
+        .and_then(|res| res.body::<bool>())?);
+
+    // once the owner disconnects, pwm0 is disabled, as unnamed channels are
+    // by default, and the fan runs at full speed:
+    drop(owner);
+    std::thread::sleep(Duration::from_millis(500));
+    check_file(&enables[0], "0");
+    check_file(&enables[1], "1");
+    check_file(&duty_cycles[1], "1000");
+
+    // ...and the channels are free again:
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "AcquireChannel",
+        &(0u32, 0u32),
+    )?;
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Enable", &(0u32, 0u32))?;
+    check_file(&enables[0], "1");
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "ReleaseChannel",
+        &(0u32, 0u32),
+    )?;
+
+    // a lease ends when its channel goes away, e.g., when another process
+    // unexports it:
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "AcquireChannel",
+        &(0u32, 0u32),
+    )?;
+    fs::remove_dir_all(chip_dir.join("pwm0")).unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    let _ = fake_channel(&chip_dir, 0, 1000, 0);
+    std::thread::sleep(Duration::from_millis(300));
+    let other = Connection::session()?;
+    let _ = other.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "AcquireChannel",
+        &(0u32, 0u32),
+    )?;
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_watchdog() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported and enabled:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 1000, 500);
+    let enable_file = write(channel_dir.join("enable"), "1");
+    let duty_cycle_file = channel_dir.join("duty_cycle");
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',member='WatchdogExpired'",
+            dbus_service_name
+        ),
+    )?;
+
+    // invalid fallbacks and timeouts are rejected:
+    for (timeout_ms, fallback) in [(200u32, "stop"), (200, "150%"), (0, "disable")] {
+        assert!(connection
+            .call_method(
+                destination,
+                pwm1,
+                pwm1_iface,
+                "ArmWatchdog",
+                &(0u32, 0u32, timeout_ms, fallback),
+            )
+            .is_err());
+    }
+
+    // as long as the watchdog is kicked in time, nothing happens:
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "ArmWatchdog",
+        &(0u32, 0u32, 200u32, "0%"),
+    )?;
+    for _ in 0..5 {
+        std::thread::sleep(Duration::from_millis(100));
+        let _ = connection.call_method(destination, pwm1, pwm1_iface, "Kick", &(0u32, 0u32))?;
+    }
+    check_file(&duty_cycle_file, "500");
+
+    // once the kicks stop, the fallback is applied:
+    let (controller, channel) = next_signal(&signals, "WatchdogExpired")?.body::<(u32, u32)>()?;
+    assert_eq!((controller, channel), (0, 0));
+    check_file(&duty_cycle_file, "0");
+    check_file(&enable_file, "1");
+
+    // the watchdog is gone then:
+    assert!(connection
+        .call_method(destination, pwm1, pwm1_iface, "Kick", &(0u32, 0u32))
+        .is_err());
+
+    // a disarmed watchdog doesn't expire:
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "ArmWatchdog",
+        &(0u32, 0u32, 100u32, "disable"),
+    )?;
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "DisarmWatchdog",
+        &(0u32, 0u32),
+    )?;
+    std::thread::sleep(Duration::from_millis(300));
+    check_file(&enable_file, "1");
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_limits() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let mut duty_cycles = vec![];
+    for i in 0..2 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        r#"
+        [channels]
+        motor = { chip = 0, channel = 0, max_duty_percent = 50, min_period_ns = 500 }
+        sensor = { chip = 0, channel = 1, read_only = true }
+        "#,
+    );
+
+    let (dbus_service_name, dbus_thread) =
+        spawn_pwmd_with(tmpdir.path(), |args| args.config = Some(config));
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetDutyPercent",
+        &(0u32, 0u32, 50.0),
+    )?;
+    check_file(&duty_cycles[0], "500");
+
+    // writes that break the limits are rejected:
+    for (method, args) in [
+        ("SetDutyPercent", (0u32, 0u32, 80.0)),
+        ("SetFrequencyHz", (0u32, 0u32, 1e7)),
+    ] {
+        let error = match connection.call_method(destination, pwm1, pwm1_iface, method, &args) {
+            Err(zbus::Error::MethodError(_, Some(error), _)) => error,
+            x => panic!("expected MethodError, got: {:?}", x),
+        };
+        assert!(
+            error.contains("maximum") || error.contains("minimum"),
+            "{}: {}",
+            method,
+            error
+        );
+    }
+    check_file(&duty_cycles[0], "500");
+
+    // read-only channels cannot be written at all:
+    for method in ["Enable", "UnexportChannel"] {
+        let error =
+            match connection.call_method(destination, pwm1, pwm1_iface, method, &(0u32, 1u32)) {
+                Err(zbus::Error::MethodError(_, Some(error), _)) => error,
+                x => panic!("expected MethodError, got: {:?}", x),
+            };
+        assert!(error.contains("read-only"), "{}: {}", method, error);
+    }
+    check_file(&chip_dir.join("unexport"), "");
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_access_rules() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm{0,1} exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    let mut duty_cycles = vec![];
+    for i in 0..2 {
+        let channel_dir = fake_channel(&chip_dir, i, 1000, 0);
+        duty_cycles.push(channel_dir.join("duty_cycle"));
+    }
+    let config = write(
+        tmpdir.child("pwmd.toml"),
+        r#"
+        [channels]
+        heater = { chip = 0, channel = 0, write = ["@heating"] }
+        "#,
+    );
+
+    let (dbus_service_name, dbus_thread) =
+        spawn_pwmd_with(tmpdir.path(), |args| args.config = Some(config));
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+    let properties = Some("org.freedesktop.DBus.Properties");
+
+    // The test runs as the user pwmd runs as, which makes it an
+    // administrator, so it may call methods that write to all channels...
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetDutyCycleNs",
+        &(0u32, 0u32, 500u64),
+    )?;
+    check_file(&duty_cycles[0], "500");
+
+    // ...and set their properties:
+    let set_property = |channel: u32, interface: &str, property: &str, value: Value<'static>| {
+        connection.call_method(
+            destination,
+            format!("/com/kevinbader/pwmd/pwmchip0/pwm{}", channel).as_str(),
+            properties,
+            "Set",
+            &(interface, property, value),
+        )
+    };
+    for channel in 0..2 {
+        let _ = set_property(
+            channel,
+            "com.kevinbader.pwmd.Channel1",
+            "DutyCycle",
+            Value::from(250u64),
+        )?;
+        check_file(&duty_cycles[channel as usize], "250");
+        let _ = set_property(
+            channel,
+            "com.kevinbader.pwmd.Servo1",
+            "Offset",
+            Value::from(5f64),
+        )?;
+    }
+
+    // administrators may quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+/// Stands in for polkit, authorizing only the given actions and recording
+/// the subject and action of every check.
+struct MockAuthority {
+    allowed: Vec<&'static str>,
+    checks: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+#[zbus::dbus_interface(name = "org.freedesktop.PolicyKit1.Authority")]
+impl MockAuthority {
+    fn check_authorization(
+        &self,
+        subject: (String, HashMap<String, OwnedValue>),
+        action_id: String,
+        _details: HashMap<String, String>,
+        _flags: u32,
+        _cancellation_id: String,
+    ) -> ((bool, bool, HashMap<String, String>),) {
+        let name = String::try_from(subject.1["name"].clone()).unwrap_or_default();
+        let authorized = self.allowed.contains(&action_id.as_str());
+        self.checks.lock().unwrap().push((name, action_id));
+        ((authorized, false, HashMap::new()),)
+    }
+}
+
+#[test]
+fn test_polkit_authority() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 1);
+    let channel_dir = fake_channel(&chip_dir, 0, 1000, 0);
+    let enable_file = channel_dir.join("enable");
+    let duty_cycle_file = channel_dir.join("duty_cycle");
+
+    // the mock authority, on the session bus:
+    let checks = Arc::new(Mutex::new(vec![]));
+    let authority_name = random_dbus_service_name();
+    let authority_connection = Connection::session()?;
+    authority_connection.object_server_mut().at(
+        "/org/freedesktop/PolicyKit1/Authority",
+        MockAuthority {
+            allowed: vec![
+                "com.kevinbader.pwmd.set-duty-cycle",
+                "com.kevinbader.pwmd.quit",
+            ],
+            checks: checks.clone(),
+        },
+    )?;
+    authority_connection.request_name(authority_name.as_str())?;
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd_with(tmpdir.path(), |args| {
+        args.authority = Authority::Polkit;
+        args.polkit_service_name = authority_name;
+    });
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // authorized:
+    let _ = connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetDutyCycleNs",
+        &(0u32, 0u32, 500u64),
+    )?;
+    check_file(&duty_cycle_file, "500");
+
+    // not authorized:
+    match connection.call_method(destination, pwm1, pwm1_iface, "Enable", &(0u32, 0u32)) {
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+            assert!(
+                error.contains("com.kevinbader.pwmd.set-enabled"),
+                "{}",
+                error
+            );
+        }
+        x => panic!("expected MethodError, got: {:?}", x),
+    }
+    check_file(&enable_file, "0");
+
+    // the interfaces of the channel objects ask as well:
+    let pwm0 = "/com/kevinbader/pwmd/pwmchip0/pwm0";
+    match connection.call_method(
+        destination,
+        pwm0,
+        Some("com.kevinbader.pwmd.Led1"),
+        "FadeTo",
+        &(1.0f64, 0u32),
+    ) {
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+            assert!(error.contains("com.kevinbader.pwmd.drive"), "{}", error);
+        }
+        x => panic!("expected MethodError, got: {:?}", x),
+    }
+    check_file(&duty_cycle_file, "500");
+
+    // so does setting properties, on behalf of the caller:
+    let set_property = |property: &str, value: Value<'static>| {
+        connection.call_method(
+            destination,
+            pwm0,
+            Some("org.freedesktop.DBus.Properties"),
+            "Set",
+            &("com.kevinbader.pwmd.Channel1", property, value),
+        )
+    };
+    let _ = set_property("DutyCycle", Value::from(250u64))?;
+    check_file(&duty_cycle_file, "250");
+    match set_property("Enabled", Value::from(true)) {
+        Err(zbus::Error::MethodError(name, Some(error), _)) => {
+            assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.AccessDenied");
+            assert!(
+                error.contains("com.kevinbader.pwmd.set-enabled"),
+                "{}",
+                error
+            );
+        }
+        x => panic!("expected MethodError, got: {:?}", x),
+    }
+    check_file(&enable_file, "0");
+
+    // reading doesn't involve the authority:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "GetPeriodNs", &(0u32, 0u32))?;
+
+    let caller = connection.unique_name().unwrap().to_string();
+    assert_eq!(
+        *checks.lock().unwrap(),
+        vec![
+            (
+                caller.clone(),
+                "com.kevinbader.pwmd.set-duty-cycle".to_owned()
+            ),
+            (caller.clone(), "com.kevinbader.pwmd.set-enabled".to_owned()),
+            (caller.clone(), "com.kevinbader.pwmd.drive".to_owned()),
+            (
+                caller.clone(),
+                "com.kevinbader.pwmd.set-duty-cycle".to_owned()
+            ),
+            (caller, "com.kevinbader.pwmd.set-enabled".to_owned()),
+        ]
+    );
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_errors_are_named_after_their_cause() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory with pwmchip0/pwm0 exported, but not
+    // pwmchip0/pwm1:
+    let tmpdir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(tmpdir.path(), 0, 2);
+    fake_channel(&chip_dir, 0, 1000, 0);
+
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+    let pwm1 = "/com/kevinbader/pwmd/pwm1";
+    let pwm1_iface = Some("com.kevinbader.pwmd.pwm1");
+
+    // Returns the name of the error and its details:
+    let error_of = |reply: zbus::Result<Arc<Message>>| match reply {
+        Err(zbus::Error::MethodError(name, Some(_), reply)) => {
+            let details = reply
+                .body::<(String, HashMap<String, OwnedValue>)>()
+                .map(|(_, details)| details)
+                .unwrap_or_default();
+            (name.to_string(), details)
+        }
+        x => panic!("expected MethodError, got: {:?}", x),
+    };
+
+    let (name, details) = error_of(connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetPolarity",
+        &(0u32, 1u32, "normal"),
+    ));
+    assert_eq!(name, "com.kevinbader.pwmd.Error.NotExported");
+    assert_eq!(details["controller"], OwnedValue::from(0u32));
+    assert_eq!(details["channel"], OwnedValue::from(1u32));
+
+    let (name, details) = error_of(connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetPolarity",
+        &(0u32, 0u32, "sideways"),
+    ));
+    assert_eq!(name, "com.kevinbader.pwmd.Error.InvalidPolarity");
+    assert!(details.is_empty());
+
+    let (name, details) = error_of(connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "SetFrequencyHz",
+        &(0u32, 0u32, -1.0f64),
+    ));
+    assert_eq!(name, "com.kevinbader.pwmd.Error.InvalidFrequency");
+    assert_eq!(details["value"], OwnedValue::from(-1.0f64));
+
+    // setting properties fails the same way:
+    let set_property = |interface: &str, property: &str, value: Value<'static>| {
+        connection.call_method(
+            destination,
+            "/com/kevinbader/pwmd/pwmchip0/pwm0",
+            Some("org.freedesktop.DBus.Properties"),
+            "Set",
+            &(interface, property, value),
+        )
+    };
+    let (name, details) = error_of(set_property(
+        "com.kevinbader.pwmd.Channel1",
+        "DutyCycle",
+        Value::from(2000u64),
+    ));
+    assert_eq!(name, "com.kevinbader.pwmd.Error.DutyCycleGreaterThanPeriod");
+    assert!(details.is_empty());
+
+    let (name, details) = error_of(set_property(
+        "com.kevinbader.pwmd.Led1",
+        "Gamma",
+        Value::from(-1.0f64),
+    ));
+    assert_eq!(name, "com.kevinbader.pwmd.Error.InvalidGamma");
+    assert_eq!(details["value"], OwnedValue::from(-1.0f64));
+
+    // errors that aren't pwmd's own keep their standard names:
+    let (name, _) = error_of(connection.call_method(
+        destination,
+        pwm1,
+        pwm1_iface,
+        "ReleaseChannel",
+        &(0u32, 0u32),
+    ));
+    assert_eq!(name, "org.freedesktop.DBus.Error.Failed");
+
+    // quit:
+    let _ = connection.call_method(destination, pwm1, pwm1_iface, "Quit", &())?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_changes_made_outside_of_pwmd_are_picked_up() -> anyhow::Result<()> {
+    // fake /sys/class/pwm directory:
+    let tmpdir = TempDir::new().unwrap();
+    let (dbus_service_name, dbus_thread) = spawn_pwmd(tmpdir.path());
+
+    let connection = Connection::session()?;
+    let destination: BusName<'_> = dbus_service_name.as_str().try_into().unwrap();
+    let destination = Some(&destination);
+
+    let signals = subscribe(
+        &connection,
+        &format!(
+            "type='signal',sender='{}',path_namespace='/com/kevinbader/pwmd'",
+            dbus_service_name
+        ),
+    )?;
+
+    // a controller shows up, with channel pwm0 already exported; the
+    // directories are moved into place to make them appear at once:
+    let staging_dir = TempDir::new().unwrap();
+    let chip_dir = fake_chip(staging_dir.path(), 0, 1);
+    fake_channel(&chip_dir, 0, 100, 70);
+    fs::rename(&chip_dir, tmpdir.child("pwmchip0")).unwrap();
+    let chip_dir = tmpdir.child("pwmchip0");
+    let channel_dir = chip_dir.join("pwm0");
+
+    let (path, interfaces) = next_signal(&signals, "InterfacesAdded")?.body::<(
+        OwnedObjectPath,
+        HashMap<String, HashMap<String, OwnedValue>>,
+    )>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/pwmchip0");
+    assert!(interfaces.contains_key("com.kevinbader.pwmd.Controller1"));
+    let (path, interfaces) = next_signal(&signals, "InterfacesAdded")?.body::<(
+        OwnedObjectPath,
+        HashMap<String, HashMap<String, OwnedValue>>,
+    )>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/pwmchip0/pwm0");
+    let channel0 = &interfaces["com.kevinbader.pwmd.Channel1"];
+    assert_eq!(u64::try_from(channel0["DutyCycle"].clone())?, 70);
+
+    // another process changes the duty cycle:
+    let _ = write(channel_dir.join("duty_cycle"), "30");
+    let changed = next_properties_changed(&signals, "com.kevinbader.pwmd.Channel1")?;
+    assert_eq!(changed.len(), 1);
+    assert_eq!(u64::try_from(changed["DutyCycle"].clone())?, 30);
+
+    // ...and unexports the channel:
+    fs::remove_dir_all(&channel_dir).unwrap();
+    let (path, mut interfaces) =
+        next_signal(&signals, "InterfacesRemoved")?.body::<(OwnedObjectPath, Vec<String>)>()?;
+    assert_eq!(path.as_str(), "/com/kevinbader/pwmd/pwmchip0/pwm0");
+    interfaces.sort();
+    assert_eq!(
+        interfaces,
+        vec![
+            "com.kevinbader.pwmd.Buzzer1",
+            "com.kevinbader.pwmd.Channel1",
+            "com.kevinbader.pwmd.Fan1",
+            "com.kevinbader.pwmd.Led1",
+            "com.kevinbader.pwmd.Servo1"
+        ]
+    );
+
+    // quit:
+    let _ = connection.call_method(
+        destination,
+        "/com/kevinbader/pwmd/pwm1",
+        Some("com.kevinbader.pwmd.pwm1"),
+        "Quit",
+        &(),
+    )?;
+    dbus_thread.join().unwrap();
+    Ok(())
+}
+
+/// Runs pwmd on the session bus in a background thread, using `sysfs_root` as
+/// a fake sysfs directory. Returns once pwmd is ready.
+fn spawn_pwmd(sysfs_root: &Path) -> (String, JoinHandle<()>) {
+    spawn_pwmd_with(sysfs_root, |_| {})
+}
+
+/// Like `spawn_pwmd`, but lets the caller change the arguments first.
+fn spawn_pwmd_with(
+    sysfs_root: &Path,
+    customize: impl FnOnce(&mut Args),
+) -> (String, JoinHandle<()>) {
+    let dbus_service_name = random_dbus_service_name();
+    let mut args = Args {
+        bus: Bus::Session,
+        dbus_service_name: dbus_service_name.clone(),
+        config: None,
+        state_file: None,
+        startup: Startup::Leave,
+        authority: Authority::Allow,
+        polkit_service_name: "org.freedesktop.PolicyKit1".to_owned(),
+        sysfs_root: Some(sysfs_root.to_owned()),
+        sensor_root: Some(sysfs_root.to_owned()),
+        poll_interval_ms: 50,
+        fan_interval_ms: 50,
+    };
+    customize(&mut args);
+
+    let (tx, rx) = channel();
+    let dbus_thread = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            pwmd::dbus::listen(args, || tx.send(()).unwrap())
+                .await
+                .unwrap();
+        });
+    });
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    (dbus_service_name, dbus_thread)
+}
+
+/// Fakes `pwmchipN` below `sysfs_root`, with `npwm` channels and the `export`
+/// and `unexport` files. Returns its directory.
+fn fake_chip(sysfs_root: &Path, controller: u32, npwm: u32) -> PathBuf {
+    let chip_dir = sysfs_root.join(format!("pwmchip{}", controller));
+    fs::create_dir_all(&chip_dir).unwrap();
+    let _ = write(chip_dir.join("npwm"), &npwm.to_string());
+    let _ = touch(chip_dir.join("export"));
+    let _ = touch(chip_dir.join("unexport"));
+    chip_dir
+}
+
+/// Fakes an exported channel of a fake chip: disabled, with normal polarity
+/// and the given period and duty cycle in nanoseconds. Returns its directory.
+fn fake_channel(chip_dir: &Path, channel: u32, period_ns: u64, duty_cycle_ns: u64) -> PathBuf {
+    let channel_dir = chip_dir.join(format!("pwm{}", channel));
+    fs::create_dir(&channel_dir).unwrap();
+    let _ = write(channel_dir.join("enable"), "0");
+    let _ = write(channel_dir.join("polarity"), "normal");
+    let _ = write(channel_dir.join("period"), &period_ns.to_string());
+    let _ = write(channel_dir.join("duty_cycle"), &duty_cycle_ns.to_string());
+    channel_dir
+}
+
+/// Forwards all signals that match the given rule.
+fn subscribe(connection: &Connection, rule: &str) -> anyhow::Result<Receiver<Arc<Message>>> {
+    let stream = MessageStream::from(connection);
+    let _ = connection.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus"),
+        "AddMatch",
+        &(rule,),
+    )?;
+
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        for msg in stream.flatten() {
+            if msg.message_type() == MessageType::Signal && tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Waits for the next signal with the given name, skipping all others.
+fn next_signal(signals: &Receiver<Arc<Message>>, name: &str) -> anyhow::Result<Arc<Message>> {
+    loop {
+        let msg = signals.recv_timeout(Duration::from_secs(5))?;
+        if msg.member()?.is_some_and(|m| m == name) {
+            return Ok(msg);
+        }
+    }
+}
+
+/// Waits for the next `PropertiesChanged` signal and returns the changed properties.
+/// Waits for the next `PropertiesChanged` signal of the given interface.
+fn next_properties_changed(
+    signals: &Receiver<Arc<Message>>,
+    interface: &str,
+) -> anyhow::Result<HashMap<String, OwnedValue>> {
+    loop {
+        let (name, changed, _) = next_signal(signals, "PropertiesChanged")?.body::<(
+            String,
+            HashMap<String, OwnedValue>,
+            Vec<String>,
+        )>()?;
+        if name == interface {
+            return Ok(changed);
+        }
+    }
+}
+
 fn touch(path: PathBuf) -> PathBuf {
     fs::write(&path, b"").unwrap();
     path